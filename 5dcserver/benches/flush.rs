@@ -0,0 +1,113 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::runtime::Runtime;
+
+use fivedcserver::datatype::*;
+
+/* relayed actions flushed one by one against batches of them, as flush_interval coalesces
+writes to the transport stand in for syscalls, they are printed once per batch size
+*/
+
+const ACTIONS: usize = 1024;
+
+// counts writes, reads and the rest pass through
+struct CountingStream<S> {
+    inner: S,
+    writes: Arc<AtomicUsize>,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for CountingStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for CountingStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if result.is_ready() {
+            self.writes.fetch_add(1, Ordering::Relaxed);
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+fn action() -> Message {
+    let square = Coordinate {
+        l: 0,
+        t: 1,
+        board_color: Color::White,
+        y: 1,
+        x: 4,
+    };
+    Message::C2SOrS2CAction(C2SOrS2CActionBody {
+        action_type: ActionType::Move,
+        color: Color::White,
+        seconds_passed: 0,
+        src: square,
+        dst: Coordinate { y: 3, ..square },
+    })
+}
+
+// returns the number of writes
+async fn relay(batch: usize) -> usize {
+    let (stream, mut peer) = tokio::io::duplex(1 << 16);
+    let drain =
+        tokio::spawn(async move { tokio::io::copy(&mut peer, &mut tokio::io::sink()).await });
+    let writes = Arc::new(AtomicUsize::new(0));
+    let stream = CountingStream {
+        inner: stream,
+        writes: writes.clone(),
+    };
+    let mut io = MessageIO::new(stream, MESSAGE_LENGTH_MAX).coalesced();
+    for i in 1..=ACTIONS {
+        io.put(action()).await.unwrap();
+        if i % batch == 0 {
+            io.flush().await.unwrap();
+        }
+    }
+    io.close().await.unwrap();
+    drain.await.unwrap().unwrap();
+    writes.load(Ordering::Relaxed)
+}
+
+fn flush_batching(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("flush_batching");
+    for batch in [1, 8, 32] {
+        println!(
+            "batch {}: {} writes for {} actions",
+            batch,
+            rt.block_on(relay(batch)),
+            ACTIONS
+        );
+        group.bench_with_input(BenchmarkId::from_parameter(batch), &batch, |b, batch| {
+            b.iter(|| rt.block_on(relay(*batch)))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, flush_batching);
+criterion_main!(benches);
@@ -0,0 +1,29 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use rand::seq::index::sample;
+use std::collections::HashMap;
+
+use fivedcserver::datatype::*;
+
+fn passcode_generation(c: &mut Criterion) {
+    let mut group = c.benchmark_group("passcode_generation");
+    let space = PASSCODE_MAX as usize + 1;
+    let mut rng = rand::thread_rng();
+    for percent in [1, 10, 50, 90, 99] {
+        // occupy a random subset of the passcode space
+        let occupied: HashMap<Passcode, ()> = sample(&mut rng, space, space * percent / 100)
+            .into_iter()
+            .map(|passcode| (passcode as Passcode, ()))
+            .collect();
+        group.bench_with_input(
+            BenchmarkId::from_parameter(format!("{}%", percent)),
+            &occupied,
+            |b, occupied| {
+                b.iter(|| generate_random_passcode_internal_excluding(occupied, &mut rng))
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, passcode_generation);
+criterion_main!(benches);
@@ -0,0 +1,50 @@
+use bytes::BytesMut;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::process::exit;
+
+use fivedcserver::datatype::*;
+use fivedcserver::vectors::golden_vectors;
+
+/* golden vectors for other implementations of the protocol
+one instance of every message that goes on the wire, named c2s_ or s2c_ and its kind_name
+<name>.bin is the packed message without the length prefix, <name>.json the decoded fields
+each vector is decoded and packed again before it is written, the bytes must not change
+internal messages never go on the wire and have no vector
+*/
+
+fn write_vectors(dir: &Path) -> Result<(), Box<dyn Error>> {
+    fs::create_dir_all(dir)?;
+    for (name, msg, from_server) in golden_vectors() {
+        let bytes = msg.pack()?;
+        let decoded = if from_server {
+            Message::unpack_s2c(BytesMut::from(&bytes[..]))
+        } else {
+            Message::unpack(BytesMut::from(&bytes[..]))
+        }
+        .map_err(|e| format!("{} doesn't decode: {}", name, e))?;
+        if decoded.pack()? != bytes {
+            Err(format!("{} changes when decoded and packed again.", name))?;
+        }
+        fs::write(dir.join(format!("{}.bin", name)), &bytes)?;
+        fs::write(
+            dir.join(format!("{}.json", name)),
+            serde_json::to_string_pretty(&decoded)? + "\n",
+        )?;
+        println!("{} {} bytes", name, bytes.len());
+    }
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() != 2 {
+        println!("usage: {} <OUTPUT DIR>", args[0]);
+        exit(1);
+    }
+    if let Err(e) = write_vectors(Path::new(&args[1])) {
+        eprintln!("{}", e);
+        exit(1);
+    }
+}
@@ -0,0 +1,333 @@
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+use fivedcserver::datatype::*;
+use fivedcserver::{err_invalid_data, err_timeout};
+
+/* soak test against a running server, pairs of simulated clients play private matches
+each match: both greet, one creates, the other joins by passcode, they take turns
+making random moves (with the occasional undo) and submitting, then one forfeits
+any unexpected reply or disconnect is an error, panics and leftover matches are failures
+*/
+
+const REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+const ERRORS_SHOWN: usize = 10;
+
+#[derive(Default)]
+struct Report {
+    matches: u64,
+    errors: Vec<ProtocolError>,
+    latencies: Vec<Duration>, // action sent until relayed to the opponent
+}
+
+async fn get(io: &mut MessageIO) -> Result<Message> {
+    match timeout(REPLY_TIMEOUT, io.get()).await {
+        Ok(Ok(Some(msg))) => Ok(msg),
+        Ok(Ok(None)) => Err(ProtocolError::Disconnected),
+        Ok(Err(e)) => Err(e),
+        Err(_) => err_timeout!("No reply within {:?}.", REPLY_TIMEOUT),
+    }
+}
+
+async fn put(io: &mut MessageIO, msg: Message) -> Result<()> {
+    io.put(msg).await?;
+    io.flush().await
+}
+
+fn unexpected<T>(expected: &str, msg: Message) -> Result<T> {
+    err_invalid_data!("Expected {}, got {:?}.", expected, msg)
+}
+
+fn greet() -> Message {
+    Message::C2SGreet(C2SGreetBody {
+        version1: GREET_VERSION.0,
+        version2: GREET_VERSION.1,
+        extensions: 0,
+    })
+}
+
+async fn connect(addr: &str) -> Result<MessageIO> {
+    let mut io = MessageIO::connect(addr).await?;
+    put(&mut io, greet()).await?;
+    match get(&mut io).await? {
+        Message::S2CGreet => Ok(io),
+        msg => unexpected("S2CGreet", msg),
+    }
+}
+
+async fn match_start(io: &mut MessageIO) -> Result<Color> {
+    match get(io).await? {
+        Message::S2CMatchStart(body) if body.m.color == OptionalColorWithRandom::White => {
+            Ok(Color::White)
+        }
+        Message::S2CMatchStart(_) => Ok(Color::Black),
+        msg => unexpected("S2CMatchStart", msg),
+    }
+}
+
+// the mover gets its action echoed, the opponent gets it relayed
+async fn relay(
+    mover: &mut MessageIO,
+    opponent: &mut MessageIO,
+    action: C2SOrS2CActionBody,
+    report: &mut Report,
+) -> Result<()> {
+    let sent = Instant::now();
+    put(mover, Message::C2SOrS2CAction(action)).await?;
+    match get(opponent).await? {
+        Message::C2SOrS2CAction(body) if body.action_type == action.action_type => {
+            report.latencies.push(sent.elapsed())
+        }
+        msg => return unexpected("the relayed action", msg),
+    }
+    match get(mover).await? {
+        Message::C2SOrS2CAction(body) if body.action_type == action.action_type => Ok(()),
+        msg => unexpected("the echoed action", msg),
+    }
+}
+
+async fn play_match(addr: &str, rng: &mut StdRng, report: &mut Report) -> Result<()> {
+    let mut host = connect(addr).await?;
+    put(
+        &mut host,
+        Message::C2SMatchCreateOrJoin(C2SMatchCreateOrJoinBody::Create(MatchSettings {
+            color: OptionalColorWithRandom::Random,
+            clock: OptionalClock::NoClock,
+            variant: Variant::Standard,
+            visibility: Visibility::Private,
+            passcode: -1,
+            match_id: -1,
+        })),
+    )
+    .await?;
+    let passcode = match get(&mut host).await? {
+        Message::S2CMatchCreateOrJoinResult(S2CMatchCreateOrJoinResultBody::Success(m)) => {
+            m.passcode
+        }
+        msg => return unexpected("a created match", msg),
+    };
+
+    let mut joiner = connect(addr).await?;
+    put(
+        &mut joiner,
+        Message::C2SMatchCreateOrJoin(C2SMatchCreateOrJoinBody::Join(passcode)),
+    )
+    .await?;
+    match get(&mut joiner).await? {
+        Message::S2CMatchCreateOrJoinResult(S2CMatchCreateOrJoinResultBody::Success(_)) => {}
+        msg => return unexpected("a joined match", msg),
+    }
+    let joiner_color = match_start(&mut joiner).await?;
+    let host_color = match_start(&mut host).await?;
+    if host_color == joiner_color {
+        return err_invalid_data!("Both sides play {:?}.", host_color);
+    }
+
+    let (width, height) = board_dimensions(Variant::Standard);
+    let (mut mover, mut opponent) = match host_color {
+        Color::White => (host, joiner),
+        Color::Black => (joiner, host),
+    };
+    let mut color = Color::White;
+    for t in 1..=rng.gen_range(1..=20) {
+        for _ in 0..rng.gen_range(1..=3) {
+            let mut square = || Coordinate {
+                l: 0,
+                t,
+                board_color: color,
+                y: rng.gen_range(0..i64::from(height)),
+                x: rng.gen_range(0..i64::from(width)),
+            };
+            let action = C2SOrS2CActionBody {
+                action_type: ActionType::Move,
+                color,
+                seconds_passed: 0,
+                src: square(),
+                dst: square(),
+            };
+            relay(&mut mover, &mut opponent, action, report).await?;
+            if rng.gen_bool(0.1) {
+                let undo = C2SOrS2CActionBody {
+                    action_type: ActionType::UndoMove,
+                    ..action
+                };
+                relay(&mut mover, &mut opponent, undo, report).await?;
+            }
+        }
+        let submit = C2SOrS2CActionBody {
+            action_type: ActionType::SubmitMoves,
+            color,
+            seconds_passed: 0,
+            src: Coordinate {
+                l: 0,
+                t: 0,
+                board_color: color,
+                y: 0,
+                x: 0,
+            },
+            dst: Coordinate {
+                l: 0,
+                t: 0,
+                board_color: color,
+                y: 0,
+                x: 0,
+            },
+        };
+        relay(&mut mover, &mut opponent, submit, report).await?;
+        std::mem::swap(&mut mover, &mut opponent);
+        color = color.reversed();
+    }
+
+    put(&mut mover, Message::C2SForfeit).await?;
+    match get(&mut opponent).await? {
+        Message::S2COpponentLeft => {}
+        msg => return unexpected("S2COpponentLeft", msg),
+    }
+    mover.close().await?;
+    opponent.close().await
+}
+
+// one pair of clients playing match after match until the deadline
+async fn run_pair(addr: String, deadline: Instant) -> Report {
+    let mut rng = StdRng::from_entropy();
+    let mut report = Report::default();
+    while Instant::now() < deadline {
+        match play_match(&addr, &mut rng, &mut report).await {
+            Ok(()) => report.matches += 1,
+            Err(e) => {
+                report.errors.push(e);
+                // don't hammer a server that is down
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        }
+    }
+    report
+}
+
+// waiting and playing from the stats command, both should be back to zero
+async fn leftover_matches(admin: &str) -> std::io::Result<(u64, u64)> {
+    let mut stream = TcpStream::connect(admin).await?;
+    stream.write_all(b"stats\n").await?;
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line).await?;
+    let count = |key: &str| {
+        line.split(", ")
+            .find_map(|field| field.strip_prefix(key)?.trim().parse().ok())
+    };
+    match (count("waiting "), count("playing ")) {
+        (Some(waiting), Some(playing)) => Ok((waiting, playing)),
+        _ => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("unexpected stats reply {:?}", line.trim()),
+        )),
+    }
+}
+
+fn percentile(sorted: &[Duration], p: usize) -> Duration {
+    match sorted.len() {
+        0 => Duration::ZERO,
+        n => sorted[(n * p / 100).min(n - 1)],
+    }
+}
+
+fn print_usage(arg0: &String) {
+    println!(
+        "usage: {} <ADDR> [<PAIRS> [<SECONDS> [<ADMIN ADDR>]]]",
+        arg0
+    );
+    println!("defaults to 16 pairs for 60 seconds, leftover matches are checked with ADMIN ADDR");
+}
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() < 2 || args.len() > 5 {
+        print_usage(&args[0]);
+        std::process::exit(2);
+    }
+    let parse = |i: usize, default: u64| match args.get(i).map(|arg| arg.parse()) {
+        None => default,
+        Some(Ok(n)) => n,
+        Some(Err(_)) => {
+            print_usage(&args[0]);
+            std::process::exit(2);
+        }
+    };
+    let pairs = parse(2, 16);
+    let seconds = parse(3, 60);
+    let deadline = Instant::now() + Duration::from_secs(seconds);
+    println!(
+        "Soaking {} with {} pairs for {} seconds.",
+        args[1], pairs, seconds
+    );
+
+    let handles: Vec<_> = (0..pairs)
+        .map(|_| tokio::spawn(run_pair(args[1].clone(), deadline)))
+        .collect();
+    let mut report = Report::default();
+    let mut panics = 0;
+    for handle in handles {
+        match handle.await {
+            Ok(pair) => {
+                report.matches += pair.matches;
+                report.errors.extend(pair.errors);
+                report.latencies.extend(pair.latencies);
+            }
+            Err(e) => {
+                eprintln!("Pair panicked: {}", e);
+                panics += 1;
+            }
+        }
+    }
+
+    let attempts = report.matches + report.errors.len() as u64;
+    println!(
+        "matches {}, errors {} ({:.2}%), panics {}",
+        report.matches,
+        report.errors.len(),
+        match attempts {
+            0 => 0.0,
+            _ => report.errors.len() as f64 * 100.0 / attempts as f64,
+        },
+        panics
+    );
+    for e in report.errors.iter().take(ERRORS_SHOWN) {
+        println!("  {}", e);
+    }
+    if report.errors.len() > ERRORS_SHOWN {
+        println!("  and {} more", report.errors.len() - ERRORS_SHOWN);
+    }
+    report.latencies.sort();
+    println!(
+        "relay latency over {} actions: p50 {:?}, p90 {:?}, p99 {:?}, max {:?}",
+        report.latencies.len(),
+        percentile(&report.latencies, 50),
+        percentile(&report.latencies, 90),
+        percentile(&report.latencies, 99),
+        report.latencies.last().copied().unwrap_or_default()
+    );
+
+    let mut failed = !report.errors.is_empty() || panics > 0;
+    if let Some(admin) = args.get(4) {
+        // closed connections are cleaned up asynchronously
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        match leftover_matches(admin).await {
+            Ok((0, 0)) => println!("no leftover matches"),
+            Ok((waiting, playing)) => {
+                println!("leftover matches: waiting {}, playing {}", waiting, playing);
+                failed = true;
+            }
+            Err(e) => {
+                println!("Failed to query the admin interface: {}", e);
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        std::process::exit(1);
+    }
+}
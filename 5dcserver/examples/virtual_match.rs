@@ -0,0 +1,152 @@
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::timeout;
+
+use fivedcserver::datatype::*;
+use fivedcserver::server::{ServerConfig, ServerState};
+use fivedcserver::{err_invalid_data, err_timeout};
+
+/* the server embedded in-process, two virtual connections play a short match
+no socket is opened, the connections go through the same matchmaking and relay as real ones
+exits with 1 if any reply is not the expected one
+*/
+
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+async fn get(io: &mut MessageIO) -> Result<Message> {
+    match timeout(REPLY_TIMEOUT, io.get()).await {
+        Ok(Ok(Some(msg))) => Ok(msg),
+        Ok(Ok(None)) => Err(ProtocolError::Disconnected),
+        Ok(Err(e)) => Err(e),
+        Err(_) => err_timeout!("No reply within {:?}.", REPLY_TIMEOUT),
+    }
+}
+
+async fn put(io: &mut MessageIO, msg: Message) -> Result<()> {
+    io.put(msg).await?;
+    io.flush().await
+}
+
+fn unexpected<T>(expected: &str, msg: Message) -> Result<T> {
+    err_invalid_data!("Expected {}, got {:?}.", expected, msg)
+}
+
+async fn connect(ss: &Arc<ServerState>, running: &watch::Receiver<bool>) -> Result<MessageIO> {
+    let mut io = ss.connect_virtual(running.clone());
+    put(
+        &mut io,
+        Message::C2SGreet(C2SGreetBody {
+            version1: GREET_VERSION.0,
+            version2: GREET_VERSION.1,
+            extensions: 0,
+        }),
+    )
+    .await?;
+    match get(&mut io).await? {
+        Message::S2CGreet => Ok(io),
+        msg => unexpected("S2CGreet", msg),
+    }
+}
+
+fn action(action_type: ActionType, color: Color, src: Coordinate, dst: Coordinate) -> Message {
+    Message::C2SOrS2CAction(C2SOrS2CActionBody {
+        action_type,
+        color,
+        seconds_passed: 0,
+        src,
+        dst,
+    })
+}
+
+// the mover gets its action echoed, the opponent gets it relayed
+async fn relay(mover: &mut MessageIO, opponent: &mut MessageIO, msg: Message) -> Result<()> {
+    put(mover, msg.clone()).await?;
+    for io in [opponent, mover] {
+        match get(io).await? {
+            relayed if relayed.kind_name() == msg.kind_name() => {}
+            other => return unexpected("the action", other),
+        }
+    }
+    Ok(())
+}
+
+async fn play(ss: &Arc<ServerState>, running: &watch::Receiver<bool>) -> Result<()> {
+    let mut white = connect(ss, running).await?;
+    put(
+        &mut white,
+        Message::C2SMatchCreateOrJoin(C2SMatchCreateOrJoinBody::Create(MatchSettings {
+            color: OptionalColorWithRandom::White,
+            clock: OptionalClock::NoClock,
+            variant: Variant::Standard,
+            visibility: Visibility::Private,
+            passcode: -1,
+            match_id: -1,
+        })),
+    )
+    .await?;
+    let passcode = match get(&mut white).await? {
+        Message::S2CMatchCreateOrJoinResult(S2CMatchCreateOrJoinResultBody::Success(m)) => {
+            m.passcode
+        }
+        msg => return unexpected("a created match", msg),
+    };
+    let mut black = connect(ss, running).await?;
+    put(
+        &mut black,
+        Message::C2SMatchCreateOrJoin(C2SMatchCreateOrJoinBody::Join(passcode)),
+    )
+    .await?;
+    match get(&mut black).await? {
+        Message::S2CMatchCreateOrJoinResult(S2CMatchCreateOrJoinResultBody::Success(_)) => {}
+        msg => return unexpected("a joined match", msg),
+    }
+    for io in [&mut black, &mut white] {
+        match get(io).await? {
+            Message::S2CMatchStart(body) => println!("match {} started", body.match_id),
+            msg => return unexpected("S2CMatchStart", msg),
+        }
+    }
+
+    // (0T1)e2 -> (0T1)e4, then (0T1)e7 -> (0T1)e5
+    let square = |t, board_color, y| Coordinate {
+        l: 0,
+        t,
+        board_color,
+        y,
+        x: 4,
+    };
+    let (mut mover, mut opponent) = (&mut white, &mut black);
+    for (color, from, to) in [(Color::White, 1, 3), (Color::Black, 6, 4)] {
+        let (src, dst) = (square(1, color, from), square(1, color, to));
+        relay(mover, opponent, action(ActionType::Move, color, src, dst)).await?;
+        let submit = action(ActionType::SubmitMoves, color, src, src);
+        relay(mover, opponent, submit).await?;
+        std::mem::swap(&mut mover, &mut opponent);
+    }
+    println!("played 1.e4 e5");
+
+    put(&mut white, Message::C2SForfeit).await?;
+    match get(&mut black).await? {
+        Message::S2COpponentLeft => {}
+        msg => return unexpected("S2COpponentLeft", msg),
+    }
+    white.close().await?;
+    black.close().await
+}
+
+#[tokio::main]
+async fn main() {
+    let ss = Arc::new(ServerState::new(ServerConfig::default()));
+    let (running_tx, running) = watch::channel(true);
+    let result = play(&ss, &running).await;
+    running_tx.send_replace(false);
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+    let history = ss.server_history_matches.lock().await;
+    for (match_id, m) in history.iter() {
+        println!("match {}: {:?}, {:?}", match_id, m.state, m.outcome);
+    }
+}
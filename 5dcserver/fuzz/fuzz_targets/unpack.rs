@@ -0,0 +1,10 @@
+#![no_main]
+
+use bytes::BytesMut;
+use fivedcserver::datatype::Message;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    // either Ok or Err is fine, panicking is not
+    let _ = Message::unpack(BytesMut::from(data));
+});
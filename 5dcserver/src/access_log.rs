@@ -0,0 +1,75 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::fs::File;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+use tracing::warn;
+
+use crate::datatype::{ConnectionId, MatchOutcome, Variant};
+
+// what a connection did, filled in by the handlers and written once it is closed
+#[derive(Debug, Default, Copy, Clone)]
+pub struct AccessRecord {
+    pub matches_created: u32,
+    pub matches_joined: u32,
+    pub variant: Option<Variant>, // of the last match, resolved once it starts
+    pub outcome: Option<MatchOutcome>, // of the last match
+    pub bytes_read: u64,          // frame payloads, see MessageIO
+    pub bytes_written: u64,
+}
+
+/* append-only usage log, one line of key=value pairs per connection
+the address is never written, only a salted hash of it
+*/
+#[derive(Debug)]
+pub struct AccessLog {
+    file: Mutex<File>,
+    salt: String,
+}
+
+impl AccessLog {
+    pub fn new(file: File, salt: String) -> Self {
+        AccessLog {
+            file: Mutex::new(file),
+            salt,
+        }
+    }
+
+    // stable across restarts as long as the salt and the binary are kept
+    fn client(&self, ip: IpAddr) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.salt.hash(&mut hasher);
+        ip.to_canonical().hash(&mut hasher);
+        hasher.finish()
+    }
+
+    pub async fn write(
+        &self,
+        ip: IpAddr,
+        id: ConnectionId,
+        duration: Duration,
+        record: &AccessRecord,
+    ) {
+        let line = format!(
+            "time={} conn={} client={:016x} duration={} created={} joined={} variant={:?} outcome={:?} read={} written={}\n",
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+            id,
+            self.client(ip),
+            duration.as_secs(),
+            record.matches_created,
+            record.matches_joined,
+            record.variant.map_or("-".to_string(), |v| v.to_string()),
+            record.outcome.map_or("-".to_string(), |o| o.to_string()),
+            record.bytes_read,
+            record.bytes_written,
+        );
+        // one write per line so concurrent connections never interleave
+        if let Err(e) = self.file.lock().await.write_all(line.as_bytes()).await {
+            warn!("Failed to write the access log: {}", e);
+        }
+    }
+}
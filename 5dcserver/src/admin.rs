@@ -0,0 +1,277 @@
+use std::cmp::Reverse;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::select;
+use tokio::sync::watch;
+use tracing::{error, info};
+
+use crate::datatype::{
+    pack_history_dump, passcode_to_notation, ActionType, C2SOrS2CActionBody, Coordinate,
+    HistoryDumpEntry, MatchId, MessageType, Variant,
+};
+use crate::server::{ServerState, MATCH_DURATION_BUCKETS};
+
+/* line based admin interface, one command per line, one reply line per command
+no authentication, bind it to localhost only
+*/
+pub async fn serve_admin(
+    ss: Arc<ServerState>,
+    listener: TcpListener,
+    mut running: watch::Receiver<bool>,
+) {
+    loop {
+        select! {
+            result = listener.accept() => match result {
+                Ok((stream, addr)) => {
+                    tokio::spawn(handle_admin(ss.clone(), stream, addr));
+                }
+                Err(e) => error!("Admin accept failed: {}", e),
+            },
+            _ = running.changed() => break
+        }
+    }
+}
+
+async fn handle_admin(ss: Arc<ServerState>, stream: TcpStream, addr: SocketAddr) {
+    info!("[{}:{}] Admin connected.", addr.ip(), addr.port());
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        let reply = admin_command(&ss, line.trim()).await;
+        if writer
+            .write_all(format!("{}\n", reply).as_bytes())
+            .await
+            .is_err()
+        {
+            break;
+        }
+    }
+    info!("[{}:{}] Admin disconnected.", addr.ip(), addr.port());
+}
+
+async fn admin_command(ss: &ServerState, line: &str) -> String {
+    let (command, args) = line.split_once(' ').unwrap_or((line, ""));
+    match command {
+        "help" => "commands: dump-history, game <MATCH_ID>, help, matches, notice <TEXT>, stats"
+            .to_string(),
+        "dump-history" => dump_history(ss).await,
+        "game" => match args.parse() {
+            Ok(match_id) => game(ss, match_id).await,
+            Err(_) => "error: game needs a match id".to_string(),
+        },
+        "matches" => matches(ss).await,
+        "notice" if args.is_empty() => "error: notice needs a text".to_string(),
+        "notice" => match ss.notice(args.to_string()) {
+            Ok(n) => format!("ok, queued for {} connections", n),
+            Err(e) => format!("error: {}", e),
+        },
+        "stats" => stats(ss).await,
+        _ => format!("error: unknown command {:?}, try help", command),
+    }
+}
+
+// public waiting matches as a json array, private ones are never in public_matches
+async fn matches(ss: &ServerState) -> String {
+    let matches: Vec<String> = ss
+        .snapshot()
+        .await
+        .public_matches
+        .iter()
+        .map(|m| {
+            // names are plain ascii, debug formatting is valid json for them
+            format!(
+                "{{\"passcode\":{:?},\"variant\":{:?},\"color\":{:?},\"clock\":{:?}}}",
+                passcode_to_notation(m.passcode),
+                m.variant.to_string(),
+                m.color.to_string(),
+                m.clock.to_string()
+            )
+        })
+        .collect();
+    format!("[{}]", matches.join(","))
+}
+
+// recent matches as a hex encoded blob, oldest first, see parse_history_dump
+async fn dump_history(ss: &ServerState) -> String {
+    let entries: Vec<HistoryDumpEntry> = ss
+        .server_history_matches
+        .lock()
+        .await
+        .iter()
+        .map(|(match_id, m)| HistoryDumpEntry::new(*match_id, m))
+        .collect();
+    pack_history_dump(&entries)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/* relayed actions of a recent match separated by "; ", oldest first
+e.g. "12s White Move (0T1)c2 -> (0T1)c4; 15s White Submit Moves"
+*/
+async fn game(ss: &ServerState, match_id: MatchId) -> String {
+    if ss.config.timeline_length == 0 {
+        return "error: timeline_length is 0".to_string();
+    }
+    match ss.server_history_matches.lock().await.get(&match_id) {
+        Some(m) if m.timeline.is_empty() => "no actions".to_string(),
+        Some(m) => {
+            let actions: Vec<String> = m.timeline.iter().map(describe_action).collect();
+            actions.join("; ")
+        }
+        None => format!("error: match {} is not in the history", match_id),
+    }
+}
+
+fn describe_action(a: &C2SOrS2CActionBody) -> String {
+    let square = |c: Coordinate| {
+        // files past z can't be lettered, only huge custom boards get there
+        let file = u8::try_from(c.x)
+            .ok()
+            .filter(|x| *x < 26)
+            .map_or(format!("x{}", c.x), |x| char::from(b'a' + x).to_string());
+        format!("({}T{}){}{}", c.l, c.t, file, c.y + 1)
+    };
+    let prefix = format!("{}s {} {}", a.seconds_passed, a.color, a.action_type);
+    match a.action_type {
+        ActionType::Move => format!("{} {} -> {}", prefix, square(a.src), square(a.dst)),
+        ActionType::DisplayCheckReason => {
+            format!("{} ({}T{}) {}", prefix, a.src.l, a.src.t, a.src.board_color)
+        }
+        _ => prefix,
+    }
+}
+
+async fn stats(ss: &ServerState) -> String {
+    let snapshot = ss.snapshot().await;
+    let mut counts: Vec<(Variant, u64)> = snapshot
+        .variant_counts
+        .iter()
+        .map(|(v, n)| (*v, *n))
+        .collect();
+    counts.sort_by_key(|(v, n)| (Reverse(*n), *v as i64));
+    let created: Vec<String> = counts.iter().map(|(v, n)| format!("{} {}", v, n)).collect();
+    // closed connections only, averaged over the uptime
+    let bytes_read = ss.bytes_read.load(Ordering::Relaxed);
+    let bytes_written = ss.bytes_written.load(Ordering::Relaxed);
+    let uptime = ss.instant_start.elapsed().as_secs_f64();
+    let messages_read = MessageType::describe_counts(&*ss.messages_read.lock().await);
+    format!(
+        "connections {}, waiting {}, playing {}, oversized frames {}, observer dropped {}, bytes read {}, bytes written {}, throughput {:.0} B/s, created: {}, durations: {}, messages: {}",
+        snapshot.connections,
+        snapshot.waiting_matches,
+        snapshot.playing_matches(),
+        ss.oversized_frames.load(Ordering::Relaxed),
+        ss.observer_dropped.load(Ordering::Relaxed),
+        bytes_read,
+        bytes_written,
+        (bytes_read + bytes_written) as f64 / uptime,
+        created.join(", "),
+        durations(ss).join(", "),
+        messages_read
+    )
+}
+
+// e.g. "<1m 3", "1-5m 2", "15m+ 0"
+fn durations(ss: &ServerState) -> Vec<String> {
+    let minutes: Vec<u64> = MATCH_DURATION_BUCKETS.iter().map(|s| s / 60).collect();
+    ss.match_durations
+        .iter()
+        .enumerate()
+        .map(|(i, n)| {
+            let label = match (i.checked_sub(1).map(|i| minutes[i]), minutes.get(i)) {
+                (None, Some(upper)) => format!("<{}m", upper),
+                (Some(lower), Some(upper)) => format!("{}-{}m", lower, upper),
+                (Some(lower), None) => format!("{}m+", lower),
+                (None, None) => unreachable!(),
+            };
+            format!("{} {}", label, n.load(Ordering::Relaxed))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datatype::*;
+    use crate::server::ServerConfig;
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    async fn get(io: &mut MessageIO) -> Message {
+        timeout(Duration::from_secs(10), io.get())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap()
+    }
+
+    async fn put(io: &mut MessageIO, msg: Message) {
+        io.put(msg).await.unwrap();
+        io.flush().await.unwrap();
+    }
+
+    // greeted with the notice extension, with the reply to msg
+    async fn connect(
+        ss: &Arc<ServerState>,
+        running: &watch::Sender<bool>,
+        msg: Message,
+    ) -> (MessageIO, S2CMatchCreateOrJoinResultBody) {
+        let mut io = ss.connect_virtual(running.subscribe());
+        let greet = C2SGreetBody {
+            version1: GREET_VERSION.0,
+            version2: GREET_VERSION.1,
+            extensions: EXTENSION_SERVER_NOTICE,
+        };
+        put(&mut io, Message::C2SGreet(greet)).await;
+        assert!(matches!(get(&mut io).await, Message::S2CGreet));
+        put(&mut io, msg).await;
+        match get(&mut io).await {
+            Message::S2CMatchCreateOrJoinResult(body) => (io, body),
+            other => panic!("Expected S2CMatchCreateOrJoinResult, got {:?}.", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn notices_reach_waiting_and_playing_connections() {
+        let ss = Arc::new(ServerState::new(ServerConfig::default()));
+        let running = watch::channel(true).0;
+        let m = MatchSettingsBuilder::new()
+            .color(OptionalColorWithRandom::White)
+            .clock(OptionalClock::NoClock)
+            .visibility(Visibility::Private)
+            .build()
+            .unwrap();
+        let create = Message::C2SMatchCreateOrJoin(C2SMatchCreateOrJoinBody::Create(m));
+        let (mut waiting, _) = connect(&ss, &running, create.clone()).await;
+        let (mut white, S2CMatchCreateOrJoinResultBody::Success(m)) =
+            connect(&ss, &running, create).await
+        else {
+            panic!("Expected a created match.");
+        };
+        let passcode = m.passcode;
+        let join = Message::C2SMatchCreateOrJoin(C2SMatchCreateOrJoinBody::Join(passcode));
+        let (mut black, _) = connect(&ss, &running, join).await;
+        for io in [&mut white, &mut black] {
+            assert!(matches!(get(io).await, Message::S2CMatchStart(_)));
+        }
+
+        assert_eq!(
+            admin_command(&ss, "notice hi").await,
+            "ok, queued for 3 connections"
+        );
+        for io in [&mut waiting, &mut white, &mut black] {
+            match get(io).await {
+                Message::S2CServerNotice(text) => assert_eq!(text, "hi"),
+                other => panic!("Expected S2CServerNotice, got {:?}.", other),
+            }
+        }
+        assert_eq!(
+            admin_command(&ss, "notice").await,
+            "error: notice needs a text"
+        );
+    }
+}
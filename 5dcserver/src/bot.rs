@@ -0,0 +1,111 @@
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio::time::timeout;
+use tracing::{info, warn};
+
+use crate::datatype::*;
+use crate::server::{stamp_action, ServerState};
+
+// what the host sees as the opponent, never a hash of a real address in practice
+pub const BOT_PEER_ID: PeerId = 0;
+
+/* echo bot, joins a waiting match like a real joiner but over the internal channels only
+every move of the human is mirrored onto the other side of the board once submitted
+it knows no rules, so it resigns when there is nothing to mirror, including playing white
+*/
+pub async fn run_echo_bot(
+    ss: Arc<ServerState>,
+    mut rx: mpsc::Receiver<Message>,
+    visibility: Visibility,
+) {
+    let tx = match recv(&ss, &mut rx).await {
+        Some(Message::InternalInitialize(tx)) => tx,
+        _ => return,
+    };
+    if !send(&ss, &tx, Message::InternalJoin(BOT_PEER_ID)).await {
+        return;
+    }
+    let body = match recv(&ss, &mut rx).await {
+        Some(Message::InternalMatchStart(body)) => body,
+        _ => return,
+    };
+    let m = MatchSettings::new(body.m, visibility);
+    ss.insert_history_match(body.match_id, m).await;
+    info!("Echo bot joined match {}.", body.match_id);
+    if body.m.color == OptionalColorWithRandom::White {
+        resign(&ss, &tx, body.match_id).await;
+        return;
+    }
+    let (_, height) = board_dimensions(body.m.variant);
+    let mut turn = Vec::new();
+    // ends when the human forfeits or disconnects
+    while let Some(msg) = rx.recv().await {
+        let Message::InternalAction(action) = msg else {
+            break;
+        };
+        match action.action_type {
+            ActionType::Move => turn.push(mirrored(action, height)),
+            ActionType::UndoMove => {
+                turn.pop();
+            }
+            ActionType::SubmitMoves if turn.is_empty() => {
+                resign(&ss, &tx, body.match_id).await;
+                return;
+            }
+            ActionType::SubmitMoves => {
+                let submit = C2SOrS2CActionBody {
+                    color: action.color.reversed(),
+                    ..action
+                };
+                for mut action in turn.drain(..).chain([submit]) {
+                    stamp_action(&ss, body.match_id, &mut action).await;
+                    if !send(&ss, &tx, Message::InternalAction(action)).await {
+                        return;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+// same move seen from the other side, e.g. (0T1)e2 -> (0T1)e4 becomes (0T1)e7 -> (0T1)e5
+fn mirrored(action: C2SOrS2CActionBody, height: u8) -> C2SOrS2CActionBody {
+    let height = i64::from(height);
+    let mirror = |c: Coordinate| Coordinate {
+        l: -c.l,
+        board_color: c.board_color.reversed(),
+        y: height - 1 - c.y,
+        ..c
+    };
+    C2SOrS2CActionBody {
+        color: action.color.reversed(),
+        src: mirror(action.src),
+        dst: mirror(action.dst),
+        ..action
+    }
+}
+
+async fn resign(ss: &ServerState, tx: &mpsc::Sender<Message>, match_id: MatchId) {
+    info!("Echo bot resigned match {}.", match_id);
+    send(ss, tx, Message::InternalForfeit(ForfeitReason::Resign)).await;
+}
+
+async fn recv(ss: &ServerState, rx: &mut mpsc::Receiver<Message>) -> Option<Message> {
+    timeout(ss.config.handshake_timeout, rx.recv())
+        .await
+        .ok()
+        .flatten()
+}
+
+// false once the human is gone or stopped reading, the bot just leaves then
+async fn send(ss: &ServerState, tx: &mpsc::Sender<Message>, msg: Message) -> bool {
+    match timeout(ss.config.lag_timeout, tx.send(msg)).await {
+        Ok(Ok(())) => true,
+        Ok(Err(_)) => false,
+        Err(_) => {
+            warn!("Echo bot left, the opponent is lagging.");
+            false
+        }
+    }
+}
@@ -0,0 +1,145 @@
+use std::collections::HashSet;
+use std::net::IpAddr;
+
+/* set of CIDR blocks, e.g. "10.0.0.0/8", "2001:db8::/32" or a single address
+ipv4 is kept in the ipv4-mapped ipv6 space so one lookup covers both families
+*/
+#[derive(Debug, Clone, Default)]
+pub struct CidrSet {
+    prefixes: Vec<(u32, HashSet<u128>)>, // masked networks grouped by prefix length
+}
+
+fn mask(bits: u32) -> u128 {
+    u128::MAX.checked_shl(128 - bits).unwrap_or(0)
+}
+
+fn to_u128(ip: IpAddr) -> u128 {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => u128::from(ip.to_ipv6_mapped()),
+        IpAddr::V6(ip) => u128::from(ip),
+    }
+}
+
+fn parse_cidr(s: &str) -> Option<(u128, u32)> {
+    let (ip, bits) = match s.split_once('/') {
+        Some((ip, bits)) => (ip, Some(bits.parse::<u32>().ok()?)),
+        None => (s, None),
+    };
+    let ip = ip.parse::<IpAddr>().ok()?;
+    let bits = match ip {
+        IpAddr::V4(_) if bits.unwrap_or(32) <= 32 => 96 + bits.unwrap_or(32),
+        IpAddr::V6(_) if bits.unwrap_or(128) <= 128 => bits.unwrap_or(128),
+        _ => return None,
+    };
+    Some((to_u128(ip) & mask(bits), bits))
+}
+
+impl CidrSet {
+    pub fn parse<S: AsRef<str>>(blocks: &[S]) -> Result<Self, String> {
+        let mut set = CidrSet::default();
+        for block in blocks {
+            let (network, bits) = parse_cidr(block.as_ref().trim())
+                .ok_or_else(|| format!("Malformed CIDR block {:?}.", block.as_ref()))?;
+            match set.prefixes.iter_mut().find(|(b, _)| *b == bits) {
+                Some((_, networks)) => {
+                    networks.insert(network);
+                }
+                None => set.prefixes.push((bits, HashSet::from([network]))),
+            }
+        }
+        Ok(set)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.prefixes.is_empty()
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = to_u128(ip);
+        self.prefixes
+            .iter()
+            .any(|(bits, networks)| networks.contains(&(ip & mask(*bits))))
+    }
+}
+
+// an empty allow list allows everyone, the deny list wins over the allow list
+#[derive(Debug, Clone, Default)]
+pub struct IpFilter {
+    pub allow: CidrSet,
+    pub deny: CidrSet,
+}
+
+impl IpFilter {
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        (self.allow.is_empty() || self.allow.contains(ip)) && !self.deny.contains(ip)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn filter(allow: &[&str], deny: &[&str]) -> IpFilter {
+        IpFilter {
+            allow: CidrSet::parse(allow).unwrap(),
+            deny: CidrSet::parse(deny).unwrap(),
+        }
+    }
+
+    #[test]
+    fn allowed_ip_is_permitted() {
+        let f = filter(&["10.0.0.0/8", "2001:db8::/32"], &[]);
+        assert!(f.permits(ip("10.1.2.3")));
+        assert!(f.permits(ip("2001:db8::1")));
+        assert!(IpFilter::default().permits(ip("192.0.2.1")));
+    }
+
+    #[test]
+    fn denied_ip_is_refused() {
+        let f = filter(&["10.0.0.0/8"], &["10.0.0.1"]);
+        assert!(!f.permits(ip("10.0.0.1")));
+        assert!(f.permits(ip("10.0.0.2")));
+        assert!(!filter(&[], &["2001:db8::/32"]).permits(ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn ip_outside_the_allow_list_is_refused() {
+        let f = filter(&["10.0.0.0/8", "2001:db8::/32"], &[]);
+        assert!(!f.permits(ip("11.0.0.1")));
+        assert!(!f.permits(ip("2001:db9::1")));
+    }
+
+    #[test]
+    fn ipv4_mapped_ipv6_is_ipv4() {
+        let f = filter(&["10.0.0.0/8"], &["10.0.0.1"]);
+        assert!(f.permits(ip("::ffff:10.1.2.3")));
+        assert!(!f.permits(ip("::ffff:10.0.0.1")));
+        assert!(!f.permits(ip("::ffff:11.0.0.1")));
+        // and the other way round
+        let f = filter(&["::ffff:10.0.0.0/104"], &[]);
+        assert!(f.permits(ip("10.1.2.3")));
+    }
+
+    #[test]
+    fn zero_prefix_covers_its_family() {
+        let v4 = CidrSet::parse(&["0.0.0.0/0"]).unwrap();
+        assert!(v4.contains(ip("0.0.0.0")));
+        assert!(v4.contains(ip("255.255.255.255")));
+        assert!(!v4.contains(ip("2001:db8::1")));
+        let all = CidrSet::parse(&["::/0"]).unwrap();
+        assert!(all.contains(ip("2001:db8::1")));
+        assert!(all.contains(ip("10.0.0.1")));
+        assert!(!filter(&[], &["::/0"]).permits(ip("192.0.2.1")));
+    }
+
+    #[test]
+    fn malformed_blocks_are_rejected() {
+        for block in ["10.0.0.0/33", "::/129", "10.0.0/8", "10.0.0.0/x"] {
+            assert!(CidrSet::parse(&[block]).is_err(), "{}", block);
+        }
+    }
+}
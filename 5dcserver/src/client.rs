@@ -0,0 +1,219 @@
+use futures::stream::{SelectAll, SplitSink, SplitStream};
+use futures::{SinkExt, Stream, StreamExt};
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::time::timeout;
+
+use crate::datatype::{MatchId, Message, MessageIO, ProtocolError, Result};
+
+const RTT_REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+
+type MatchStream = futures::stream::Map<
+    SplitStream<MessageIO>,
+    Box<dyn FnMut(Result<Message>) -> (MatchId, Result<Message>) + Send + Sync>,
+>;
+
+/* watches several matches at once, one connection per match
+yields the messages of every connection tagged with its match, ends when all are closed
+*/
+#[derive(Default)]
+pub struct MultiplexClient {
+    sinks: HashMap<MatchId, SplitSink<MessageIO, Message>>,
+    streams: SelectAll<MatchStream>,
+}
+
+impl MultiplexClient {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // replaces the sink of a match added twice, both streams are still polled
+    pub fn add(&mut self, match_id: MatchId, io: MessageIO) {
+        let (sink, stream) = io.split();
+        self.sinks.insert(match_id, sink);
+        self.streams
+            .push(stream.map(Box::new(move |result| (match_id, result))));
+    }
+
+    pub fn match_ids(&self) -> impl Iterator<Item = MatchId> + '_ {
+        self.sinks.keys().copied()
+    }
+
+    pub async fn put(&mut self, match_id: MatchId, msg: Message) -> Result<()> {
+        match self.sinks.get_mut(&match_id) {
+            Some(sink) => sink.send(msg).await,
+            None => err_invalid_data!("Match {} is not watched.", match_id),
+        }
+    }
+
+    // closes the connection, its remaining messages are still yielded
+    pub async fn remove(&mut self, match_id: MatchId) -> Result<()> {
+        match self.sinks.remove(&match_id) {
+            Some(mut sink) => sink.close().await,
+            None => Ok(()),
+        }
+    }
+}
+
+impl Stream for MultiplexClient {
+    type Item = (MatchId, Result<Message>);
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.streams.poll_next_unpin(cx)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct RttStats {
+    pub samples: usize,
+    pub min: Duration,
+    pub mean: Duration,
+    pub p50: Duration,
+    pub p99: Duration,
+}
+
+/* round trips of C2SMatchListRequest, the protocol has no ping and it's answered in any state
+the connection must be greeted already, messages other than S2CMatchList in between are skipped
+*/
+pub async fn measure_rtt(io: &mut MessageIO, samples: usize) -> Result<RttStats> {
+    if samples == 0 {
+        return err_invalid_data!("At least one sample is needed.");
+    }
+    let mut rtts = Vec::with_capacity(samples);
+    for _ in 0..samples {
+        let sent = Instant::now();
+        io.put(Message::C2SMatchListRequest).await?;
+        io.flush().await?;
+        loop {
+            match timeout(RTT_REPLY_TIMEOUT, io.get()).await {
+                Ok(Ok(Some(Message::S2CMatchList(_)))) => break,
+                Ok(Ok(Some(_))) => continue,
+                Ok(Ok(None)) => return Err(ProtocolError::Disconnected),
+                Ok(Err(e)) => return Err(e),
+                Err(_) => return err_timeout!("No S2CMatchList within {:?}.", RTT_REPLY_TIMEOUT),
+            }
+        }
+        rtts.push(sent.elapsed());
+    }
+    rtts.sort();
+    let percentile = |p: usize| rtts[(samples * p / 100).min(samples - 1)];
+    Ok(RttStats {
+        samples,
+        min: rtts[0],
+        mean: rtts.iter().sum::<Duration>() / samples as u32,
+        p50: percentile(50),
+        p99: percentile(99),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datatype::S2CMatchListNonhostBody;
+
+    async fn next(client: &mut MultiplexClient) -> Option<(MatchId, Result<Message>)> {
+        timeout(Duration::from_secs(10), client.next())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn messages_are_tagged_with_their_match() {
+        let mut client = MultiplexClient::new();
+        let mut servers = Vec::new();
+        for match_id in [1, 2] {
+            let (io, server) = MessageIO::new_duplex();
+            client.add(match_id, io.client_side());
+            servers.push(server);
+        }
+        let mut match_ids: Vec<_> = client.match_ids().collect();
+        match_ids.sort();
+        assert_eq!(match_ids, [1, 2]);
+
+        // each put reaches only its own server
+        client.put(2, Message::C2SForfeit).await.unwrap();
+        client.put(1, Message::C2SMatchListRequest).await.unwrap();
+        assert!(matches!(
+            servers[0].get().await,
+            Ok(Some(Message::C2SMatchListRequest))
+        ));
+        assert!(matches!(
+            servers[1].get().await,
+            Ok(Some(Message::C2SForfeit))
+        ));
+        assert!(client.put(3, Message::C2SForfeit).await.is_err());
+
+        servers[1].put(Message::S2COpponentLeft).await.unwrap();
+        servers[1].flush().await.unwrap();
+        assert!(matches!(
+            next(&mut client).await,
+            Some((2, Ok(Message::S2COpponentLeft)))
+        ));
+        servers[0].put(Message::S2CGreet).await.unwrap();
+        servers[0].flush().await.unwrap();
+        assert!(matches!(
+            next(&mut client).await,
+            Some((1, Ok(Message::S2CGreet)))
+        ));
+
+        // removing closes the connection, the stream ends once both are closed
+        client.remove(1).await.unwrap();
+        assert!(matches!(servers[0].get().await, Ok(None)));
+        assert!(client.put(1, Message::C2SForfeit).await.is_err());
+        drop(servers);
+        assert!(next(&mut client).await.is_none());
+    }
+
+    // answers every request with a notice first, which measure_rtt skips
+    fn loopback() -> MessageIO {
+        let (io, mut server) = MessageIO::new_duplex();
+        tokio::spawn(async move {
+            while let Ok(Some(Message::C2SMatchListRequest)) = server.get().await {
+                let notice = Message::S2CServerNotice("Not a reply.".to_string());
+                let list = Message::nonhost_match_list(S2CMatchListNonhostBody::empty(false));
+                if server.put(notice).await.is_err() || server.put(list).await.is_err() {
+                    break;
+                }
+                if server.flush().await.is_err() {
+                    break;
+                }
+            }
+        });
+        io.client_side()
+    }
+
+    fn assert_ordered(stats: RttStats) {
+        assert!(stats.min <= stats.mean);
+        assert!(stats.min <= stats.p50);
+        assert!(stats.p50 <= stats.p99);
+    }
+
+    #[tokio::test]
+    async fn round_trips_are_measured_over_the_loopback() {
+        let mut io = loopback();
+        let stats = measure_rtt(&mut io, 20).await.unwrap();
+        assert_eq!(stats.samples, 20);
+        assert_ordered(stats);
+
+        // a single sample is every statistic at once
+        let stats = measure_rtt(&mut io, 1).await.unwrap();
+        assert_eq!(stats.samples, 1);
+        assert_eq!(stats.min, stats.p99);
+        assert_ordered(stats);
+
+        assert!(matches!(
+            measure_rtt(&mut io, 0).await,
+            Err(ProtocolError::InvalidData(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn disconnect_before_the_reply_is_an_error() {
+        let (io, server) = MessageIO::new_duplex();
+        drop(server);
+        let mut io = io.client_side();
+        assert!(measure_rtt(&mut io, 1).await.is_err());
+    }
+}
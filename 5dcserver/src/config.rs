@@ -0,0 +1,440 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fs;
+use std::io::ErrorKind;
+use std::time::Duration;
+use tracing::warn;
+
+use crate::cidr::{CidrSet, IpFilter};
+use crate::datatype::*;
+use crate::server::{ActionLimitPolicy, InvalidMessagePolicy, LagPolicy, ServerConfig};
+
+// written where the config file is missing
+pub fn default_config() -> toml::Table {
+    toml::toml! {
+        access_log = ""
+        access_log_salt = ""
+        addr = "0.0.0.0"
+        admin = ""
+        allow_cidr = []
+        allow_reset_puzzle = false
+        bot_fill_timeout = 0
+        deny_cidr = []
+        flush_interval_ms = 0
+        handshake_timeout = 5
+        history_size = 13
+        lag_timeout = 5
+        limit_actions_per_second = 0
+        limit_connection_duration = 0
+        limit_connections = 0
+        limit_idle_duration = 0
+        limit_message_length = 4096
+        limit_pending_frames = 64
+        listen_backlog = 1024
+        log_config = false
+        match_idle_timeout = 0
+        max_client_version = []
+        min_client_version = []
+        observer = ""
+        on_action_limit = "disconnect"
+        on_invalid_message = "disconnect"
+        on_lag = "disconnect"
+        port = 39005
+        proxy_protocol = false
+        server_name = ""
+        shutdown_timeout = 10
+        strict_actions = false
+        strict_messages = false
+        tcp_nodelay = true
+        timeline_length = 0
+        trace = false
+        trace_moves = false
+        variants = []
+        variant_weights = {}
+        waiting_match_ttl = 0
+        websocket = ""
+    }
+}
+
+// a missing file is written with default_config unless write_default is false
+pub fn load_config(path: &str, write_default: bool) -> Result<toml::Table, Box<dyn Error>> {
+    // reading a directory fails with an error that doesn't name the cause on every platform
+    if fs::metadata(path).is_ok_and(|metadata| metadata.is_dir()) {
+        Err(format!(
+            "Config path {:?} is a directory, not a file.",
+            path
+        ))?;
+    }
+    match fs::read(path) {
+        Ok(config) => Ok(toml::from_str(String::from_utf8(config)?.as_str())?),
+        Err(e) if e.kind() == ErrorKind::NotFound && !write_default => {
+            Err(format!("Config file {:?} doesn't exist.", path))?
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            let config = default_config();
+            fs::write(path, config.to_string())
+                .map_err(|e| format!("Failed to write the default config to {:?}: {}", path, e))?;
+            Ok(config)
+        }
+        Err(e) if e.kind() == ErrorKind::PermissionDenied => {
+            Err(format!("Permission denied reading config {:?}.", path))?
+        }
+        Err(e) => Err(format!("Failed to read config {:?}: {}", path, e))?,
+    }
+}
+
+pub fn get_config<'a, T: toml::macros::Deserialize<'a>>(
+    config: &toml::value::Table,
+    name: &str,
+    default: T,
+) -> Result<T, Box<dyn Error>> {
+    match config.get(name) {
+        Some(value) => match value.clone().try_into() {
+            Ok(value) => Ok(value),
+            Err(e) => Err(format!(
+                "Invalid value for {:?}: {}",
+                name,
+                e.to_string().trim_end()
+            ))?,
+        },
+        None => Ok(default),
+    }
+}
+
+// seconds, 0 means no limit
+pub fn get_duration(
+    config: &toml::value::Table,
+    name: &str,
+) -> Result<Option<Duration>, Box<dyn Error>> {
+    Ok(match get_config(config, name, 0)? {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    })
+}
+
+// [major, minor], [] means no bound
+pub fn get_client_version(
+    config: &toml::value::Table,
+    name: &str,
+) -> Result<Option<(i64, i64)>, Box<dyn Error>> {
+    match get_config::<Vec<i64>>(config, name, Vec::new())?[..] {
+        [] => Ok(None),
+        [major, minor] => Ok(Some((major, minor))),
+        _ => Err(format!(
+            "Invalid value for {:?}: expected [major, minor].",
+            name
+        ))?,
+    }
+}
+
+pub fn get_cidr_set(config: &toml::value::Table, name: &str) -> Result<CidrSet, Box<dyn Error>> {
+    let blocks: Vec<String> = get_config(config, name, Vec::new())?;
+    Ok(CidrSet::parse(&blocks).map_err(|e| format!("Invalid value for {:?}: {}", name, e))?)
+}
+
+// "listen" takes precedence over the scalar "addr" and "port"
+pub fn get_listen_addrs(config: &toml::value::Table) -> Result<Vec<String>, Box<dyn Error>> {
+    if config.contains_key("listen") {
+        let listen: Vec<String> = get_config(config, "listen", Vec::new())?;
+        if listen.is_empty() {
+            Err("No listen address is configured.")?;
+        }
+        Ok(listen)
+    } else {
+        let addr: String = get_config(config, "addr", "0.0.0.0".to_string())?;
+        let port: u16 = get_config(config, "port", 39005)?;
+        if addr.contains(':') {
+            Ok(vec![format!("[{}]:{}", addr, port)])
+        } else {
+            Ok(vec![format!("{}:{}", addr, port)])
+        }
+    }
+}
+
+pub fn get_server_config(config: &toml::value::Table) -> Result<ServerConfig, Box<dyn Error>> {
+    let variants: Vec<i64> = get_config(config, "variants", Vec::new())?;
+    let variants = {
+        let mut variants_set = HashSet::new();
+        if variants.is_empty() {
+            variants_set.extend(Variant::all());
+        } else {
+            for i in variants {
+                variants_set.insert(try_i64_to_enum(i)?);
+            }
+        }
+        variants_set
+    };
+    // keys are variant ids, tables only have string keys
+    let weights: HashMap<String, u32> = get_config(config, "variant_weights", HashMap::new())?;
+    let mut variant_weights = HashMap::new();
+    for (id, weight) in weights {
+        let variant: Variant = match id.parse() {
+            Ok(id) => try_i64_to_enum(id)?,
+            Err(_) => Err(format!("Invalid variant id {:?} in variant_weights.", id))?,
+        };
+        if variant == Variant::Random || !variants.contains(&variant) {
+            Err(format!("{} in variant_weights can't be drawn.", variant))?;
+        }
+        variant_weights.insert(variant, weight);
+    }
+    if !variants
+        .iter()
+        .any(|v| *v != Variant::Random && variant_weights.get(v).copied().unwrap_or(1) > 0)
+    {
+        Err("At least one allowed variant other than Random needs a positive weight.")?;
+    }
+    let on_invalid_message =
+        match get_config(config, "on_invalid_message", "disconnect".to_string())?.as_str() {
+            "ignore" => InvalidMessagePolicy::Ignore,
+            "disconnect" => InvalidMessagePolicy::Disconnect,
+            other => Err(format!("Unknown on_invalid_message policy {:?}.", other))?,
+        };
+    let on_action_limit =
+        match get_config(config, "on_action_limit", "disconnect".to_string())?.as_str() {
+            "drop" => ActionLimitPolicy::Drop,
+            "disconnect" => ActionLimitPolicy::Disconnect,
+            other => Err(format!("Unknown on_action_limit policy {:?}.", other))?,
+        };
+    let on_lag = match get_config(config, "on_lag", "disconnect".to_string())?.as_str() {
+        "skip" => LagPolicy::Skip,
+        "disconnect" => LagPolicy::Disconnect,
+        other => Err(format!("Unknown on_lag policy {:?}.", other))?,
+    };
+    let min_client_version = get_client_version(config, "min_client_version")?;
+    let max_client_version = get_client_version(config, "max_client_version")?;
+    if let (Some(min), Some(max)) = (min_client_version, max_client_version) {
+        if min > max {
+            Err("min_client_version must be at most max_client_version.")?;
+        }
+    }
+    // outgoing messages are limited too
+    let limit_message_length = get_config(config, "limit_message_length", MESSAGE_LENGTH_MAX)?;
+    if limit_message_length < MESSAGE_LENGTH_MIN {
+        Err(format!(
+            "limit_message_length must be at least {}.",
+            MESSAGE_LENGTH_MIN
+        ))?;
+    }
+    let history_size = get_config(config, "history_size", 13)?;
+    if history_size < 1 {
+        Err("history_size must be at least 1.")?;
+    }
+    let ip_filter = IpFilter {
+        allow: get_cidr_set(config, "allow_cidr")?,
+        deny: get_cidr_set(config, "deny_cidr")?,
+    };
+    let server_name: String = get_config(config, "server_name", String::new())?;
+    if server_name.len() > NOTICE_LENGTH_MAX {
+        Err(format!(
+            "server_name must be at most {} bytes.",
+            NOTICE_LENGTH_MAX
+        ))?;
+    }
+    // ban_reset_puzzle is the old inverted name, still read so old configs keep their meaning
+    let allow_reset_puzzle = match config.get("ban_reset_puzzle") {
+        Some(_) if config.contains_key("allow_reset_puzzle") => {
+            Err("Only one of allow_reset_puzzle and ban_reset_puzzle may be set.")?
+        }
+        Some(_) => {
+            warn!("ban_reset_puzzle is deprecated, use allow_reset_puzzle instead.");
+            !get_config(config, "ban_reset_puzzle", true)?
+        }
+        None => get_config(config, "allow_reset_puzzle", false)?,
+    };
+    let flush_interval_ms: u64 = get_config(config, "flush_interval_ms", 0)?;
+    // undocumented, for testing client timeouts, never on by accident
+    let artificial_delay_ms: u64 = get_config(config, "artificial_delay_ms", 0)?;
+    if artificial_delay_ms > 0 && !get_config(config, "allow_debug_features", false)? {
+        Err("artificial_delay_ms needs allow_debug_features = true.")?;
+    }
+    Ok(ServerConfig {
+        allow_reset_puzzle,
+        artificial_delay: (artificial_delay_ms > 0)
+            .then(|| Duration::from_millis(artificial_delay_ms)),
+        bot_fill_timeout: get_duration(config, "bot_fill_timeout")?,
+        flush_interval: (flush_interval_ms > 0).then(|| Duration::from_millis(flush_interval_ms)),
+        handshake_timeout: Duration::from_secs(get_config(config, "handshake_timeout", 5)?),
+        history_size,
+        ip_filter,
+        lag_timeout: Duration::from_secs(get_config(config, "lag_timeout", 5)?),
+        limit_connection_duration: get_duration(config, "limit_connection_duration")?,
+        limit_connections: get_config(config, "limit_connections", 0)?,
+        limit_actions_per_second: get_config(config, "limit_actions_per_second", 0)?,
+        limit_idle_duration: get_duration(config, "limit_idle_duration")?,
+        limit_message_length,
+        limit_pending_frames: get_config(config, "limit_pending_frames", 64)?,
+        match_idle_timeout: get_duration(config, "match_idle_timeout")?,
+        max_client_version,
+        min_client_version,
+        on_action_limit,
+        on_invalid_message,
+        on_lag,
+        proxy_protocol: get_config(config, "proxy_protocol", false)?,
+        // undocumented, for tests
+        rng_seed: get_config(config, "rng_seed", None)?,
+        server_name,
+        strict_actions: get_config(config, "strict_actions", false)?,
+        strict_messages: get_config(config, "strict_messages", false)?,
+        timeline_length: get_config(config, "timeline_length", 0)?,
+        trace_moves: get_config(config, "trace_moves", false)?,
+        variants,
+        variant_weights,
+        waiting_match_ttl: get_duration(config, "waiting_match_ttl")?,
+    })
+}
+
+// after defaults are applied, the admin address and the salt are redacted so it can be shared
+pub fn resolved_config(
+    config: &toml::Table,
+    server_config: &ServerConfig,
+) -> Result<Vec<String>, Box<dyn Error>> {
+    let redacted = |value: String| {
+        if value.is_empty() {
+            "\"\"".to_string()
+        } else {
+            "<redacted>".to_string()
+        }
+    };
+    Ok(vec![
+        format!("listen = {:?}", get_listen_addrs(config)?),
+        format!(
+            "listen_backlog = {}",
+            get_config::<u32>(config, "listen_backlog", 1024)?
+        ),
+        format!("tcp_nodelay = {}", get_config(config, "tcp_nodelay", true)?),
+        format!(
+            "admin = {}",
+            redacted(get_config(config, "admin", String::new())?)
+        ),
+        format!(
+            "access_log = {:?}",
+            get_config(config, "access_log", String::new())?
+        ),
+        format!(
+            "access_log_salt = {}",
+            redacted(get_config(config, "access_log_salt", String::new())?)
+        ),
+        format!("trace = {}", get_config(config, "trace", false)?),
+        format!(
+            "observer = {:?}",
+            get_config(config, "observer", String::new())?
+        ),
+        format!(
+            "websocket = {:?}",
+            get_config(config, "websocket", String::new())?
+        ),
+        format!("{:#?}", server_config),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server_config(toml: &str) -> Result<ServerConfig, String> {
+        get_server_config(&toml.parse().unwrap()).map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn reset_puzzle_is_allowed_by_either_name() {
+        let allowed = |toml| server_config(toml).unwrap().allow_reset_puzzle;
+        assert!(!allowed(""));
+        assert!(allowed("allow_reset_puzzle = true"));
+        assert!(!allowed("allow_reset_puzzle = false"));
+        // the deprecated name is inverted
+        assert!(!allowed("ban_reset_puzzle = true"));
+        assert!(allowed("ban_reset_puzzle = false"));
+        assert_eq!(
+            server_config("allow_reset_puzzle = true\nban_reset_puzzle = false").unwrap_err(),
+            "Only one of allow_reset_puzzle and ban_reset_puzzle may be set."
+        );
+    }
+
+    #[test]
+    fn artificial_delay_needs_debug_features() {
+        assert_eq!(server_config("").unwrap().artificial_delay, None);
+        assert_eq!(
+            server_config("artificial_delay_ms = 300").unwrap_err(),
+            "artificial_delay_ms needs allow_debug_features = true."
+        );
+        assert_eq!(
+            server_config("artificial_delay_ms = 300\nallow_debug_features = true")
+                .unwrap()
+                .artificial_delay,
+            Some(Duration::from_millis(300))
+        );
+    }
+
+    #[test]
+    fn secrets_are_redacted_in_the_resolved_config() {
+        let resolved = |toml: &str| {
+            let config = toml.parse().unwrap();
+            let server_config = get_server_config(&config).unwrap();
+            resolved_config(&config, &server_config).unwrap().join("\n")
+        };
+        let lines = resolved("admin = \"127.0.0.1:39006\"\naccess_log_salt = \"pepper\"");
+        assert!(lines.contains("admin = <redacted>"));
+        assert!(lines.contains("access_log_salt = <redacted>"));
+        assert!(!lines.contains("39006"));
+        assert!(!lines.contains("pepper"));
+        // unset is shown as such, there is nothing to hide
+        let lines = resolved("");
+        assert!(lines.contains("admin = \"\""));
+        assert!(lines.contains("access_log_salt = \"\""));
+    }
+
+    // unique per test, removed by the test
+    fn scratch_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("5dcserver-{}-{}", std::process::id(), name));
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn directory_config_path_is_named() {
+        let path = scratch_path("directory");
+        fs::create_dir(&path).unwrap();
+        let e = load_config(&path, true).unwrap_err().to_string();
+        fs::remove_dir(&path).unwrap();
+        assert_eq!(
+            e,
+            format!("Config path {:?} is a directory, not a file.", path)
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unreadable_config_is_permission_denied() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = scratch_path("unreadable");
+        fs::write(&path, "").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o000)).unwrap();
+        // root reads it regardless, nothing to test then
+        let readable = fs::read(&path).is_ok();
+        let result = load_config(&path, true).map_err(|e| e.to_string());
+        fs::remove_file(&path).unwrap();
+        if !readable {
+            assert_eq!(
+                result.unwrap_err(),
+                format!("Permission denied reading config {:?}.", path)
+            );
+        }
+    }
+
+    #[test]
+    fn missing_config_is_written_only_when_asked() {
+        let path = scratch_path("missing");
+        assert_eq!(
+            load_config(&path, false).unwrap_err().to_string(),
+            format!("Config file {:?} doesn't exist.", path)
+        );
+        assert!(fs::metadata(&path).is_err());
+
+        let config = load_config(&path, true).unwrap();
+        assert_eq!(config, default_config());
+        // written as is, so the next start reads the same
+        let reread = load_config(&path, false);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(reread.unwrap(), config);
+    }
+}
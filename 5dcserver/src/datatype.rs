@@ -1,43 +1,107 @@
 use byteorder::{ByteOrder, LittleEndian};
-use bytes::{Bytes, BytesMut};
+use bytes::{Buf, Bytes, BytesMut};
 use enum_primitive::{enum_from_primitive, enum_from_primitive_impl, enum_from_primitive_impl_ty};
-use futures::{SinkExt, StreamExt};
+use futures::{ready, FutureExt, Sink, SinkExt, Stream, StreamExt};
+use rand::distributions::{Distribution, WeightedIndex};
 use rand::Rng;
-use std::collections::HashMap;
-use std::io::{Error, Result};
-use tokio::net::TcpStream;
-use tokio::sync::{broadcast, Mutex};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::ErrorKind;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio::sync::mpsc;
 use tokio::time::Instant;
-use tokio_util::codec::{Framed, LengthDelimitedCodec};
+use tokio_util::codec::{Framed, LengthDelimitedCodec, LengthDelimitedCodecError};
 use tracing::trace;
 
-pub const MESSAGE_LENGTH_MAX: usize = 4096; // >= 1008, prevent attacks
+use crate::server::ConnectionStateEnum;
+
+pub const MESSAGE_LENGTH_MAX: usize = 4096; // default limit, >= MESSAGE_LENGTH_MIN, prevent attacks
+pub const MESSAGE_LENGTH_MIN: usize = 1008 + 13 * 8; // S2CMatchList with outcomes
+pub const PASSCODE_MAX: Passcode = 2985983; // kkkkkk
+pub const NOTICE_LENGTH_MAX: usize = 256; // bytes of utf-8 in S2CServerNotice and S2CServerInfo
+pub const GREET_VERSION: (i64, i64) = (11, 16); // sent by the official client
+pub const VARIANT_ID_MAX: i64 = 46; // see analysis/variant_list.txt
 
 pub type Passcode = i64;
 pub type MatchId = i64;
+pub type PeerId = u64;
+pub type ConnectionId = u64; // unique per server run, unlike the address
+
+// extension flags, sent by analysis clients in the first unknown field of C2SGreet
+pub const EXTENSION_PEER_INFO: i64 = 1; // opponent identifier in S2CMatchStart
+pub const EXTENSION_SERVER_NOTICE: i64 = 2; // S2CServerNotice
+pub const EXTENSION_MATCH_OUTCOME: i64 = 4; // outcomes of history matches in S2CMatchList
+pub const EXTENSION_SERVER_INFO: i64 = 8; // S2CServerInfo after S2CGreet
+
+#[derive(Debug, thiserror::Error)]
+pub enum ProtocolError {
+    #[error("{0}")]
+    InvalidData(String),
+    #[error("Disconnected.")]
+    Disconnected,
+    #[error("{0}")]
+    LimitExceeded(String),
+    #[error("Frame exceeded limit_message_length.")]
+    FrameTooLong, // counted in ServerState::oversized_frames
+    #[error("{0}")]
+    Timeout(String),
+    #[error("{0}")]
+    Stalled(String), // the client stopped reading
+    #[error("Invalid message {kind} at state {state:?}.")]
+    UnexpectedMessage {
+        state: ConnectionStateEnum,
+        kind: &'static str, // see Message::kind_name, internal messages have no type
+    },
+    #[error("Undeclared transition {event:?} from {state:?}.")]
+    UndeclaredTransition {
+        state: ConnectionStateEnum,
+        event: &'static str, // a bug of the server, not of the client
+    },
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+pub type Result<T, E = ProtocolError> = std::result::Result<T, E>;
 
 #[macro_export]
 macro_rules! err_invalid_data {
     ( $($arg:tt)* ) => {
-        Err(std::io::Error::new(
-            std::io::ErrorKind::InvalidData,
-            format!($($arg)*),
-        ))
+        Err($crate::datatype::ProtocolError::InvalidData(format!($($arg)*)))
     };
 }
 #[macro_export]
-macro_rules! err_disconnected {
-    () => {
-        Err(std::io::Error::new(
-            std::io::ErrorKind::ConnectionAborted,
-            "Disconnected.",
-        ))
+macro_rules! err_limit {
+    ( $($arg:tt)* ) => {
+        Err($crate::datatype::ProtocolError::LimitExceeded(format!($($arg)*)))
+    };
+}
+#[macro_export]
+macro_rules! err_timeout {
+    ( $($arg:tt)* ) => {
+        Err($crate::datatype::ProtocolError::Timeout(format!($($arg)*)))
+    };
+}
+
+// player-facing names for logs
+macro_rules! display_names {
+    ( $t:ident { $($v:ident => $name:expr),* $(,)? } ) => {
+        impl std::fmt::Display for $t {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                f.write_str(match self {
+                    $($t::$v => $name,)*
+                })
+            }
+        }
     };
 }
 
 enum_from_primitive! {
     #[repr(i64)]
     #[derive(Debug, Copy, Clone, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize))]
     pub enum OptionalColorWithRandom {
         None = 0,
         Random = 1,
@@ -45,23 +109,29 @@ enum_from_primitive! {
         Black = 3
     }
 }
+display_names!(OptionalColorWithRandom {
+    None => "None",
+    Random => "Random",
+    White => "White",
+    Black => "Black",
+});
 impl OptionalColorWithRandom {
     pub fn reversed(&self) -> Self {
         match self {
             OptionalColorWithRandom::White => OptionalColorWithRandom::Black,
             OptionalColorWithRandom::Black => OptionalColorWithRandom::White,
-            _ => self.clone(),
+            _ => *self,
         }
     }
 
-    pub fn determined(&self) -> Self {
+    pub fn determined<R: Rng + ?Sized>(&self, rng: &mut R) -> Self {
         match self {
-            OptionalColorWithRandom::Random => match rand::thread_rng().gen_range(0..=1) {
+            OptionalColorWithRandom::Random => match rng.gen_range(0..=1) {
                 0 => OptionalColorWithRandom::White,
                 1 => OptionalColorWithRandom::Black,
                 _ => unreachable!(),
             },
-            _ => self.clone(),
+            _ => *self,
         }
     }
 }
@@ -76,21 +146,26 @@ impl From<Color> for OptionalColorWithRandom {
 enum_from_primitive! {
     #[repr(i64)]
     #[derive(Debug, Copy, Clone, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize))]
     pub enum Color {
         White = 0,
         Black = 1
     }
 }
+display_names!(Color {
+    White => "White",
+    Black => "Black",
+});
 impl Color {
     pub fn reversed(&self) -> Self {
         match self {
-            Color::White => Color::White,
-            Color::Black => Color::Black,
+            Color::White => Color::Black,
+            Color::Black => Color::White,
         }
     }
 }
 impl TryFrom<OptionalColorWithRandom> for Color {
-    type Error = Error;
+    type Error = ProtocolError;
 
     fn try_from(value: OptionalColorWithRandom) -> Result<Self> {
         match value {
@@ -103,6 +178,7 @@ impl TryFrom<OptionalColorWithRandom> for Color {
 enum_from_primitive! {
     #[repr(i64)]
     #[derive(Debug, Copy, Clone, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize))]
     pub enum OptionalClock {
         None = 0,
         NoClock = 1,
@@ -111,9 +187,17 @@ enum_from_primitive! {
         Long = 4
     }
 }
+display_names!(OptionalClock {
+    None => "None",
+    NoClock => "No Clock",
+    Short => "Short",
+    Medium => "Medium",
+    Long => "Long",
+});
 enum_from_primitive! {
     #[repr(i64)]
     #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize))]
     pub enum Variant {
         Standard = 1,
         Random = 34,
@@ -163,28 +247,89 @@ enum_from_primitive! {
         CheckmatePracticePawns = 33,
     }
 }
+display_names!(Variant {
+    Standard => "Standard",
+    Random => "Random",
+    SimpleNoBishops => "Simple - No Bishops",
+    SimpleNoKnights => "Simple - No Knights",
+    SimpleNoRooks => "Simple - No Rooks",
+    SimpleNoQueens => "Simple - No Queens",
+    SimpleKnightsVsBishops => "Simple - Knights vs. Bishops",
+    SimpleSimpleSet => "Simple - Simple Set",
+    Small => "Small",
+    SmallFlipped => "Small - Flipped",
+    SmallCentered => "Small - Centered",
+    SmallOpen => "Small - Open",
+    VerySmall => "Very Small",
+    VerySmallOpen => "Very Small - Open",
+    MiscTimelineInvasion => "Misc - Timeline Invasion",
+    MiscTimelineFormations => "Misc - Timeline Formations",
+    MiscTimelineTactician => "Misc - Timeline Tactician",
+    MiscTimelineStrategos => "Misc - Timeline Strategos",
+    MiscTimelineBattleground => "Misc - Timeline Battleground",
+    MiscTimelineSkirmish => "Misc - Timeline Skirmish",
+    MiscTimelineFragments => "Misc - Timeline Fragments",
+    MiscTimelineMarauders => "Misc - Timeline Marauders",
+    StandardHalfReflected => "Standard - Half Reflected",
+    StandardDefendedPawn => "Standard - Defended Pawn",
+    StandardPrincess => "Standard - Princess",
+    StandardTurnZero => "Standard - Turn Zero",
+    StandardTwoTimelines => "Standard - Two Timelines",
+    StandardReversedRoyalty => "Standard - Reversed Royalty",
+    MiscExcessive => "Misc - Excessive",
+    MiscGlobalWarming => "Misc - Global Warming",
+    MiscKingofKings => "Misc - King of Kings",
+    MiscRoyalQueenShowdown => "Misc - Royal Queen Showdown",
+    FocusedJustKnights => "Focused - Just Knights",
+    FocusedJustBishops => "Focused - Just Bishops",
+    FocusedJustRooks => "Focused - Just Rooks",
+    FocusedJustQueens => "Focused - Just Queens",
+    FocusedJustPawns => "Focused - Just Pawns",
+    FocusedJustKings => "Focused - Just Kings",
+    FocusedJustUnicorns => "Focused - Just Unicorns",
+    FocusedJustDragons => "Focused - Just Dragons",
+    FocusedJustBrawns => "Focused - Just Brawns",
+    CheckmatePracticeKnight => "Checkmate Practice - Knight",
+    CheckmatePracticeBishop => "Checkmate Practice - Bishop",
+    CheckmatePracticeRook => "Checkmate Practice - Rook",
+    CheckmatePracticeQueen => "Checkmate Practice - Queen",
+    CheckmatePracticePawns => "Checkmate Practice - Pawns",
+});
 impl Variant {
-    pub fn determined(&self, variants_without_random: &Vec<Self>) -> Self {
+    // every variant in id order, ids without a variant are skipped
+    pub fn all() -> impl Iterator<Item = Self> {
+        (1..=VARIANT_ID_MAX).filter_map(|id| try_i64_to_enum(id).ok())
+    }
+
+    pub fn determined<R: Rng + ?Sized>(
+        &self,
+        variants_without_random: &[Self],
+        weights: &WeightedIndex<u32>, // one per variant
+        rng: &mut R,
+    ) -> Self {
         match self {
-            Variant::Random => {
-                variants_without_random
-                    [rand::thread_rng().gen_range(0..variants_without_random.len())]
-            }
-            _ => self.clone(),
+            Variant::Random => variants_without_random[weights.sample(rng)],
+            _ => *self,
         }
     }
 }
 enum_from_primitive! {
     #[repr(i64)]
     #[derive(Debug, Copy, Clone, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize))]
     pub enum Visibility {
         Public = 1,
         Private = 2
     }
 }
+display_names!(Visibility {
+    Public => "Public",
+    Private => "Private",
+});
 enum_from_primitive! {
     #[repr(i64)]
     #[derive(Debug, Copy, Clone, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize))]
     pub enum ActionType {
         Move = 1,
         UndoMove = 2,
@@ -194,16 +339,40 @@ enum_from_primitive! {
         Header = 6
     }
 }
+display_names!(ActionType {
+    Move => "Move",
+    UndoMove => "Undo Move",
+    SubmitMoves => "Submit Moves",
+    ResetPuzzle => "Reset Puzzle",
+    DisplayCheckReason => "Display Check Reason",
+    Header => "Header",
+});
+// (width, height) of the boards, the largest board for unknown or undetermined variants
+pub fn board_dimensions(v: Variant) -> (u8, u8) {
+    match v {
+        Variant::Small | Variant::SmallFlipped | Variant::SmallCentered | Variant::SmallOpen => {
+            (5, 5)
+        }
+        Variant::VerySmall | Variant::VerySmallOpen => (4, 4),
+        _ => (8, 8),
+    }
+}
 enum_from_primitive! {
     #[repr(i64)]
     #[derive(Debug, Copy, Clone, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize))]
     pub enum HistoryMatchState {
         Completed = 0,
         InProgress = 1
     }
 }
+display_names!(HistoryMatchState {
+    Completed => "Completed",
+    InProgress => "In Progress",
+});
 
 #[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct MatchSettings {
     pub color: OptionalColorWithRandom,
     pub clock: OptionalClock,
@@ -223,8 +392,119 @@ impl MatchSettings {
             match_id: m.match_id,
         }
     }
+
+    // whether a quick match asking for self may join the waiting match, random means any
+    // a specific color only joins hosts of the other one, not those who let the server pick
+    pub fn accepts(&self, waiting: &MatchSettingsWithoutVisibility) -> bool {
+        waiting.clock == self.clock
+            && (self.variant == Variant::Random || waiting.variant == self.variant)
+            && (self.color == OptionalColorWithRandom::Random
+                || waiting.color == self.color.reversed())
+    }
+}
+#[derive(Debug, Copy, Clone)]
+pub struct MatchSettingsBuilder<'a> {
+    m: MatchSettings,
+    variants: Option<&'a HashSet<Variant>>,
+    random_color: bool,
+}
+impl<'a> MatchSettingsBuilder<'a> {
+    // color and clock are left unset and must be provided
+    pub fn new() -> Self {
+        MatchSettingsBuilder {
+            m: MatchSettings {
+                color: OptionalColorWithRandom::None,
+                clock: OptionalClock::None,
+                variant: Variant::Standard,
+                visibility: Visibility::Public,
+                passcode: -1,
+                match_id: -1,
+            },
+            variants: None,
+            random_color: false,
+        }
+    }
+
+    pub fn color(mut self, color: OptionalColorWithRandom) -> Self {
+        self.m.color = color;
+        self
+    }
+
+    pub fn clock(mut self, clock: OptionalClock) -> Self {
+        self.m.clock = clock;
+        self
+    }
+
+    pub fn variant(mut self, variant: Variant) -> Self {
+        self.m.variant = variant;
+        self
+    }
+
+    pub fn visibility(mut self, visibility: Visibility) -> Self {
+        self.m.visibility = visibility;
+        self
+    }
+
+    pub fn passcode(mut self, passcode: Passcode) -> Self {
+        self.m.passcode = passcode;
+        self
+    }
+
+    pub fn match_id(mut self, match_id: MatchId) -> Self {
+        self.m.match_id = match_id;
+        self
+    }
+
+    pub fn allowed_variants(mut self, variants: &'a HashSet<Variant>) -> Self {
+        self.variants = Some(variants);
+        self
+    }
+
+    // a request from a client may leave the color to the server, settings of a match may not
+    pub fn allow_random_color(mut self) -> Self {
+        self.random_color = true;
+        self
+    }
+
+    pub fn build(self) -> Result<MatchSettings> {
+        let m = self.m;
+        if let Some(variants) = self.variants {
+            if !variants.contains(&m.variant) {
+                return err_invalid_data!("Variant {:?} is not allowed.", m.variant);
+            }
+        }
+        if m.clock == OptionalClock::None {
+            return err_invalid_data!("Clock of the match is not set.");
+        }
+        if m.color == OptionalColorWithRandom::None {
+            return err_invalid_data!("Color of the match is not set.");
+        }
+        if m.color == OptionalColorWithRandom::Random && !self.random_color {
+            return err_invalid_data!("Color of the match is not determined.");
+        }
+        // -1 means not assigned yet
+        if m.passcode < -1 || m.passcode > PASSCODE_MAX {
+            return err_invalid_data!("Passcode {} is out of range.", m.passcode);
+        }
+        Ok(m)
+    }
+}
+impl Default for MatchSettingsBuilder<'_> {
+    fn default() -> Self {
+        MatchSettingsBuilder::new()
+    }
+}
+impl From<MatchSettings> for MatchSettingsBuilder<'_> {
+    fn from(m: MatchSettings) -> Self {
+        MatchSettingsBuilder {
+            m,
+            variants: None,
+            random_color: false,
+        }
+    }
 }
 #[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct MatchSettingsWithoutVisibility {
     pub color: OptionalColorWithRandom,
     pub clock: OptionalClock,
@@ -248,26 +528,164 @@ impl From<MatchSettings> for MatchSettingsWithoutVisibility {
         MatchSettingsWithoutVisibility::new(m)
     }
 }
-#[derive(Debug, Copy, Clone)]
+// why a match ended before checkmate
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum ForfeitReason {
+    Resign,
+    Disconnect,
+    Abandoned, // no action within match_idle_timeout, nobody loses
+}
+enum_from_primitive! {
+    #[repr(i64)]
+    #[derive(Debug, Copy, Clone, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize))]
+    pub enum MatchOutcome {
+        WhiteWin = 1,
+        BlackWin = 2,
+        Draw = 3, // judged by the clients, never reported to the server
+        Aborted = 4
+    }
+}
+display_names!(MatchOutcome {
+    WhiteWin => "White Wins",
+    BlackWin => "Black Wins",
+    Draw => "Draw",
+    Aborted => "Aborted",
+});
+impl MatchOutcome {
+    pub fn lost_by(color: Color) -> Self {
+        match color {
+            Color::White => MatchOutcome::BlackWin,
+            Color::Black => MatchOutcome::WhiteWin,
+        }
+    }
+}
+#[derive(Debug, Clone)]
 pub struct ServerHistoryMatch {
     pub state: HistoryMatchState,
+    pub end_reason: Option<ForfeitReason>, // set once completed
+    pub outcome: Option<MatchOutcome>,     // set once completed
     pub clock: OptionalClock,
     pub variant: Variant,
     pub visibility: Visibility,
     pub time_start: Instant,
+    pub time_end: Option<Instant>,              // set once completed
+    pub timeline: VecDeque<C2SOrS2CActionBody>, // relayed actions, oldest dropped beyond timeline_length
 }
 impl ServerHistoryMatch {
     pub fn new(m: MatchSettings) -> Self {
         ServerHistoryMatch {
             state: HistoryMatchState::InProgress,
+            end_reason: None,
+            outcome: None,
             clock: m.clock,
             variant: m.variant,
             visibility: m.visibility,
             time_start: Instant::now(),
+            time_end: None,
+            timeline: VecDeque::new(),
+        }
+    }
+
+    // so far if still in progress
+    pub fn duration(&self) -> Duration {
+        self.time_end
+            .unwrap_or_else(Instant::now)
+            .duration_since(self.time_start)
+    }
+}
+
+/* compact dump of the match history for offline analysis, see the admin dump-history command
+all integers little endian, header: magic "5DCH", u8 version 1, u32 count
+then per match (21 bytes): i64 match_id, u8 variant, u8 clock, u8 visibility, u8 state,
+u8 outcome (0 while in progress), u64 duration in seconds
+*/
+const HISTORY_DUMP_MAGIC: &[u8; 4] = b"5DCH";
+const HISTORY_DUMP_VERSION: u8 = 1;
+const HISTORY_DUMP_RECORD_LENGTH: usize = 21;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct HistoryDumpEntry {
+    pub match_id: MatchId,
+    pub variant: Variant,
+    pub clock: OptionalClock,
+    pub visibility: Visibility,
+    pub state: HistoryMatchState,
+    pub outcome: Option<MatchOutcome>,
+    pub duration: Duration, // whole seconds
+}
+impl HistoryDumpEntry {
+    pub fn new(match_id: MatchId, m: &ServerHistoryMatch) -> Self {
+        HistoryDumpEntry {
+            match_id,
+            variant: m.variant,
+            clock: m.clock,
+            visibility: m.visibility,
+            state: m.state,
+            outcome: m.outcome,
+            duration: Duration::from_secs(m.duration().as_secs()),
         }
     }
 }
 
+pub fn pack_history_dump(entries: &[HistoryDumpEntry]) -> Bytes {
+    let mut bytes = BytesMut::with_capacity(9 + entries.len() * HISTORY_DUMP_RECORD_LENGTH);
+    bytes.extend_from_slice(HISTORY_DUMP_MAGIC);
+    bytes.extend_from_slice(&[HISTORY_DUMP_VERSION]);
+    bytes.extend_from_slice(&(entries.len() as u32).to_le_bytes());
+    for e in entries {
+        write_i64_le(&mut bytes, e.match_id);
+        // every id fits in a byte, see VARIANT_ID_MAX
+        bytes.extend_from_slice(&[
+            e.variant as u8,
+            e.clock as u8,
+            e.visibility as u8,
+            e.state as u8,
+            e.outcome.map_or(0, |o| o as u8),
+        ]);
+        write_u64_le(&mut bytes, e.duration.as_secs());
+    }
+    bytes.freeze()
+}
+
+pub fn parse_history_dump(data: &[u8]) -> Result<Vec<HistoryDumpEntry>> {
+    if data.len() < 9 || &data[..4] != HISTORY_DUMP_MAGIC {
+        return err_invalid_data!("Not a history dump.");
+    }
+    if data[4] != HISTORY_DUMP_VERSION {
+        return err_invalid_data!("Unsupported history dump version {}.", data[4]);
+    }
+    let count = LittleEndian::read_u32(&data[5..9]) as usize;
+    if data.len() - 9 != count * HISTORY_DUMP_RECORD_LENGTH {
+        return err_invalid_data!(
+            "History dump of {} matches should be of length {}, not {}.",
+            count,
+            9 + count * HISTORY_DUMP_RECORD_LENGTH,
+            data.len()
+        );
+    }
+    let mut bytes = BytesMut::from(&data[9..]);
+    let mut entries = Vec::with_capacity(count);
+    for _ in 0..count {
+        let match_id = read_i64_le(&mut bytes);
+        let ids = bytes.split_to(5);
+        entries.push(HistoryDumpEntry {
+            match_id,
+            variant: try_i64_to_enum(ids[0].into())?,
+            clock: try_i64_to_enum(ids[1].into())?,
+            visibility: try_i64_to_enum(ids[2].into())?,
+            state: try_i64_to_enum(ids[3].into())?,
+            outcome: match ids[4] {
+                0 => None,
+                outcome => Some(try_i64_to_enum(outcome.into())?),
+            },
+            duration: Duration::from_secs(read_u64_le(&mut bytes)),
+        });
+    }
+    Ok(entries)
+}
+
 enum_from_primitive! {
     #[repr(i64)]
     #[derive(Debug, Copy, Clone, PartialEq)]
@@ -284,10 +702,63 @@ enum_from_primitive! {
         C2SForfeit = 10,
         C2SOrS2CAction = 11,
         C2SMatchListRequest = 12,
-        S2CMatchList = 13
+        S2CMatchList = 13,
+
+        // extensions, never sent to the official client
+        S2CServerNotice = 101,
+        S2CServerInfo = 102,
+        C2SQuickMatch = 103,
+        C2SVariantListRequest = 104,
+        S2CVariantList = 105
     }
 }
+display_names!(MessageType {
+    C2SGreet => "C2SGreet",
+    S2CGreet => "S2CGreet",
+    C2SMatchCreateOrJoin => "C2SMatchCreateOrJoin",
+    S2CMatchCreateOrJoinResult => "S2CMatchCreateOrJoinResult",
+    C2SMatchCancel => "C2SMatchCancel",
+    S2CMatchCancelResult => "S2CMatchCancelResult",
+    S2CMatchStart => "S2CMatchStart",
+    S2COpponentLeft => "S2COpponentLeft",
+    C2SForfeit => "C2SForfeit",
+    C2SOrS2CAction => "C2SOrS2CAction",
+    C2SMatchListRequest => "C2SMatchListRequest",
+    S2CMatchList => "S2CMatchList",
+    S2CServerNotice => "S2CServerNotice",
+    S2CServerInfo => "S2CServerInfo",
+    C2SQuickMatch => "C2SQuickMatch",
+    C2SVariantListRequest => "C2SVariantListRequest",
+    S2CVariantList => "S2CVariantList",
+});
+// received messages by MessageType::index, see MessageIO
+pub type MessageCounts = [u64; MESSAGE_TYPE_SLOTS];
+pub const MESSAGE_TYPE_SLOTS: usize = 18;
+
 impl MessageType {
+    // dense index, the extensions are packed after the official ids
+    pub fn index(&self) -> usize {
+        match *self as usize {
+            id @ 1..=13 => id - 1,
+            id => id - 101 + 13,
+        }
+    }
+
+    pub fn all() -> impl Iterator<Item = Self> {
+        (1..=13)
+            .chain(101..=105)
+            .filter_map(|id| try_i64_to_enum(id).ok())
+    }
+
+    // e.g. "C2SGreet 1, C2SMatchListRequest 3", types never seen are left out
+    pub fn describe_counts(counts: &MessageCounts) -> String {
+        let counts: Vec<String> = MessageType::all()
+            .filter(|t| counts[t.index()] > 0)
+            .map(|t| format!("{} {}", t, counts[t.index()]))
+            .collect();
+        counts.join(", ")
+    }
+
     pub fn legal_length(&self) -> usize {
         match self {
             MessageType::C2SGreet => 56,
@@ -302,12 +773,18 @@ impl MessageType {
             MessageType::C2SOrS2CAction => 112,
             MessageType::C2SMatchListRequest => 9,
             MessageType::S2CMatchList => 1008,
+            MessageType::S2CServerNotice => 16 + NOTICE_LENGTH_MAX,
+            MessageType::S2CServerInfo => 16 + NOTICE_LENGTH_MAX,
+            MessageType::C2SQuickMatch => 32,
+            MessageType::C2SVariantListRequest => 8,
+            MessageType::S2CVariantList => 16 + VARIANT_ID_MAX as usize * 8,
         }
     }
 }
 
 // unknown or unused fields omitted
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub enum Message {
     C2SGreet(C2SGreetBody),
     S2CGreet,
@@ -320,62 +797,164 @@ pub enum Message {
     C2SForfeit,
     C2SOrS2CAction(C2SOrS2CActionBody),
     C2SMatchListRequest,
-    S2CMatchList(S2CMatchListBody),
+    S2CMatchList(Box<S2CMatchListBody>),
+    S2CServerNotice(String),
+    S2CServerInfo(String),        // server_name
+    C2SQuickMatch(MatchSettings), // always public, passcode and match_id unset
+    C2SVariantListRequest,
+    S2CVariantList(Vec<Variant>), // allowed ones in id order, Random too if allowed
 
-    InternalInitialize(broadcast::Sender<Message>),
-    InternalJoin,
+    // never on the wire
+    #[cfg_attr(feature = "serde", serde(skip))]
+    InternalInitialize(mpsc::Sender<Message>),
+    #[cfg_attr(feature = "serde", serde(skip))]
+    InternalJoin(PeerId),
+    #[cfg_attr(feature = "serde", serde(skip))]
     InternalMatchStart(S2CMatchStartBody),
-    InternalForfeit,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    InternalForfeit(ForfeitReason),
+    #[cfg_attr(feature = "serde", serde(skip))]
     InternalAction(C2SOrS2CActionBody),
 }
 #[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct C2SGreetBody {
     pub version1: i64,
     pub version2: i64,
+    pub extensions: i64, // 0 for the official client
 }
 #[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub enum C2SMatchCreateOrJoinBody {
-    Create(MatchSettings),
+    Create(MatchSettings), // a passcode other than -1 asks to reopen it, see insert_waiting_match
     Join(i64),
 }
 #[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub enum S2CMatchCreateOrJoinResultBody {
     Success(MatchSettings),
     Failed,
 }
 #[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub enum S2CMatchCancelResultBody {
     Success,
     Failed,
 }
 #[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct S2CMatchStartBody {
     pub m: MatchSettingsWithoutVisibility,
     pub match_id: MatchId,
     pub seconds_passed: u64,
+    pub opponent: Option<PeerId>, // only for clients with EXTENSION_PEER_INFO
 }
+// a single header without action is considered an opponent timeout
 #[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct C2SOrS2CActionBody {
     pub action_type: ActionType,
     pub color: Color,
     pub seconds_passed: u64,
-    pub src_l: i64,
-    pub src_t: i64,
-    pub src_board_color: Color,
-    pub src_y: i64,
-    pub src_x: i64,
-    pub dst_l: i64,
-    pub dst_t: i64,
-    pub dst_board_color: Color,
-    pub dst_y: i64,
-    pub dst_x: i64,
+    // l, t and board_color of src also for DisplayCheckReason, the rest only for Move
+    pub src: Coordinate,
+    pub dst: Coordinate,
+}
+// a square on a board, fields in wire order
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Coordinate {
+    pub l: i64,
+    pub t: i64,
+    pub board_color: Color,
+    pub y: i64,
+    pub x: i64,
+}
+impl Coordinate {
+    fn pack(&self, bytes: &mut BytesMut) {
+        write_i64_le(bytes, self.l);
+        write_i64_le(bytes, self.t);
+        write_i64_le(bytes, self.board_color as i64);
+        write_i64_le(bytes, self.y);
+        write_i64_le(bytes, self.x);
+    }
+
+    fn unpack(bytes: &mut BytesMut) -> Result<Self> {
+        Ok(Coordinate {
+            l: read_i64_le(bytes),
+            t: read_i64_le(bytes),
+            board_color: try_i64_to_enum(read_i64_le(bytes))?,
+            y: read_i64_le(bytes),
+            x: read_i64_le(bytes),
+        })
+    }
+}
+impl C2SOrS2CActionBody {
+    // moves carry two board squares, check reasons only src
+    pub fn check_coordinates(&self, variant: Variant) -> Result<()> {
+        let squares = match self.action_type {
+            ActionType::Move => 2,
+            ActionType::DisplayCheckReason => 1,
+            _ => return Ok(()),
+        };
+        let (width, height) = board_dimensions(variant);
+        for Coordinate { y, x, .. } in [self.src, self.dst].into_iter().take(squares) {
+            if !(0..height as i64).contains(&y) || !(0..width as i64).contains(&x) {
+                return err_invalid_data!(
+                    "Square ({}, {}) is outside the {:?} board.",
+                    y,
+                    x,
+                    variant
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /* the few rules that hold for any move without knowing the position, see strict_actions
+    a move starts on a board where its player is to move and lands on one too, so both board
+    colors are the color of the action, and pieces only travel to the present or the past,
+    so dst.t is at most src.t, timelines are not checked, any l may exist by then
+    */
+    pub fn check_board_colors(&self) -> Result<()> {
+        if self.action_type != ActionType::Move {
+            return Ok(());
+        }
+        for Coordinate { board_color, .. } in [self.src, self.dst] {
+            if board_color != self.color {
+                return err_invalid_data!(
+                    "{} moved on a board of {} to move.",
+                    self.color,
+                    board_color
+                );
+            }
+        }
+        if self.dst.t > self.src.t {
+            return err_invalid_data!(
+                "Move from T{} travels forward to T{}.",
+                self.src.t,
+                self.dst.t
+            );
+        }
+        Ok(())
+    }
 }
 #[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub enum S2CMatchListBody {
     Host(S2CMatchListHostBody),
     Nonhost(S2CMatchListNonhostBody),
 }
+impl S2CMatchListBody {
+    pub fn nonhost_body(&self) -> &S2CMatchListNonhostBody {
+        match self {
+            S2CMatchListBody::Host(body) => &body.body,
+            S2CMatchListBody::Nonhost(body) => body,
+        }
+    }
+}
 #[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct S2CMatchListHostBody {
     pub color: OptionalColorWithRandom,
     pub clock: OptionalClock,
@@ -384,24 +963,57 @@ pub struct S2CMatchListHostBody {
     pub body: S2CMatchListNonhostBody,
 }
 #[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct S2CMatchListNonhostBody {
     pub public_matches: [MatchSettingsWithoutVisibility; 13],
     pub public_matches_count: usize,
     pub server_history_matches: [S2CMatchListServerHistoryMatch; 13],
     pub server_history_matches_count: usize,
+    pub with_outcomes: bool, // only for clients with EXTENSION_MATCH_OUTCOME
+}
+impl S2CMatchListNonhostBody {
+    // the arrays are padded with placeholders beyond the counts
+    pub fn empty(with_outcomes: bool) -> Self {
+        S2CMatchListNonhostBody {
+            public_matches: [MatchSettingsWithoutVisibility {
+                color: OptionalColorWithRandom::None,
+                clock: OptionalClock::None,
+                variant: Variant::Standard,
+                passcode: 0,
+                match_id: -1,
+            }; 13],
+            public_matches_count: 0,
+            server_history_matches: [S2CMatchListServerHistoryMatch {
+                state: HistoryMatchState::Completed,
+                end_reason: None,
+                outcome: None,
+                clock: OptionalClock::None,
+                variant: Variant::Standard,
+                visibility: Visibility::Public,
+                seconds_passed: 0,
+            }; 13],
+            server_history_matches_count: 0,
+            with_outcomes,
+        }
+    }
 }
 #[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct S2CMatchListServerHistoryMatch {
     pub state: HistoryMatchState,
+    pub end_reason: Option<ForfeitReason>, // not sent
+    pub outcome: Option<MatchOutcome>,
     pub clock: OptionalClock,
     pub variant: Variant,
     pub visibility: Visibility,
     pub seconds_passed: u64,
 }
-impl From<ServerHistoryMatch> for S2CMatchListServerHistoryMatch {
-    fn from(m: ServerHistoryMatch) -> Self {
+impl From<&ServerHistoryMatch> for S2CMatchListServerHistoryMatch {
+    fn from(m: &ServerHistoryMatch) -> Self {
         S2CMatchListServerHistoryMatch {
             state: m.state,
+            end_reason: m.end_reason,
+            outcome: m.outcome,
             clock: m.clock,
             variant: m.variant,
             visibility: m.visibility,
@@ -411,6 +1023,31 @@ impl From<ServerHistoryMatch> for S2CMatchListServerHistoryMatch {
 }
 
 impl Message {
+    // shorthands for the server responses built by the handlers
+    pub fn create_success(m: MatchSettings) -> Self {
+        Message::S2CMatchCreateOrJoinResult(S2CMatchCreateOrJoinResultBody::Success(m))
+    }
+
+    pub fn create_failed() -> Self {
+        Message::S2CMatchCreateOrJoinResult(S2CMatchCreateOrJoinResultBody::Failed)
+    }
+
+    pub fn cancel_success() -> Self {
+        Message::S2CMatchCancelResult(S2CMatchCancelResultBody::Success)
+    }
+
+    pub fn cancel_failed() -> Self {
+        Message::S2CMatchCancelResult(S2CMatchCancelResultBody::Failed)
+    }
+
+    pub fn host_match_list(body: S2CMatchListHostBody) -> Self {
+        Message::S2CMatchList(Box::new(S2CMatchListBody::Host(body)))
+    }
+
+    pub fn nonhost_match_list(body: S2CMatchListNonhostBody) -> Self {
+        Message::S2CMatchList(Box::new(S2CMatchListBody::Nonhost(body)))
+    }
+
     pub fn message_type(&self) -> MessageType {
         match self {
             Message::C2SGreet(_) => MessageType::C2SGreet,
@@ -425,18 +1062,91 @@ impl Message {
             Message::C2SOrS2CAction(_) => MessageType::C2SOrS2CAction,
             Message::C2SMatchListRequest => MessageType::C2SMatchListRequest,
             Message::S2CMatchList(_) => MessageType::S2CMatchList,
+            Message::S2CServerNotice(_) => MessageType::S2CServerNotice,
+            Message::S2CServerInfo(_) => MessageType::S2CServerInfo,
+            Message::C2SQuickMatch(_) => MessageType::C2SQuickMatch,
+            Message::C2SVariantListRequest => MessageType::C2SVariantListRequest,
+            Message::S2CVariantList(_) => MessageType::S2CVariantList,
             _ => panic!("Invalid message type."),
         }
     }
 
+    // short stable tag for log lines, internal messages included
+    pub fn kind_name(&self) -> &'static str {
+        match self {
+            Message::C2SGreet(_) => "greet",
+            Message::S2CGreet => "greet_reply",
+            Message::C2SMatchCreateOrJoin(_) => "create_or_join",
+            Message::S2CMatchCreateOrJoinResult(_) => "create_or_join_result",
+            Message::C2SMatchCancel => "cancel",
+            Message::S2CMatchCancelResult(_) => "cancel_result",
+            Message::S2CMatchStart(_) => "match_start",
+            Message::S2COpponentLeft => "opponent_left",
+            Message::C2SForfeit => "forfeit",
+            Message::C2SOrS2CAction(_) => "action",
+            Message::C2SMatchListRequest => "match_list_request",
+            Message::S2CMatchList(_) => "match_list",
+            Message::S2CServerNotice(_) => "server_notice",
+            Message::S2CServerInfo(_) => "server_info",
+            Message::C2SQuickMatch(_) => "quick_match",
+            Message::C2SVariantListRequest => "variant_list_request",
+            Message::S2CVariantList(_) => "variant_list",
+            Message::InternalInitialize(_) => "internal_initialize",
+            Message::InternalJoin(_) => "internal_join",
+            Message::InternalMatchStart(_) => "internal_match_start",
+            Message::InternalForfeit(_) => "internal_forfeit",
+            Message::InternalAction(_) => "internal_action",
+        }
+    }
+
     pub fn legal_length(&self) -> usize {
-        self.message_type().legal_length()
+        match self {
+            Message::S2CMatchStart(S2CMatchStartBody {
+                opponent: Some(_), ..
+            }) => self.message_type().legal_length() + 8,
+            Message::S2CMatchList(body) if body.nonhost_body().with_outcomes => {
+                self.message_type().legal_length() + 13 * 8
+            }
+            _ => self.message_type().legal_length(),
+        }
     }
 
     pub fn pack(&self) -> Result<Bytes> {
         let mut bytes = BytesMut::new();
         write_i64_le(&mut bytes, self.message_type() as i64);
         match self {
+            // client side, laid out like the official client
+            Message::C2SGreet(body) => {
+                write_i64_le(&mut bytes, body.version1);
+                write_i64_le(&mut bytes, body.version2);
+                write_i64_le(&mut bytes, body.extensions);
+                for _ in 0..3 {
+                    write_i64_le(&mut bytes, 0); // unknown
+                }
+            }
+            Message::C2SMatchCreateOrJoin(C2SMatchCreateOrJoinBody::Create(m)) => {
+                write_i64_le(&mut bytes, m.color as i64);
+                write_i64_le(&mut bytes, m.clock as i64);
+                write_i64_le(&mut bytes, m.variant as i64);
+                write_i64_le(&mut bytes, m.visibility as i64);
+                // create, below -1 it reopens the passcode -2 - value
+                let passcode = if m.passcode >= 0 { -2 - m.passcode } else { -1 };
+                write_i64_le(&mut bytes, passcode);
+            }
+            Message::C2SMatchCreateOrJoin(C2SMatchCreateOrJoinBody::Join(passcode)) => {
+                for _ in 0..4 {
+                    write_i64_le(&mut bytes, 0);
+                }
+                write_i64_le(&mut bytes, *passcode);
+            }
+            Message::C2SQuickMatch(m) => {
+                write_i64_le(&mut bytes, m.color as i64);
+                write_i64_le(&mut bytes, m.clock as i64);
+                write_i64_le(&mut bytes, m.variant as i64);
+            }
+            Message::C2SMatchCancel | Message::C2SForfeit | Message::C2SMatchListRequest => {
+                bytes.extend_from_slice(&[0]); // unknown
+            }
             Message::S2CGreet => {
                 write_i64_le(&mut bytes, 1); // version, unconfirmed
                 for _ in 0..5 {
@@ -479,27 +1189,23 @@ impl Message {
                 write_i64_le(&mut bytes, body.match_id);
                 write_i64_le(&mut bytes, TryInto::<Color>::try_into(body.m.color)? as i64);
                 write_u64_le(&mut bytes, body.seconds_passed);
+                if let Some(opponent) = body.opponent {
+                    write_u64_le(&mut bytes, opponent);
+                }
             }
             Message::S2COpponentLeft => {
+                // always 0 in captures, whatever the reason, so not a reason code
                 bytes.extend_from_slice(&[0]); // unknown
             }
             Message::C2SOrS2CAction(body) => {
                 write_i64_le(&mut bytes, body.action_type as i64);
                 write_i64_le(&mut bytes, body.color as i64);
                 write_u64_le(&mut bytes, body.seconds_passed);
-                write_i64_le(&mut bytes, body.src_l);
-                write_i64_le(&mut bytes, body.src_t);
-                write_i64_le(&mut bytes, body.src_board_color as i64);
-                write_i64_le(&mut bytes, body.src_y);
-                write_i64_le(&mut bytes, body.src_x);
-                write_i64_le(&mut bytes, body.dst_l);
-                write_i64_le(&mut bytes, body.dst_t);
-                write_i64_le(&mut bytes, body.dst_board_color as i64);
-                write_i64_le(&mut bytes, body.dst_y);
-                write_i64_le(&mut bytes, body.dst_x);
+                body.src.pack(&mut bytes);
+                body.dst.pack(&mut bytes);
             }
             Message::S2CMatchList(body) => {
-                let body = match body {
+                let body = match body.as_ref() {
                     S2CMatchListBody::Host(body) => {
                         write_i64_le(&mut bytes, 1); // unknown
                         write_i64_le(&mut bytes, body.color as i64);
@@ -517,31 +1223,64 @@ impl Message {
                         body
                     }
                 };
-                for i in 0..body.public_matches_count {
-                    write_i64_le(&mut bytes, body.public_matches[i].color as i64);
-                    write_i64_le(&mut bytes, body.public_matches[i].clock as i64);
-                    write_i64_le(&mut bytes, body.public_matches[i].variant as i64);
-                    write_i64_le(&mut bytes, body.public_matches[i].passcode);
+                // counts beyond the arrays are never sent
+                let public_matches = &body.public_matches[..body.public_matches_count.min(13)];
+                let server_history_matches =
+                    &body.server_history_matches[..body.server_history_matches_count.min(13)];
+                for m in public_matches {
+                    write_i64_le(&mut bytes, m.color as i64);
+                    write_i64_le(&mut bytes, m.clock as i64);
+                    write_i64_le(&mut bytes, m.variant as i64);
+                    write_i64_le(&mut bytes, m.passcode);
                 }
-                for _ in body.public_matches_count..13 {
+                for _ in public_matches.len()..13 {
                     for _ in 0..4 {
                         write_i64_le(&mut bytes, 0);
                     }
                 }
-                write_u64_le(&mut bytes, body.public_matches_count as u64);
-                for i in 0..body.server_history_matches_count {
-                    write_i64_le(&mut bytes, body.server_history_matches[i].state as i64);
-                    write_i64_le(&mut bytes, body.server_history_matches[i].clock as i64);
-                    write_i64_le(&mut bytes, body.server_history_matches[i].variant as i64);
-                    write_i64_le(&mut bytes, body.server_history_matches[i].visibility as i64);
-                    write_u64_le(&mut bytes, body.server_history_matches[i].seconds_passed);
+                write_u64_le(&mut bytes, public_matches.len() as u64);
+                for m in server_history_matches {
+                    write_i64_le(&mut bytes, m.state as i64);
+                    write_i64_le(&mut bytes, m.clock as i64);
+                    write_i64_le(&mut bytes, m.variant as i64);
+                    write_i64_le(&mut bytes, m.visibility as i64);
+                    write_u64_le(&mut bytes, m.seconds_passed);
                 }
-                for _ in body.server_history_matches_count..13 {
+                for _ in server_history_matches.len()..13 {
                     for _ in 0..5 {
                         write_i64_le(&mut bytes, 0);
                     }
                 }
-                write_u64_le(&mut bytes, body.server_history_matches_count as u64);
+                write_u64_le(&mut bytes, server_history_matches.len() as u64);
+                if body.with_outcomes {
+                    // 0 for matches in progress and padding
+                    for i in 0..13 {
+                        let outcome = server_history_matches.get(i).and_then(|m| m.outcome);
+                        write_i64_le(&mut bytes, outcome.map_or(0, |o| o as i64));
+                    }
+                }
+            }
+            Message::C2SVariantListRequest => {}
+            Message::S2CVariantList(variants) => {
+                // ids are unique, so at most VARIANT_ID_MAX of them
+                if variants.len() > VARIANT_ID_MAX as usize {
+                    return err_limit!("{} variants are too many.", variants.len());
+                }
+                // count, then ids padded with zeros
+                write_u64_le(&mut bytes, variants.len() as u64);
+                for v in variants {
+                    write_i64_le(&mut bytes, *v as i64);
+                }
+                bytes.resize(16 + VARIANT_ID_MAX as usize * 8, 0);
+            }
+            Message::S2CServerNotice(text) | Message::S2CServerInfo(text) => {
+                if text.len() > NOTICE_LENGTH_MAX {
+                    return err_limit!("Text of length {} is too long.", text.len());
+                }
+                // byte length, then utf-8 padded with zeros
+                write_u64_le(&mut bytes, text.len() as u64);
+                bytes.extend_from_slice(text.as_bytes());
+                bytes.resize(16 + NOTICE_LENGTH_MAX, 0);
             }
             _ => {
                 return err_invalid_data!(
@@ -564,8 +1303,22 @@ impl Message {
     }
 
     pub fn unpack(mut bytes: BytesMut) -> Result<Message> {
+        Message::read_c2s(&mut bytes)
+    }
+
+    // client side of unpack, for what the server sends
+    pub fn unpack_s2c(mut bytes: BytesMut) -> Result<Message> {
+        Message::read_s2c(&mut bytes)
+    }
+
+    // what a known message doesn't read is left in bytes, see MessageIO::strict
+    pub fn read_c2s(bytes: &mut BytesMut) -> Result<Message> {
         let length = bytes.len();
-        let message_type: MessageType = try_i64_to_enum(read_i64_le(&mut bytes))?;
+        if length < 8 {
+            return err_invalid_data!("Message of length {} is too short.", length);
+        }
+        // every read below is guarded by the length check
+        let message_type: MessageType = try_i64_to_enum(read_i64_le(bytes))?;
 
         // check message length
         if length != message_type.legal_length() {
@@ -579,18 +1332,28 @@ impl Message {
 
         match message_type {
             MessageType::C2SGreet => {
-                let version1 = read_i64_le(&mut bytes);
-                let version2 = read_i64_le(&mut bytes);
-                Ok(Message::C2SGreet(C2SGreetBody { version1, version2 }))
+                let version1 = read_i64_le(bytes);
+                let version2 = read_i64_le(bytes);
+                let extensions = read_i64_le(bytes);
+                bytes.advance(24); // unknown
+                Ok(Message::C2SGreet(C2SGreetBody {
+                    version1,
+                    version2,
+                    extensions,
+                }))
             }
             MessageType::C2SMatchCreateOrJoin => {
-                let color = read_i64_le(&mut bytes);
-                let clock = read_i64_le(&mut bytes);
-                let variant = read_i64_le(&mut bytes);
-                let visibility = read_i64_le(&mut bytes);
-                let passcode = read_i64_le(&mut bytes);
+                let color = read_i64_le(bytes);
+                let clock = read_i64_le(bytes);
+                let variant = read_i64_le(bytes);
+                let visibility = read_i64_le(bytes);
+                let passcode = read_i64_le(bytes);
                 if passcode < 0 {
-                    // create match
+                    // create match, reopening -2 - passcode below -1, out of range is ignored
+                    let passcode = match -2 - passcode {
+                        reopen @ 0..=PASSCODE_MAX => reopen,
+                        _ => -1,
+                    };
                     let color = try_i64_to_enum(color)?;
                     let clock = try_i64_to_enum(clock)?;
                     let variant = try_i64_to_enum(variant)?;
@@ -605,6 +1368,8 @@ impl Message {
                             match_id: -1,
                         }),
                     ))
+                } else if passcode > PASSCODE_MAX {
+                    err_invalid_data!("Passcode {} is out of range.", passcode)
                 } else {
                     // join match
                     Ok(Message::C2SMatchCreateOrJoin(
@@ -612,88 +1377,569 @@ impl Message {
                     ))
                 }
             }
-            MessageType::C2SMatchCancel => Ok(Message::C2SMatchCancel),
-            MessageType::C2SForfeit => Ok(Message::C2SForfeit),
+            MessageType::C2SMatchCancel => {
+                bytes.advance(1); // unknown
+                Ok(Message::C2SMatchCancel)
+            }
+            MessageType::C2SForfeit => {
+                bytes.advance(1); // unknown
+                Ok(Message::C2SForfeit)
+            }
             MessageType::C2SOrS2CAction => {
-                let action_type = try_i64_to_enum(read_i64_le(&mut bytes))?;
-                let color = try_i64_to_enum(read_i64_le(&mut bytes))?;
-                let seconds_passed = read_u64_le(&mut bytes);
-                let src_l = read_i64_le(&mut bytes);
-                let src_t = read_i64_le(&mut bytes);
-                let src_board_color = try_i64_to_enum(read_i64_le(&mut bytes))?;
-                let src_y = read_i64_le(&mut bytes);
-                let src_x = read_i64_le(&mut bytes);
-                let dst_l = read_i64_le(&mut bytes);
-                let dst_t = read_i64_le(&mut bytes);
-                let dst_board_color = try_i64_to_enum(read_i64_le(&mut bytes))?;
-                let dst_y = read_i64_le(&mut bytes);
-                let dst_x = read_i64_le(&mut bytes);
+                let action_type = try_i64_to_enum(read_i64_le(bytes))?;
+                let color = try_i64_to_enum(read_i64_le(bytes))?;
+                let seconds_passed = read_u64_le(bytes);
+                let src = Coordinate::unpack(bytes)?;
+                let dst = Coordinate::unpack(bytes)?;
                 Ok(Message::C2SOrS2CAction(C2SOrS2CActionBody {
                     action_type,
                     color,
                     seconds_passed,
-                    src_l,
-                    src_t,
-                    src_board_color,
-                    src_y,
-                    src_x,
-                    dst_l,
-                    dst_t,
-                    dst_board_color,
-                    dst_y,
-                    dst_x,
+                    src,
+                    dst,
                 }))
             }
-            MessageType::C2SMatchListRequest => Ok(Message::C2SMatchListRequest),
+            MessageType::C2SMatchListRequest => {
+                bytes.advance(1); // unknown
+                Ok(Message::C2SMatchListRequest)
+            }
+            MessageType::C2SQuickMatch => Ok(Message::C2SQuickMatch(MatchSettings {
+                color: try_i64_to_enum(read_i64_le(bytes))?,
+                clock: try_i64_to_enum(read_i64_le(bytes))?,
+                variant: try_i64_to_enum(read_i64_le(bytes))?,
+                visibility: Visibility::Public,
+                passcode: -1,
+                match_id: -1,
+            })),
+            MessageType::C2SVariantListRequest => Ok(Message::C2SVariantListRequest),
             _ => err_invalid_data!("Message type {:?} shouldn't be unpacked.", message_type),
         }
     }
+
+    pub fn read_s2c(bytes: &mut BytesMut) -> Result<Message> {
+        let length = bytes.len();
+        if length < 8 {
+            return err_invalid_data!("Message of length {} is too short.", length);
+        }
+        let message_type: MessageType = try_i64_to_enum(LittleEndian::read_i64(&bytes[..8]))?;
+        if message_type == MessageType::C2SOrS2CAction {
+            return Message::read_c2s(bytes);
+        }
+        read_i64_le(bytes);
+
+        // analysis extensions append to these
+        let extended_length = match message_type {
+            MessageType::S2CMatchStart => message_type.legal_length() + 8,
+            MessageType::S2CMatchList => message_type.legal_length() + 13 * 8,
+            _ => message_type.legal_length(),
+        };
+        if length != message_type.legal_length() && length != extended_length {
+            return err_invalid_data!(
+                "Message of type {:?} should be of length {}, not {}.",
+                message_type,
+                message_type.legal_length(),
+                length
+            );
+        }
+        let extended = length != message_type.legal_length();
+
+        match message_type {
+            MessageType::S2CGreet => {
+                bytes.advance(48); // version and unknown
+                Ok(Message::S2CGreet)
+            }
+            MessageType::S2CMatchCreateOrJoinResult => {
+                let success = read_i64_le(bytes);
+                read_i64_le(bytes); // failed
+                let color = read_i64_le(bytes);
+                let clock = read_i64_le(bytes);
+                let variant = read_i64_le(bytes);
+                let visibility = read_i64_le(bytes);
+                let passcode = read_i64_le(bytes);
+                if success != 1 {
+                    return Ok(Message::create_failed());
+                }
+                Ok(Message::create_success(MatchSettings {
+                    color: try_i64_to_enum(color)?,
+                    clock: try_i64_to_enum(clock)?,
+                    variant: try_i64_to_enum(variant)?,
+                    visibility: try_i64_to_enum(visibility)?,
+                    passcode,
+                    match_id: -1, // not sent
+                }))
+            }
+            MessageType::S2CMatchCancelResult => match read_i64_le(bytes) {
+                1 => Ok(Message::cancel_success()),
+                _ => Ok(Message::cancel_failed()),
+            },
+            MessageType::S2CMatchStart => {
+                let clock = try_i64_to_enum(read_i64_le(bytes))?;
+                let variant = try_i64_to_enum(read_i64_le(bytes))?;
+                let match_id = read_i64_le(bytes);
+                let color: Color = try_i64_to_enum(read_i64_le(bytes))?;
+                let seconds_passed = read_u64_le(bytes);
+                let opponent = extended.then(|| read_u64_le(bytes));
+                Ok(Message::S2CMatchStart(S2CMatchStartBody {
+                    m: MatchSettingsWithoutVisibility {
+                        color: color.into(),
+                        clock,
+                        variant,
+                        passcode: -1, // not sent
+                        match_id,
+                    },
+                    match_id,
+                    seconds_passed,
+                    opponent,
+                }))
+            }
+            MessageType::S2COpponentLeft => {
+                bytes.advance(1); // unknown
+                Ok(Message::S2COpponentLeft)
+            }
+            MessageType::S2CMatchList => {
+                read_i64_le(bytes); // unknown
+                let color = read_i64_le(bytes);
+                let clock = read_i64_le(bytes);
+                let variant = read_i64_le(bytes);
+                let passcode = read_i64_le(bytes);
+                let is_host = read_i64_le(bytes) == 1;
+                let mut body = S2CMatchListNonhostBody::empty(extended);
+                // padding is all zeros, not valid enums, so only entries within the counts are
+                let public_matches: [[i64; 4]; 13] =
+                    std::array::from_fn(|_| std::array::from_fn(|_| read_i64_le(bytes)));
+                body.public_matches_count = read_count(bytes)?;
+                for (i, [color, clock, variant, passcode]) in public_matches
+                    .into_iter()
+                    .take(body.public_matches_count)
+                    .enumerate()
+                {
+                    body.public_matches[i] = MatchSettingsWithoutVisibility {
+                        color: try_i64_to_enum(color)?,
+                        clock: try_i64_to_enum(clock)?,
+                        variant: try_i64_to_enum(variant)?,
+                        passcode,
+                        match_id: -1, // not sent
+                    };
+                }
+                let server_history_matches: [[i64; 5]; 13] =
+                    std::array::from_fn(|_| std::array::from_fn(|_| read_i64_le(bytes)));
+                body.server_history_matches_count = read_count(bytes)?;
+                let outcomes: [i64; 13] =
+                    std::array::from_fn(|_| if extended { read_i64_le(bytes) } else { 0 });
+                for (i, [state, clock, variant, visibility, seconds_passed]) in
+                    server_history_matches
+                        .into_iter()
+                        .take(body.server_history_matches_count)
+                        .enumerate()
+                {
+                    body.server_history_matches[i] = S2CMatchListServerHistoryMatch {
+                        state: try_i64_to_enum(state)?,
+                        end_reason: None,
+                        outcome: match outcomes[i] {
+                            0 => None,
+                            outcome => Some(try_i64_to_enum(outcome)?),
+                        },
+                        clock: try_i64_to_enum(clock)?,
+                        variant: try_i64_to_enum(variant)?,
+                        visibility: try_i64_to_enum(visibility)?,
+                        seconds_passed: seconds_passed as u64,
+                    };
+                }
+                if !is_host {
+                    return Ok(Message::nonhost_match_list(body));
+                }
+                Ok(Message::host_match_list(S2CMatchListHostBody {
+                    color: try_i64_to_enum(color)?,
+                    clock: try_i64_to_enum(clock)?,
+                    variant: try_i64_to_enum(variant)?,
+                    passcode,
+                    body,
+                }))
+            }
+            MessageType::S2CServerNotice | MessageType::S2CServerInfo => {
+                let text_length = read_u64_le(bytes) as usize;
+                if text_length > NOTICE_LENGTH_MAX {
+                    return err_invalid_data!("Text of length {} is too long.", text_length);
+                }
+                // the padding is part of the message
+                let text = bytes.split_to(NOTICE_LENGTH_MAX);
+                let text = String::from_utf8(text[..text_length].to_vec())
+                    .map_err(|e| ProtocolError::InvalidData(format!("Text is not utf-8, {}", e)))?;
+                Ok(match message_type {
+                    MessageType::S2CServerNotice => Message::S2CServerNotice(text),
+                    _ => Message::S2CServerInfo(text),
+                })
+            }
+            MessageType::S2CVariantList => {
+                let count = read_u64_le(bytes) as usize;
+                if count > VARIANT_ID_MAX as usize {
+                    return err_invalid_data!(
+                        "Count {} exceeds the {} variants.",
+                        count,
+                        VARIANT_ID_MAX
+                    );
+                }
+                let ids: Vec<i64> = (0..VARIANT_ID_MAX).map(|_| read_i64_le(bytes)).collect();
+                let variants = ids[..count]
+                    .iter()
+                    .map(|id| try_i64_to_enum(*id))
+                    .collect::<Result<_>>()?;
+                Ok(Message::S2CVariantList(variants))
+            }
+            _ => err_invalid_data!("Message type {:?} shouldn't be unpacked.", message_type),
+        }
+    }
+}
+
+/* source and sink of whole frames, without any length prefix
+the tcp transport frames with a u64 length prefix, see MessageIO::new
+*/
+pub trait FrameIO:
+    Stream<Item = Result<BytesMut>> + Sink<Bytes, Error = ProtocolError> + Send + Sync + Unpin
+{
+}
+impl<T> FrameIO for T where
+    T: Stream<Item = Result<BytesMut>> + Sink<Bytes, Error = ProtocolError> + Send + Sync + Unpin
+{
 }
 
-#[derive(Debug)]
 pub struct MessageIO {
-    framed: Framed<TcpStream, LengthDelimitedCodec>,
+    frames: Box<dyn FrameIO>,
+    read: fn(&mut BytesMut) -> Result<Message>, // which side's messages are read
+    strict: bool,                               // bytes left after a message are an error
+    pending: usize,                             // frames put but not flushed yet
+    bytes_read: u64,                            // frame payloads, without the length prefix
+    bytes_written: u64,
+    messages_read: MessageCounts, // decoded ones only
+    delay: Option<Duration>,      // before each put, see delayed
+    coalesced: bool,              // pending frames wait for flush, see coalesced
+}
+
+impl std::fmt::Debug for MessageIO {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MessageIO")
+            .field("pending", &self.pending)
+            .field("bytes_read", &self.bytes_read)
+            .field("bytes_written", &self.bytes_written)
+            .finish_non_exhaustive()
+    }
+}
+
+// u64 little endian length prefix, shared by MessageIO and offline decoders
+pub fn frame_codec(max_frame_length: usize) -> LengthDelimitedCodec {
+    LengthDelimitedCodec::builder()
+        .little_endian()
+        .length_field_type::<u64>()
+        .max_frame_length(max_frame_length)
+        .new_codec()
 }
 
 impl MessageIO {
-    pub fn new(stream: TcpStream) -> Self {
+    // connected pair over an in-memory pipe, for testing without sockets
+    pub fn new_duplex() -> (Self, Self) {
+        let (a, b) = tokio::io::duplex(MESSAGE_LENGTH_MAX * 4);
+        (
+            MessageIO::new(a, MESSAGE_LENGTH_MAX),
+            MessageIO::new(b, MESSAGE_LENGTH_MAX),
+        )
+    }
+
+    // client side, frames are limited to the default limit_message_length
+    pub async fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        let stream = TcpStream::connect(addr).await?;
+        Ok(MessageIO::new(stream, MESSAGE_LENGTH_MAX).client_side())
+    }
+
+    // slow replies for testing client timeouts
+    pub fn delayed(self, delay: Duration) -> Self {
         MessageIO {
-            framed: LengthDelimitedCodec::builder()
-                .little_endian()
-                .length_field_type::<u64>()
-                .max_frame_length(MESSAGE_LENGTH_MAX)
-                .new_framed(stream),
+            delay: Some(delay),
+            ..self
         }
     }
 
-    pub async fn get(&mut self) -> Result<Message> {
-        match self.framed.next().await {
-            Some(Ok(msg)) => match Message::unpack(msg) {
-                Ok(msg) => {
-                    trace!("Get {:?}", msg);
-                    Ok(msg)
-                }
-                Err(e) => Err(e),
-            },
-            Some(Err(e)) => Err(e),
-            None => err_disconnected!(),
+    // polling the stream no longer flushes pending frames, the owner batches them
+    pub fn coalesced(self) -> Self {
+        MessageIO {
+            coalesced: true,
+            ..self
+        }
+    }
+
+    // read what the server sends instead of what clients send
+    pub fn client_side(self) -> Self {
+        MessageIO {
+            read: Message::read_s2c,
+            ..self
         }
     }
 
+    // catches readers out of date with the layout, a field added without reading it
+    pub fn strict(self) -> Self {
+        MessageIO {
+            strict: true,
+            ..self
+        }
+    }
+
+    pub fn new<S: AsyncRead + AsyncWrite + Send + Sync + Unpin + 'static>(
+        stream: S,
+        max_frame_length: usize,
+    ) -> Self {
+        let mut framed = Framed::new(stream, frame_codec(max_frame_length));
+        // feeding never waits for the peer, the number of pending frames is limited instead
+        framed.set_backpressure_boundary(usize::MAX);
+        MessageIO::from_frames(CodecFrames(framed))
+    }
+
+    // transports that frame by themselves
+    pub fn from_frames<F: FrameIO + 'static>(frames: F) -> Self {
+        MessageIO {
+            frames: Box::new(frames),
+            read: Message::read_c2s,
+            strict: false,
+            pending: 0,
+            bytes_read: 0,
+            bytes_written: 0,
+            messages_read: [0; MESSAGE_TYPE_SLOTS],
+            delay: None,
+            coalesced: false,
+        }
+    }
+
+    // None on clean disconnect
+    pub async fn get(&mut self) -> Result<Option<Message>> {
+        self.next().await.transpose()
+    }
+
     pub async fn put(&mut self, msg: Message) -> Result<()> {
-        trace!("Put {:?}", msg);
-        match msg.pack() {
-            Ok(msg) => self.framed.feed(msg).await,
-            Err(e) => Err(e),
+        if let Some(delay) = self.delay {
+            tokio::time::sleep(delay).await;
         }
+        trace!("Put {:?}", msg);
+        let msg = msg.pack()?;
+        self.bytes_written += msg.len() as u64;
+        self.frames.feed(msg).await?;
+        self.pending += 1;
+        Ok(())
+    }
+
+    pub fn pending(&self) -> usize {
+        self.pending
+    }
+
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read
+    }
+
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written
+    }
+
+    pub fn messages_read(&self) -> &MessageCounts {
+        &self.messages_read
     }
 
     pub async fn flush(&mut self) -> Result<()> {
-        self.framed.flush().await
+        self.frames.flush().await?;
+        self.pending = 0;
+        Ok(())
+    }
+
+    // flush without waiting for the peer, returns the number of frames still pending
+    // polling the stream keeps flushing them in the background
+    pub fn try_flush(&mut self) -> Result<usize> {
+        if let Some(result) = self.frames.flush().now_or_never() {
+            result?;
+            self.pending = 0;
+        }
+        Ok(self.pending)
     }
 
+    // puts that weren't flushed yet are sent first, whichever way the main loop ended
     pub async fn close(mut self) -> Result<()> {
-        self.framed.close().await
+        self.frames.flush().await?;
+        self.frames.close().await
+    }
+}
+
+// delays grow by multiplier, each scaled by a random factor in [1 - jitter, 1 + jitter]
+#[derive(Debug, Copy, Clone)]
+pub struct RetryPolicy {
+    pub max_attempts: u32, // including the first one
+    pub initial_delay: Duration,
+    pub multiplier: f64,
+    pub jitter: f64,
+}
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 5,
+            initial_delay: Duration::from_millis(500),
+            multiplier: 2.0,
+            jitter: 0.2,
+        }
+    }
+}
+impl RetryPolicy {
+    fn delay<R: Rng + ?Sized>(&self, retry: u32, rng: &mut R) -> Duration {
+        let factor = self.multiplier.powi(retry as i32);
+        let jitter = match self.jitter {
+            jitter if jitter > 0.0 => rng.gen_range(-jitter..=jitter),
+            _ => 0.0,
+        };
+        self.initial_delay
+            .mul_f64((factor * (1.0 + jitter)).max(0.0))
+    }
+}
+
+// the server may be restarting, anything else won't go away by waiting
+fn is_retryable(e: &ProtocolError) -> bool {
+    match e {
+        ProtocolError::Io(e) => matches!(
+            e.kind(),
+            ErrorKind::ConnectionRefused
+                | ErrorKind::ConnectionReset
+                | ErrorKind::ConnectionAborted
+                | ErrorKind::TimedOut
+        ),
+        _ => false,
+    }
+}
+
+// MessageIO::connect with exponential backoff, returns the last error once attempts run out
+pub async fn connect_with_retry<A: ToSocketAddrs + Clone>(
+    addr: A,
+    policy: RetryPolicy,
+) -> Result<MessageIO> {
+    let mut retry = 0;
+    loop {
+        match MessageIO::connect(addr.clone()).await {
+            Ok(io) => return Ok(io),
+            Err(e) if is_retryable(&e) && retry + 1 < policy.max_attempts => {
+                let delay = policy.delay(retry, &mut rand::thread_rng());
+                trace!("Connect failed, retrying in {:?}: {}", delay, e);
+                tokio::time::sleep(delay).await;
+                retry += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// the codec reports frames over max_frame_length as io errors
+fn codec_error(e: std::io::Error) -> ProtocolError {
+    if e.get_ref()
+        .is_some_and(|e| e.is::<LengthDelimitedCodecError>())
+    {
+        ProtocolError::FrameTooLong
+    } else {
+        ProtocolError::Io(e)
+    }
+}
+
+// sink_map_err gives up its function on the first error and panics if polled again,
+// but a connection that failed to write is still closed afterwards
+struct CodecFrames<S>(Framed<S, LengthDelimitedCodec>);
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Stream for CodecFrames<S> {
+    type Item = Result<BytesMut>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.0
+            .poll_next_unpin(cx)
+            .map(|frame| frame.map(|frame| frame.map_err(codec_error)))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Sink<Bytes> for CodecFrames<S> {
+    type Error = ProtocolError;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.0.poll_ready_unpin(cx).map_err(codec_error)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, frame: Bytes) -> Result<()> {
+        self.0.start_send_unpin(frame).map_err(codec_error)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.0.poll_flush_unpin(cx).map_err(codec_error)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.0.poll_close_unpin(cx).map_err(codec_error)
+    }
+}
+
+// yields decoded messages, terminates on disconnect
+impl Stream for MessageIO {
+    type Item = Result<Message>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.pending > 0 && !self.coalesced {
+            match self.frames.poll_flush_unpin(cx) {
+                Poll::Ready(Ok(())) => self.pending = 0,
+                Poll::Ready(Err(e)) => return Poll::Ready(Some(Err(e))),
+                Poll::Pending => {}
+            }
+        }
+        Poll::Ready(match ready!(self.frames.poll_next_unpin(cx)) {
+            Some(Ok(mut frame)) => {
+                // undecodable frames count too, they were read all the same
+                self.bytes_read += frame.len() as u64;
+                match (self.read)(&mut frame) {
+                    Ok(msg) if self.strict && !frame.is_empty() => Some(err_invalid_data!(
+                        "Message of type {:?} has {} bytes left unread.",
+                        msg.message_type(),
+                        frame.len()
+                    )),
+                    Ok(msg) => {
+                        trace!("Get {:?}", msg);
+                        self.messages_read[msg.message_type().index()] += 1;
+                        Some(Ok(msg))
+                    }
+                    Err(e) => Some(Err(e)),
+                }
+            }
+            Some(Err(e)) => Some(Err(e)),
+            None => None,
+        })
+    }
+}
+
+impl Sink<Message> for MessageIO {
+    type Error = ProtocolError;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.frames.poll_ready_unpin(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, msg: Message) -> Result<()> {
+        trace!("Put {:?}", msg);
+        let msg = msg.pack()?;
+        self.bytes_written += msg.len() as u64;
+        self.frames.start_send_unpin(msg)?;
+        self.pending += 1;
+        Ok(())
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        ready!(self.frames.poll_flush_unpin(cx))?;
+        self.pending = 0;
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.frames.poll_close_unpin(cx)
+    }
+}
+
+// number of used entries of a 13-entry array in S2CMatchList
+fn read_count(bytes: &mut BytesMut) -> Result<usize> {
+    match read_u64_le(bytes) {
+        count @ 0..=13 => Ok(count as usize),
+        count => err_invalid_data!("Count {} exceeds the 13 entries.", count),
     }
 }
 
@@ -728,18 +1974,484 @@ pub fn try_i64_to_enum<T: num::FromPrimitive>(v: i64) -> Result<T> {
     }
 }
 
-pub fn generate_random_passcode_internal() -> Passcode {
-    rand::thread_rng().gen_range(0..=2985983) // kkkkkk = 2985983
+// as typed in game, six base-12 piece letters with the least significant first, capital for white
+pub fn passcode_to_notation(mut passcode: Passcode) -> String {
+    const NOTATION: &[u8; 12] = b"PNBRQKpnbrqk";
+    let mut notation = String::new();
+    for _ in 0..6 {
+        notation.push(NOTATION[passcode.rem_euclid(12) as usize] as char);
+        passcode = passcode.div_euclid(12);
+    }
+    notation
+}
+
+pub fn generate_random_passcode_internal<R: Rng + ?Sized>(rng: &mut R) -> Passcode {
+    rng.gen_range(0..=PASSCODE_MAX)
 }
 
-pub async fn generate_random_passcode_internal_with_exceptions(
-    exceptions: &Mutex<HashMap<Passcode, broadcast::Receiver<Message>>>,
+// rejection sampling takes 1 / (1 - occupancy) draws on average (see benches/passcode.rs),
+// about 0.5us at 90% and 5us at 99% occupancy, only unacceptable beyond ~99.9%
+pub fn generate_random_passcode_internal_excluding<V, R: Rng + ?Sized>(
+    exceptions: &HashMap<Passcode, V>,
+    rng: &mut R,
 ) -> Passcode {
-    let exceptions = exceptions.lock().await;
     loop {
-        let passcode = generate_random_passcode_internal();
+        let passcode = generate_random_passcode_internal(rng);
         if !exceptions.contains_key(&passcode) {
             return passcode;
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn builder() -> MatchSettingsBuilder<'static> {
+        MatchSettingsBuilder::new()
+            .color(OptionalColorWithRandom::White)
+            .clock(OptionalClock::Medium)
+    }
+
+    fn build_error(builder: MatchSettingsBuilder) -> String {
+        match builder.build() {
+            Err(ProtocolError::InvalidData(e)) => e,
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn complete_settings_are_built() {
+        let m = builder().passcode(PASSCODE_MAX).build().unwrap();
+        assert_eq!(m.color, OptionalColorWithRandom::White);
+        assert_eq!(m.clock, OptionalClock::Medium);
+        assert_eq!(m.passcode, PASSCODE_MAX);
+    }
+
+    #[test]
+    fn disallowed_variant_is_rejected() {
+        let variants = HashSet::from([Variant::Standard]);
+        let builder = builder().allowed_variants(&variants);
+        assert!(builder.build().is_ok());
+        let e = build_error(builder.variant(Variant::Random));
+        assert!(e.contains("not allowed"), "{}", e);
+    }
+
+    #[test]
+    fn unset_clock_is_rejected() {
+        let e = build_error(builder().clock(OptionalClock::None));
+        assert!(e.contains("Clock"), "{}", e);
+    }
+
+    #[test]
+    fn undetermined_color_is_rejected() {
+        let e = build_error(builder().color(OptionalColorWithRandom::None));
+        assert!(e.contains("not set"), "{}", e);
+        let random = builder().color(OptionalColorWithRandom::Random);
+        let e = build_error(random);
+        assert!(e.contains("not determined"), "{}", e);
+        assert!(random.allow_random_color().build().is_ok());
+    }
+
+    #[test]
+    fn undetermined_visibility_is_rejected() {
+        // Visibility has no random value, a create asking for one doesn't decode
+        let m = builder().passcode(-1).build().unwrap();
+        let msg = Message::C2SMatchCreateOrJoin(C2SMatchCreateOrJoinBody::Create(m));
+        let mut bytes = BytesMut::from(&msg.pack().unwrap()[..]);
+        bytes[32..40].copy_from_slice(&3i64.to_le_bytes()); // visibility
+        match Message::unpack(bytes) {
+            Err(ProtocolError::InvalidData(_)) => {}
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn out_of_range_passcode_is_rejected() {
+        assert!(builder().passcode(-1).build().is_ok());
+        for passcode in [-2, PASSCODE_MAX + 1] {
+            let e = build_error(builder().passcode(passcode));
+            assert!(e.contains("out of range"), "{}: {}", passcode, e);
+        }
+    }
+
+    #[test]
+    fn coordinate_round_trips_y_before_x() {
+        let c = Coordinate {
+            l: -1,
+            t: 5,
+            board_color: Color::Black,
+            y: 2,
+            x: 7,
+        };
+        let mut bytes = BytesMut::new();
+        c.pack(&mut bytes);
+        assert_eq!(bytes.len(), 40);
+        assert_eq!(bytes[24..32], 2i64.to_le_bytes());
+        assert_eq!(bytes[32..40], 7i64.to_le_bytes());
+        assert_eq!(Coordinate::unpack(&mut bytes).unwrap(), c);
+        assert!(bytes.is_empty());
+
+        let body = C2SOrS2CActionBody {
+            action_type: ActionType::Move,
+            color: Color::Black,
+            seconds_passed: 0,
+            src: c,
+            dst: Coordinate { y: 6, x: 1, ..c },
+        };
+        let msg = Message::C2SOrS2CAction(body);
+        match Message::unpack(BytesMut::from(&msg.pack().unwrap()[..])) {
+            Ok(Message::C2SOrS2CAction(decoded)) => {
+                assert_eq!(decoded.src, body.src);
+                assert_eq!(decoded.dst, body.dst);
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    // the passcode as sent, and as decoded
+    fn create_round_trip(passcode: Passcode) -> (i64, Passcode) {
+        let m = builder().passcode(passcode).build().unwrap();
+        let msg = Message::C2SMatchCreateOrJoin(C2SMatchCreateOrJoinBody::Create(m));
+        let bytes = msg.pack().unwrap();
+        let sent = i64::from_le_bytes(bytes[40..48].try_into().unwrap());
+        match Message::unpack(BytesMut::from(&bytes[..])) {
+            Ok(Message::C2SMatchCreateOrJoin(C2SMatchCreateOrJoinBody::Create(m))) => {
+                (sent, m.passcode)
+            }
+            other => panic!("create {}: {:?}", passcode, other),
+        }
+    }
+
+    #[test]
+    fn reopened_passcodes_round_trip() {
+        assert_eq!(create_round_trip(-1), (-1, -1));
+        // reopening 0 is sent as -2, not as the -1 of a plain create
+        assert_eq!(create_round_trip(0), (-2, 0));
+        assert_eq!(
+            create_round_trip(PASSCODE_MAX),
+            (-2 - PASSCODE_MAX, PASSCODE_MAX)
+        );
+    }
+
+    #[test]
+    fn out_of_range_reopen_is_a_plain_create() {
+        let m = builder().passcode(-1).build().unwrap();
+        let msg = Message::C2SMatchCreateOrJoin(C2SMatchCreateOrJoinBody::Create(m));
+        for sent in [-3 - PASSCODE_MAX, i64::MIN] {
+            let mut bytes = BytesMut::from(&msg.pack().unwrap()[..]);
+            bytes[40..48].copy_from_slice(&sent.to_le_bytes());
+            match Message::unpack(bytes) {
+                Ok(Message::C2SMatchCreateOrJoin(C2SMatchCreateOrJoinBody::Create(m))) => {
+                    assert_eq!(m.passcode, -1, "{}", sent)
+                }
+                other => panic!("create {}: {:?}", sent, other),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn byte_counters_count_payloads() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let square = Coordinate {
+            l: 0,
+            t: 1,
+            board_color: Color::White,
+            y: 0,
+            x: 0,
+        };
+        let action = C2SOrS2CActionBody {
+            action_type: ActionType::SubmitMoves,
+            color: Color::White,
+            seconds_passed: 0,
+            src: square,
+            dst: square,
+        };
+        let c2s = [
+            Message::C2SGreet(C2SGreetBody {
+                version1: GREET_VERSION.0,
+                version2: GREET_VERSION.1,
+                extensions: 0,
+            }),
+            Message::C2SMatchListRequest,
+            Message::C2SOrS2CAction(action),
+            Message::C2SForfeit,
+        ];
+        let c2s_length: usize = c2s.iter().map(|msg| msg.legal_length()).sum();
+
+        // each frame is prefixed by its u64 length, which the counters leave out
+        let (client, mut wire) = tokio::io::duplex(1 << 16);
+        let mut client = MessageIO::new(client, MESSAGE_LENGTH_MAX).client_side();
+        for msg in c2s.iter().cloned() {
+            client.put(msg).await.unwrap();
+        }
+        client.flush().await.unwrap();
+        assert_eq!(client.bytes_written(), c2s_length as u64);
+        drop(client);
+        let mut raw = Vec::new();
+        wire.read_to_end(&mut raw).await.unwrap();
+        assert_eq!(raw.len(), c2s_length + 8 * c2s.len());
+
+        let (mut wire, server) = tokio::io::duplex(1 << 16);
+        wire.write_all(&raw).await.unwrap();
+        drop(wire);
+        let mut server = MessageIO::new(server, MESSAGE_LENGTH_MAX);
+        while server.get().await.unwrap().is_some() {}
+        assert_eq!(server.bytes_read(), c2s_length as u64);
+
+        let s2c = [
+            Message::S2CGreet,
+            Message::S2CServerNotice("Restarting in 5 minutes.".to_string()),
+            Message::C2SOrS2CAction(action),
+            Message::S2COpponentLeft,
+        ];
+        let s2c_length: usize = s2c.iter().map(|msg| msg.legal_length()).sum();
+        let (mut server, client) = MessageIO::new_duplex();
+        let mut client = client.client_side();
+        for msg in s2c.iter().cloned() {
+            server.put(msg).await.unwrap();
+        }
+        server.flush().await.unwrap();
+        for _ in 0..s2c.len() {
+            client.get().await.unwrap().unwrap();
+        }
+        assert_eq!(server.bytes_written(), s2c_length as u64);
+        assert_eq!(client.bytes_read(), s2c_length as u64);
+    }
+
+    #[tokio::test]
+    async fn closing_after_a_failed_flush_is_an_error() {
+        let (mut server, client) = MessageIO::new_duplex();
+        drop(client);
+        server.put(Message::S2COpponentLeft).await.unwrap();
+        assert!(server.flush().await.is_err());
+        assert!(server.close().await.is_err());
+    }
+
+    #[test]
+    fn enums_display_player_facing_names() {
+        fn names<T: std::fmt::Display>(values: impl IntoIterator<Item = T>) -> Vec<String> {
+            values.into_iter().map(|v| v.to_string()).collect()
+        }
+        use OptionalColorWithRandom as C;
+        assert_eq!(
+            names([C::None, C::Random, C::White, C::Black]),
+            ["None", "Random", "White", "Black"]
+        );
+        assert_eq!(names([Color::White, Color::Black]), ["White", "Black"]);
+        use OptionalClock as K;
+        assert_eq!(
+            names([K::None, K::NoClock, K::Short, K::Medium, K::Long]),
+            ["None", "No Clock", "Short", "Medium", "Long"]
+        );
+        assert_eq!(
+            names([Visibility::Public, Visibility::Private]),
+            ["Public", "Private"]
+        );
+        use ActionType as A;
+        assert_eq!(
+            names([
+                A::Move,
+                A::UndoMove,
+                A::SubmitMoves,
+                A::ResetPuzzle,
+                A::DisplayCheckReason,
+                A::Header
+            ]),
+            [
+                "Move",
+                "Undo Move",
+                "Submit Moves",
+                "Reset Puzzle",
+                "Display Check Reason",
+                "Header"
+            ]
+        );
+        assert_eq!(
+            names([HistoryMatchState::Completed, HistoryMatchState::InProgress]),
+            ["Completed", "In Progress"]
+        );
+        use MatchOutcome as O;
+        assert_eq!(
+            names([O::WhiteWin, O::BlackWin, O::Draw, O::Aborted]),
+            ["White Wins", "Black Wins", "Draw", "Aborted"]
+        );
+        assert_eq!(
+            names([Variant::Standard, Variant::SimpleKnightsVsBishops]),
+            ["Standard", "Simple - Knights vs. Bishops"]
+        );
+        // message types are named after their variants
+        for t in MessageType::all() {
+            assert_eq!(t.to_string(), format!("{:?}", t));
+        }
+    }
+
+    #[test]
+    fn message_type_indices_are_distinct_slots() {
+        let mut indices: Vec<usize> = MessageType::all().map(|t| t.index()).collect();
+        assert_eq!(indices.len(), 17);
+        indices.sort();
+        indices.dedup();
+        assert_eq!(indices.len(), 17);
+        assert!(indices.iter().all(|&i| i < MESSAGE_TYPE_SLOTS));
+    }
+
+    #[test]
+    fn counts_are_described_in_type_order() {
+        let mut counts: MessageCounts = [0; MESSAGE_TYPE_SLOTS];
+        assert_eq!(MessageType::describe_counts(&counts), "");
+        counts[MessageType::C2SVariantListRequest.index()] = 2;
+        counts[MessageType::C2SMatchListRequest.index()] = 3;
+        counts[MessageType::C2SGreet.index()] = 1;
+        assert_eq!(
+            MessageType::describe_counts(&counts),
+            "C2SGreet 1, C2SMatchListRequest 3, C2SVariantListRequest 2"
+        );
+    }
+
+    #[test]
+    fn kind_names_are_stable() {
+        let m = builder().build().unwrap();
+        let start = S2CMatchStartBody {
+            m: m.into(),
+            match_id: 1,
+            seconds_passed: 0,
+            opponent: None,
+        };
+        let square = Coordinate {
+            l: 0,
+            t: 1,
+            board_color: Color::White,
+            y: 0,
+            x: 0,
+        };
+        let action = C2SOrS2CActionBody {
+            action_type: ActionType::SubmitMoves,
+            color: Color::White,
+            seconds_passed: 0,
+            src: square,
+            dst: square,
+        };
+        let greet = C2SGreetBody {
+            version1: GREET_VERSION.0,
+            version2: GREET_VERSION.1,
+            extensions: 0,
+        };
+        let names = [
+            (Message::C2SGreet(greet), "greet"),
+            (Message::S2CGreet, "greet_reply"),
+            (
+                Message::C2SMatchCreateOrJoin(C2SMatchCreateOrJoinBody::Join(0)),
+                "create_or_join",
+            ),
+            (
+                Message::S2CMatchCreateOrJoinResult(S2CMatchCreateOrJoinResultBody::Failed),
+                "create_or_join_result",
+            ),
+            (Message::C2SMatchCancel, "cancel"),
+            (Message::cancel_failed(), "cancel_result"),
+            (Message::S2CMatchStart(start), "match_start"),
+            (Message::S2COpponentLeft, "opponent_left"),
+            (Message::C2SForfeit, "forfeit"),
+            (Message::C2SOrS2CAction(action), "action"),
+            (Message::C2SMatchListRequest, "match_list_request"),
+            (
+                Message::nonhost_match_list(S2CMatchListNonhostBody::empty(false)),
+                "match_list",
+            ),
+            (Message::S2CServerNotice(String::new()), "server_notice"),
+            (Message::S2CServerInfo(String::new()), "server_info"),
+            (Message::C2SQuickMatch(m), "quick_match"),
+            (Message::C2SVariantListRequest, "variant_list_request"),
+            (Message::S2CVariantList(Vec::new()), "variant_list"),
+            (
+                Message::InternalInitialize(mpsc::channel(1).0),
+                "internal_initialize",
+            ),
+            (Message::InternalJoin(0), "internal_join"),
+            (Message::InternalMatchStart(start), "internal_match_start"),
+            (
+                Message::InternalForfeit(ForfeitReason::Resign),
+                "internal_forfeit",
+            ),
+            (Message::InternalAction(action), "internal_action"),
+        ];
+        for (msg, name) in names {
+            assert_eq!(msg.kind_name(), name);
+        }
+    }
+
+    async fn time_to_put(mut io: MessageIO) -> Duration {
+        let start = tokio::time::Instant::now();
+        io.put(Message::S2COpponentLeft).await.unwrap();
+        start.elapsed()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn delayed_puts_wait_and_others_do_not() {
+        let (io, _client) = MessageIO::new_duplex();
+        let delay = Duration::from_millis(250);
+        assert!(time_to_put(io.delayed(delay)).await >= delay);
+        let (io, _client) = MessageIO::new_duplex();
+        assert_eq!(time_to_put(io).await, Duration::ZERO);
+    }
+
+    // predates the body of S2COpponentLeft, reads the type and stops
+    fn outdated_reader(bytes: &mut BytesMut) -> Result<Message> {
+        read_i64_le(bytes);
+        Ok(Message::S2COpponentLeft)
+    }
+
+    async fn read_with_outdated_reader(strict: bool) -> Result<Option<Message>> {
+        let (mut server, client) = MessageIO::new_duplex();
+        let client = MessageIO {
+            read: outdated_reader,
+            ..client
+        };
+        let mut client = if strict { client.strict() } else { client };
+        server.put(Message::S2COpponentLeft).await.unwrap();
+        server.flush().await.unwrap();
+        client.get().await
+    }
+
+    #[tokio::test]
+    async fn bytes_left_unread_are_an_error_when_strict() {
+        match read_with_outdated_reader(true).await {
+            Err(ProtocolError::InvalidData(e)) => assert!(e.contains("1 bytes left unread")),
+            other => panic!("Expected InvalidData, got {:?}", other),
+        }
+        assert!(matches!(
+            read_with_outdated_reader(false).await,
+            Ok(Some(Message::S2COpponentLeft))
+        ));
+    }
+
+    fn decode_join(passcode: Passcode) -> Result<Message> {
+        let msg = Message::C2SMatchCreateOrJoin(C2SMatchCreateOrJoinBody::Join(passcode));
+        Message::unpack(BytesMut::from(&msg.pack().unwrap()[..]))
+    }
+
+    #[test]
+    fn join_passcodes_in_range_are_accepted() {
+        for passcode in [0, PASSCODE_MAX] {
+            match decode_join(passcode) {
+                Ok(Message::C2SMatchCreateOrJoin(C2SMatchCreateOrJoinBody::Join(p))) => {
+                    assert_eq!(p, passcode)
+                }
+                other => panic!("join {}: {:?}", passcode, other),
+            }
+        }
+    }
+
+    #[test]
+    fn join_passcodes_out_of_range_are_rejected() {
+        // kkkkkk + 1, and what a player typing eight digits sends
+        for passcode in [PASSCODE_MAX + 1, 99999999] {
+            match decode_join(passcode) {
+                Err(ProtocolError::InvalidData(_)) => {}
+                other => panic!("join {}: {:?}", passcode, other),
+            }
+        }
+    }
+}
@@ -0,0 +1,16 @@
+#[macro_use]
+pub mod datatype;
+pub mod access_log;
+pub mod admin;
+pub mod bot;
+pub mod cidr;
+pub mod client;
+pub mod config;
+pub mod observer;
+#[cfg(feature = "pcap")]
+pub mod pcap;
+pub mod proxy;
+pub mod server;
+pub mod vectors;
+#[cfg(feature = "websocket")]
+pub mod websocket;
@@ -1,61 +1,188 @@
-use futures::future::join_all;
-use std::collections::{HashSet, VecDeque};
+use bytes::BytesMut;
+use futures::future::select_all;
+use std::collections::VecDeque;
+use std::env;
 use std::error::Error;
-use std::io::ErrorKind;
+use std::net::SocketAddr;
 use std::process::exit;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
-use std::{env, fs};
-use tokio::sync::watch;
-use tokio::{net::TcpListener, select};
-use tracing::{info, subscriber, Level};
+use std::time::Duration;
+use tokio::net::{lookup_host, TcpListener, TcpSocket};
+use tokio::select;
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use tracing::{info, info_span, subscriber, trace, warn, Instrument, Level};
 use tracing_subscriber::fmt::time::{OffsetTime, UtcTime};
 use tracing_subscriber::FmtSubscriber;
 
-#[macro_use]
-pub mod datatype;
-pub mod server;
-
-use datatype::*;
-use server::{handle_connection, ServerState};
+use fivedcserver::access_log::AccessLog;
+use fivedcserver::admin::serve_admin;
+use fivedcserver::config::{
+    get_config, get_listen_addrs, get_server_config, load_config, resolved_config,
+};
+use fivedcserver::datatype::*;
+use fivedcserver::observer::{forward_actions, OBSERVER_QUEUE_LENGTH};
+use fivedcserver::server::{
+    handle_connection, join_or_abort, set_stream_options, ServerConfig, ServerState,
+};
+#[cfg(feature = "websocket")]
+use fivedcserver::websocket::handle_websocket_connection;
 
 fn print_usage(arg0: &String) {
     println!();
-    println!("usage: {} <CONFIG FILE>", arg0);
+    println!(
+        "usage: {} [--check-config] [--no-write-default] <CONFIG FILE>",
+        arg0
+    );
+    println!("       {} decode <HEX>", arg0);
+    println!("       {} pcap <FILE> [<SERVER PORT> [<MAX FRAME>]]", arg0);
+    println!("       {} --version [--verbose]", arg0);
+}
+
+// remove a flag from anywhere in the args
+fn take_flag(args: &mut Vec<String>, flag: &str) -> bool {
+    match args.iter().position(|arg| arg == flag) {
+        Some(i) => {
+            args.remove(i);
+            true
+        }
+        None => false,
+    }
+}
+
+// what this binary supports, after the banner
+fn print_version_verbose() {
+    println!();
+    println!("protocol:");
+    println!(
+        "    C2SGreet version {}.{}",
+        GREET_VERSION.0, GREET_VERSION.1
+    );
+    println!("extensions:");
+    println!("    {} peer info", EXTENSION_PEER_INFO);
+    println!("    {} server notice", EXTENSION_SERVER_NOTICE);
+    println!("    {} match outcome", EXTENSION_MATCH_OUTCOME);
+    println!("    {} server info", EXTENSION_SERVER_INFO);
+    println!("variants:");
+    for variant in Variant::all() {
+        println!("    {} {}", variant as i64, variant);
+    }
+    println!("features:");
+    let features = [
+        ("pcap", cfg!(feature = "pcap")),
+        ("websocket", cfg!(feature = "websocket")),
+    ];
+    for (name, _) in features.iter().filter(|(_, enabled)| *enabled) {
+        println!("    {}", name);
+    }
+    if !features.iter().any(|(_, enabled)| *enabled) {
+        println!("    (none)");
+    }
 }
 
-fn get_config<'a, T: toml::macros::Deserialize<'a>>(
-    config: &toml::value::Table,
-    name: &str,
-    default: T,
-) -> T {
-    match config.get(name) {
-        Some(value) => match value.clone().try_into() {
-            Ok(value) => value,
-            _ => default,
-        },
-        None => default,
+fn parse_hex(hex: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    if let Some(i) = hex.find(|c: char| !c.is_ascii_hexdigit()) {
+        Err(format!("Invalid hex digit at offset {}.", i))?;
+    }
+    if hex.len() % 2 == 1 {
+        Err("Hex string has an odd number of digits.")?;
     }
+    Ok((0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+        .collect())
+}
+
+// decode one frame without the length prefix, same path as the server
+fn decode(hex: &str) -> Result<(), Box<dyn Error>> {
+    let bytes = parse_hex(hex.trim())?;
+    match Message::unpack(BytesMut::from(&bytes[..])) {
+        Ok(msg) => println!("{:#?}", msg),
+        Err(e) => Err(e.to_string())?,
+    }
+    Ok(())
+}
+
+// one line per message, with the time since the first packet and the client
+// max_frame is the limit_message_length of the captured server, bytes read mid-stream as a
+// length prefix can claim anything and must not be allocated
+#[cfg(feature = "pcap")]
+fn decode_pcap_file(path: &str, port: u16, max_frame: usize) -> Result<(), Box<dyn Error>> {
+    let messages = fivedcserver::pcap::decode_pcap(&std::fs::read(path)?, port, max_frame)?;
+    for m in messages {
+        let direction = if m.from_server { "<-" } else { "->" };
+        match m.result {
+            Ok(msg) => println!(
+                "{:.6} {} {} {:?}",
+                m.time.as_secs_f64(),
+                m.client,
+                direction,
+                msg
+            ),
+            Err(e) => println!(
+                "{:.6} {} {} error: {}",
+                m.time.as_secs_f64(),
+                m.client,
+                direction,
+                e
+            ),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "pcap"))]
+fn decode_pcap_file(_path: &str, _port: u16, _max_frame: usize) -> Result<(), Box<dyn Error>> {
+    Err("pcap requires the pcap feature at build time.")?
+}
+
+// like TcpListener::bind but with the accept queue length
+async fn bind_listener(bind_addr: &str, backlog: u32) -> Result<TcpListener, Box<dyn Error>> {
+    let addr = lookup_host(bind_addr)
+        .await?
+        .next()
+        .ok_or_else(|| format!("Failed to resolve {:?}.", bind_addr))?;
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    // same as TcpListener::bind, restarts don't wait for TIME_WAIT
+    #[cfg(unix)]
+    socket.set_reuseaddr(true)?;
+    socket.bind(addr)?;
+    Ok(socket.listen(backlog)?)
 }
 
 #[tokio::main]
-async fn async_main(config: toml::Table) -> Result<(), Box<dyn Error>> {
+async fn async_main(
+    config: toml::Table,
+    server_config: ServerConfig,
+) -> Result<(), Box<dyn Error>> {
     // init server state
-    let allow_reset_puzzle = get_config(&config, "allow_reset_puzzle", false);
-    let variants = get_config(&config, "variants", toml::value::Array::new());
-    let variants = {
-        let mut variants_set = HashSet::new();
-        if variants.len() == 0 {
-            for i in 1..46 {
-                variants_set.insert(try_i64_to_enum(i)?);
-            }
-        } else {
-            for i in variants {
-                variants_set.insert(try_i64_to_enum(i.as_integer().unwrap())?);
-            }
+    let mut state = ServerState::new(server_config);
+    let access_log: String = get_config(&config, "access_log", String::new())?;
+    if !access_log.is_empty() {
+        let file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&access_log)
+            .await
+            .map_err(|e| format!("Failed to open access_log {:?}: {}", access_log, e))?;
+        let salt: String = get_config(&config, "access_log_salt", String::new())?;
+        // an unsalted hash of an ipv4 address is trivially reversed
+        if salt.is_empty() {
+            Err("access_log_salt must be set when access_log is enabled.")?;
         }
-        variants_set
-    };
-    let state = Arc::new(ServerState::new(allow_reset_puzzle, variants));
+        state = state.with_access_log(AccessLog::new(file, salt));
+        info!("access log appended to {} ...", access_log);
+    }
+    let observer: String = get_config(&config, "observer", String::new())?;
+    let (observer_tx, observer_rx) = mpsc::channel(OBSERVER_QUEUE_LENGTH);
+    if !observer.is_empty() {
+        state = state.with_observer(observer_tx);
+    }
+    let state = Arc::new(state);
 
     // handle ctrl-c
     let (running_tx, mut running_rx) = watch::channel(true);
@@ -72,21 +199,79 @@ async fn async_main(config: toml::Table) -> Result<(), Box<dyn Error>> {
     })?;
 
     // bind and listen for connections
-    let addr = get_config(&config, "addr", "0.0.0.0");
-    let port = get_config(&config, "port", 39005);
-    let bind_addr = (addr, port);
-    let listener = TcpListener::bind(bind_addr).await?;
-    info!("listening on {}:{} ...", bind_addr.0, bind_addr.1);
+    let backlog: u32 = get_config(&config, "listen_backlog", 1024)?;
+    let tcp_nodelay: bool = get_config(&config, "tcp_nodelay", true)?;
+    let shutdown_timeout = match get_config(&config, "shutdown_timeout", 10)? {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    };
+    let mut listeners = Vec::new(); // and whether they speak WebSocket
+    for bind_addr in get_listen_addrs(&config)? {
+        listeners.push((bind_listener(&bind_addr, backlog).await?, false));
+        info!("listening on {} ...", bind_addr);
+    }
+    let websocket: String = get_config(&config, "websocket", String::new())?;
+    if !websocket.is_empty() {
+        if !cfg!(feature = "websocket") {
+            Err("websocket requires the websocket feature at build time.")?;
+        }
+        listeners.push((bind_listener(&websocket, backlog).await?, true));
+        info!("WebSocket listening on {} ...", websocket);
+    }
+    let admin: String = get_config(&config, "admin", String::new())?;
+    if !admin.is_empty() {
+        let listener = TcpListener::bind(&admin).await?;
+        info!("admin listening on {} ...", admin);
+        tokio::spawn(serve_admin(state.clone(), listener, running_rx.clone()));
+    }
+    if !observer.is_empty() {
+        info!("observer forwarding to {} ...", observer);
+        tokio::spawn(forward_actions(
+            state.clone(),
+            observer,
+            observer_rx,
+            running_rx.clone(),
+        ));
+    }
 
-    let mut handles = VecDeque::new();
+    let mut handles: VecDeque<JoinHandle<()>> = VecDeque::new();
     loop {
         select! {
-            result = listener.accept() => {
+            (result, i, _) = select_all(listeners.iter().map(|(listener, _)| Box::pin(listener.accept()))) => {
                 let (stream, addr) = result?;
-                handles.push_back(tokio::spawn(handle_connection(state.clone(), stream, addr, running_rx.clone())));
+                if !state.config.ip_filter.permits(addr.ip()) {
+                    trace!("[{}:{}] Refused by allow_cidr or deny_cidr.", addr.ip(), addr.port());
+                    drop(stream);
+                    continue;
+                }
+                let Some(guard) = state.admit_connection() else {
+                    warn!("[{}:{}] Refused, limit_connections reached.", addr.ip(), addr.port());
+                    drop(stream);
+                    continue;
+                };
+                set_stream_options(&stream, addr, tcp_nodelay);
+                // every handler ends on running.changed(), only the live ones need joining
+                handles.retain(|handle| !handle.is_finished());
+                let state = state.clone();
+                let running_rx = running_rx.clone();
+                let is_websocket = listeners[i].1;
+                // every log line of the connection carries the id, addresses repeat across reconnects
+                let id = state.connection_id.fetch_add(1, Ordering::Relaxed);
+                handles.push_back(tokio::spawn(async move {
+                    if is_websocket {
+                        #[cfg(feature = "websocket")]
+                        handle_websocket_connection(state.clone(), stream, addr, running_rx, id).await;
+                    } else {
+                        handle_connection(state.clone(), stream, addr, running_rx, id).await;
+                    }
+                    drop(guard);
+                }.instrument(info_span!("connection", id))));
             },
             result = running_rx.changed() => {
-                join_all(handles).await;
+                let aborted = join_or_abort(&mut handles, shutdown_timeout).await;
+                if aborted > 0 {
+                    warn!("Aborted {} stuck connections after shutdown_timeout.", aborted);
+                }
                 info!("Stopped.");
                 break Ok(result?);
             }
@@ -100,43 +285,75 @@ fn main() -> Result<(), Box<dyn Error>> {
     println!("Copyright (C) 2022 NKID00, licensed under AGPL-3.0-only");
 
     // parse args
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
+    let check_config = take_flag(&mut args, "--check-config");
+    // a missing config is an error instead, e.g. read-only deployments or a mistyped path
+    let no_write_default = take_flag(&mut args, "--no-write-default");
+    if take_flag(&mut args, "--version") {
+        // the banner is the short version
+        if take_flag(&mut args, "--verbose") {
+            print_version_verbose();
+        }
+        return Ok(());
+    }
     if args.len() <= 1 {
         print_usage(&args[0]);
         exit(1);
     }
+    if args[1] == "decode" {
+        if args.len() != 3 {
+            print_usage(&args[0]);
+            exit(1);
+        }
+        return decode(&args[2]);
+    }
+    if args[1] == "pcap" {
+        if !(3..=5).contains(&args.len()) {
+            print_usage(&args[0]);
+            exit(1);
+        }
+        let port = match args.get(3) {
+            Some(port) => port.parse()?,
+            None => 39005,
+        };
+        let max_frame = match args.get(4) {
+            Some(max_frame) => max_frame.parse()?,
+            None => MESSAGE_LENGTH_MAX,
+        };
+        return decode_pcap_file(&args[2], port, max_frame);
+    }
 
     // load config
-    let config = match fs::read(&args[1]) {
-        Ok(config) => toml::from_str(String::from_utf8(config)?.as_str())?,
-        Err(e) if e.kind() == ErrorKind::NotFound => {
-            let config = toml::toml! {
-                addr = "0.0.0.0"
-                allow_reset_puzzle = false
-                port = 39005
-                trace = false
-                variants = []
-            };
-            fs::write(&args[1], config.to_string())?;
-            config
+    let path = &args[1];
+    let config = load_config(path, !(check_config || no_write_default))?;
+
+    // validate and print the resolved config without binding
+    if check_config {
+        let server_config = get_server_config(&config)?;
+        for line in resolved_config(&config, &server_config)? {
+            println!("{}", line);
         }
-        Err(e) => Err(e)?,
+        return Ok(());
     }
-    .try_into()
-    .unwrap();
 
     // register tracing
-    let trace = get_config(&config, "trace", false);
-    let sub = FmtSubscriber::builder()
-        .with_max_level(if cfg!(debug_assertions) || trace {
-            Level::TRACE
-        } else {
-            Level::INFO
-        });
+    let trace = get_config(&config, "trace", false)?;
+    let sub = FmtSubscriber::builder().with_max_level(if cfg!(debug_assertions) || trace {
+        Level::TRACE
+    } else {
+        Level::INFO
+    });
     match OffsetTime::local_rfc_3339() {
         Ok(timer) => subscriber::set_global_default(sub.with_timer(timer).finish()),
         Err(_) => subscriber::set_global_default(sub.with_timer(UtcTime::rfc_3339()).finish()),
     }?;
+    // once, after tracing so that its deprecation warnings are logged
+    let server_config = get_server_config(&config)?;
+    if get_config(&config, "log_config", false)? {
+        for line in resolved_config(&config, &server_config)? {
+            info!("{}", line);
+        }
+    }
 
-    async_main(config)
+    async_main(config, server_config)
 }
@@ -0,0 +1,142 @@
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::select;
+use tokio::sync::{mpsc, watch};
+use tokio::time::{timeout, Instant};
+use tracing::{info, warn};
+
+use crate::datatype::{C2SOrS2CActionBody, MatchId};
+use crate::server::{action_line, ServerState};
+
+pub const OBSERVER_QUEUE_LENGTH: usize = 1024; // actions waiting for the collector
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/* firehose of every relayed action to a collector, one trace_moves line each
+the relay never waits for it, actions are dropped when the queue is full or the collector is down
+*/
+pub async fn forward_actions(
+    ss: Arc<ServerState>,
+    addr: String,
+    mut rx: mpsc::Receiver<(MatchId, C2SOrS2CActionBody)>,
+    mut running: watch::Receiver<bool>,
+) {
+    let mut stream: Option<TcpStream> = None;
+    let mut retry_at = Instant::now();
+    loop {
+        let (match_id, body) = select! {
+            Some(action) = rx.recv() => action,
+            _ = running.changed() => break,
+        };
+        if stream.is_none() {
+            if Instant::now() < retry_at {
+                ss.observer_dropped.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+            match timeout(CONNECT_TIMEOUT, TcpStream::connect(&addr)).await {
+                Ok(Ok(s)) => {
+                    info!("Connected to the observer {}.", addr);
+                    stream = Some(s);
+                }
+                Ok(Err(e)) => warn!("Failed to connect to the observer {}: {}", addr, e),
+                Err(_) => warn!("Failed to connect to the observer {}: timed out.", addr),
+            }
+        }
+        let line = format!("{}\n", action_line(match_id, &body));
+        let written = match &mut stream {
+            Some(s) => s.write_all(line.as_bytes()).await,
+            None => Err(std::io::ErrorKind::NotConnected.into()),
+        };
+        if let Err(e) = written {
+            if stream.take().is_some() {
+                warn!("Lost the observer {}: {}", addr, e);
+            }
+            ss.observer_dropped.fetch_add(1, Ordering::Relaxed);
+            retry_at = Instant::now() + RECONNECT_DELAY;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datatype::{ActionType, Color, Coordinate};
+    use crate::server::ServerConfig;
+    use tokio::io::{AsyncBufReadExt, BufReader};
+    use tokio::net::TcpListener;
+
+    const TEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+    fn action(seconds_passed: u64) -> C2SOrS2CActionBody {
+        let src = Coordinate {
+            l: 0,
+            t: 1,
+            board_color: Color::White,
+            y: 1,
+            x: 4,
+        };
+        C2SOrS2CActionBody {
+            action_type: ActionType::Move,
+            color: Color::White,
+            seconds_passed,
+            src,
+            dst: Coordinate { y: 3, ..src },
+        }
+    }
+
+    #[tokio::test]
+    async fn actions_reach_the_collector() {
+        let collector = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = collector.local_addr().unwrap().to_string();
+        let ss = Arc::new(ServerState::new(ServerConfig::default()));
+        let (tx, rx) = mpsc::channel(OBSERVER_QUEUE_LENGTH);
+        let (running_tx, running) = watch::channel(true);
+        let forwarder = tokio::spawn(forward_actions(ss.clone(), addr, rx, running));
+
+        let actions = [(1, action(0)), (2, action(7))];
+        for action in actions {
+            tx.send(action).await.unwrap();
+        }
+        let (stream, _) = timeout(TEST_TIMEOUT, collector.accept())
+            .await
+            .unwrap()
+            .unwrap();
+        let mut lines = BufReader::new(stream).lines();
+        for (match_id, body) in &actions {
+            let line = timeout(TEST_TIMEOUT, lines.next_line()).await.unwrap();
+            assert_eq!(line.unwrap().unwrap(), action_line(*match_id, body));
+        }
+        assert_eq!(ss.observer_dropped.load(Ordering::Relaxed), 0);
+
+        running_tx.send_replace(false);
+        timeout(TEST_TIMEOUT, forwarder).await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn actions_are_dropped_without_a_collector() {
+        let collector = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = collector.local_addr().unwrap().to_string();
+        drop(collector);
+        let ss = Arc::new(ServerState::new(ServerConfig::default()));
+        let (tx, rx) = mpsc::channel(OBSERVER_QUEUE_LENGTH);
+        let (running_tx, running) = watch::channel(true);
+        let forwarder = tokio::spawn(forward_actions(ss.clone(), addr, rx, running));
+
+        // the first fails to connect, the second is dropped before the reconnect delay
+        tx.send((1, action(0))).await.unwrap();
+        tx.send((1, action(1))).await.unwrap();
+        timeout(TEST_TIMEOUT, async {
+            while ss.observer_dropped.load(Ordering::Relaxed) < 2 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        running_tx.send_replace(false);
+        timeout(TEST_TIMEOUT, forwarder).await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,324 @@
+use bytes::BytesMut;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio_util::codec::{Decoder, LengthDelimitedCodec};
+
+use crate::datatype::{frame_codec, Message, ProtocolError, Result};
+
+/* offline decoder for classic pcap captures (tcpdump -w, not pcapng)
+the format is simple enough to read without a dependency
+link types: ethernet, loopback, raw ip and linux cooked v1/v2, ip fragments are skipped
+segments are assumed to arrive in order per direction, retransmitted bytes are dropped
+a gap (lost or reordered segment) stops decoding that direction, the framing is lost
+*/
+
+// one decoded frame, or why the stream couldn't be decoded further
+#[derive(Debug)]
+pub struct PcapMessage {
+    pub time: Duration, // since the first packet
+    pub client: SocketAddr,
+    pub from_server: bool,
+    pub result: Result<Message>,
+}
+
+struct Direction {
+    next_seq: Option<u32>, // None before the first segment
+    buffer: BytesMut,
+    codec: LengthDelimitedCodec, // remembers a length prefix already read
+    broken: bool,
+}
+
+struct Segment<'a> {
+    src: SocketAddr,
+    dst: SocketAddr,
+    seq: u32,
+    syn: bool,
+    payload: &'a [u8],
+}
+
+fn be16(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(
+        data.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+fn be32(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(
+        data.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+// the ip packet inside a link layer frame
+fn link_payload(link_type: u32, frame: &[u8]) -> Option<&[u8]> {
+    match link_type {
+        // loopback, the 4-byte address family is host endian, the ip version tells anyway
+        0 => frame.get(4..),
+        1 => {
+            let mut offset = 12;
+            // 802.1Q tags
+            while be16(frame, offset)? == 0x8100 {
+                offset += 4;
+            }
+            frame.get(offset + 2..)
+        }
+        12 | 101 => Some(frame),
+        113 => frame.get(16..),
+        276 => frame.get(20..),
+        _ => None,
+    }
+}
+
+fn tcp_segment(packet: &[u8]) -> Option<Segment<'_>> {
+    let (src_ip, dst_ip, tcp): (IpAddr, IpAddr, &[u8]) = match packet.first()? >> 4 {
+        4 => {
+            let header_length = usize::from(packet[0] & 0x0f) * 4;
+            let total_length = usize::from(be16(packet, 2)?);
+            // fragmented or not tcp
+            if be16(packet, 6)? & 0x3fff != 0 || *packet.get(9)? != 6 {
+                return None;
+            }
+            let src: [u8; 4] = packet.get(12..16)?.try_into().ok()?;
+            let dst: [u8; 4] = packet.get(16..20)?.try_into().ok()?;
+            (
+                Ipv4Addr::from(src).into(),
+                Ipv4Addr::from(dst).into(),
+                packet.get(header_length..total_length)?,
+            )
+        }
+        // extension headers are not followed
+        6 if *packet.get(6)? == 6 => {
+            let payload_length = usize::from(be16(packet, 4)?);
+            let src: [u8; 16] = packet.get(8..24)?.try_into().ok()?;
+            let dst: [u8; 16] = packet.get(24..40)?.try_into().ok()?;
+            (
+                Ipv6Addr::from(src).into(),
+                Ipv6Addr::from(dst).into(),
+                packet.get(40..40 + payload_length)?,
+            )
+        }
+        _ => return None,
+    };
+    let data_offset = usize::from(tcp.get(12)? >> 4) * 4;
+    Some(Segment {
+        src: SocketAddr::new(src_ip, be16(tcp, 0)?),
+        dst: SocketAddr::new(dst_ip, be16(tcp, 2)?),
+        seq: be32(tcp, 4)?,
+        syn: tcp.get(13)? & 0x02 != 0,
+        payload: tcp.get(data_offset..)?,
+    })
+}
+
+impl Direction {
+    // None if the segment carries nothing new
+    fn append(&mut self, segment: &Segment) -> Option<Result<()>> {
+        if segment.syn {
+            self.next_seq = Some(segment.seq.wrapping_add(1));
+            self.buffer.clear();
+            self.codec = frame_codec(self.codec.max_frame_length());
+            self.broken = false;
+        }
+        if self.broken || segment.payload.is_empty() {
+            return None;
+        }
+        // a capture started mid-stream is decoded from the first segment seen
+        let next_seq = *self.next_seq.get_or_insert(segment.seq);
+        let offset = segment.seq.wrapping_sub(next_seq) as i32;
+        if offset > 0 {
+            self.broken = true;
+            return Some(Err(ProtocolError::InvalidData(format!(
+                "Missing {} bytes of the stream, the rest of it is skipped.",
+                offset
+            ))));
+        }
+        let payload = segment.payload.get(offset.unsigned_abs() as usize..)?;
+        if payload.is_empty() {
+            return None;
+        }
+        self.buffer.extend_from_slice(payload);
+        self.next_seq = Some(next_seq.wrapping_add(payload.len() as u32));
+        Some(Ok(()))
+    }
+}
+
+// TCP streams from or to server_port, messages in capture order
+pub fn decode_pcap(
+    data: &[u8],
+    server_port: u16,
+    max_frame_length: usize,
+) -> std::result::Result<Vec<PcapMessage>, String> {
+    let magic = data.get(..4).ok_or("Not a pcap file, too short.")?;
+    let (big_endian, nanoseconds) = match magic {
+        [0xd4, 0xc3, 0xb2, 0xa1] => (false, false),
+        [0x4d, 0x3c, 0xb2, 0xa1] => (false, true),
+        [0xa1, 0xb2, 0xc3, 0xd4] => (true, false),
+        [0xa1, 0xb2, 0x3c, 0x4d] => (true, true),
+        [0x0a, 0x0d, 0x0d, 0x0a] => {
+            Err("pcapng is not supported, convert it with editcap -F pcap.")?
+        }
+        _ => Err("Not a pcap file.")?,
+    };
+    let u32_at = |offset: usize| {
+        data.get(offset..offset + 4).map(|b| {
+            let b: [u8; 4] = b.try_into().unwrap();
+            if big_endian {
+                u32::from_be_bytes(b)
+            } else {
+                u32::from_le_bytes(b)
+            }
+        })
+    };
+    let link_type = u32_at(20).ok_or("Truncated pcap header.")? & 0xffff;
+    let mut directions: HashMap<(SocketAddr, bool), Direction> = HashMap::new();
+    let mut messages = Vec::new();
+    let mut first = None;
+    let mut offset = 24;
+    while offset < data.len() {
+        let header = (u32_at(offset), u32_at(offset + 4), u32_at(offset + 8));
+        let (Some(secs), Some(frac), Some(captured)) = header else {
+            Err("Truncated packet header.")?
+        };
+        let frame = data
+            .get(offset + 16..offset + 16 + captured as usize)
+            .ok_or("Truncated packet.")?;
+        offset += 16 + captured as usize;
+        let time = Duration::new(
+            u64::from(secs),
+            if nanoseconds {
+                frac
+            } else {
+                frac.saturating_mul(1000)
+            },
+        );
+        let time = time.saturating_sub(*first.get_or_insert(time));
+        let Some(segment) = link_payload(link_type, frame).and_then(tcp_segment) else {
+            continue;
+        };
+        let (client, from_server) = if segment.src.port() == server_port {
+            (segment.dst, true)
+        } else if segment.dst.port() == server_port {
+            (segment.src, false)
+        } else {
+            continue;
+        };
+        let direction = directions
+            .entry((client, from_server))
+            .or_insert_with(|| Direction {
+                next_seq: None,
+                buffer: BytesMut::new(),
+                codec: frame_codec(max_frame_length),
+                broken: false,
+            });
+        let mut push = |result| {
+            messages.push(PcapMessage {
+                time,
+                client,
+                from_server,
+                result,
+            })
+        };
+        match direction.append(&segment) {
+            None => continue,
+            Some(Err(e)) => push(Err(e)),
+            // partial frames stay in the buffer until the next segment
+            Some(Ok(())) => loop {
+                match direction.codec.decode(&mut direction.buffer) {
+                    Ok(Some(frame)) if from_server => push(Message::unpack_s2c(frame)),
+                    Ok(Some(frame)) => push(Message::unpack(frame)),
+                    Ok(None) => break,
+                    Err(_) => {
+                        direction.broken = true;
+                        push(Err(ProtocolError::FrameTooLong));
+                        break;
+                    }
+                }
+            },
+        }
+    }
+    Ok(messages)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datatype::{C2SGreetBody, GREET_VERSION, MESSAGE_LENGTH_MAX};
+
+    const PORT: u16 = 39005;
+
+    // little endian microsecond pcap of raw ipv4, one client talking to PORT
+    fn capture(segments: &[(u32, &[u8])]) -> Vec<u8> {
+        let mut data = Vec::new();
+        for field in [0xa1b2c3d4, 0x00040002, 0, 0, 65535, 101u32] {
+            data.extend_from_slice(&field.to_le_bytes());
+        }
+        for (seq, payload) in segments {
+            let mut packet = vec![0x45, 0];
+            packet.extend_from_slice(&(40 + payload.len() as u16).to_be_bytes());
+            packet.extend_from_slice(&[0, 0, 0, 0, 64, 6, 0, 0, 10, 0, 0, 2, 10, 0, 0, 1]);
+            packet.extend_from_slice(&50000u16.to_be_bytes());
+            packet.extend_from_slice(&PORT.to_be_bytes());
+            packet.extend_from_slice(&seq.to_be_bytes());
+            packet.extend_from_slice(&[0, 0, 0, 0, 0x50, 0x18, 0xff, 0xff, 0, 0, 0, 0]);
+            packet.extend_from_slice(payload);
+            for field in [1, 0, packet.len() as u32, packet.len() as u32] {
+                data.extend_from_slice(&field.to_le_bytes());
+            }
+            data.extend_from_slice(&packet);
+        }
+        data
+    }
+
+    fn greet_frame() -> Vec<u8> {
+        let msg = Message::C2SGreet(C2SGreetBody {
+            version1: GREET_VERSION.0,
+            version2: GREET_VERSION.1,
+            extensions: 0,
+        });
+        let bytes = msg.pack().unwrap();
+        let mut frame = (bytes.len() as u64).to_le_bytes().to_vec();
+        frame.extend_from_slice(&bytes);
+        frame
+    }
+
+    #[test]
+    fn frames_are_decoded() {
+        let frame = greet_frame();
+        let (head, tail) = frame.split_at(5);
+        let messages = decode_pcap(
+            &capture(&[(100, head), (105, tail)]),
+            PORT,
+            MESSAGE_LENGTH_MAX,
+        )
+        .unwrap();
+        assert_eq!(messages.len(), 1);
+        assert!(!messages[0].from_server);
+        assert!(matches!(messages[0].result, Ok(Message::C2SGreet(_))));
+    }
+
+    // started mid-message, the first bytes seen are read as a length prefix
+    #[test]
+    fn mid_stream_capture_is_bounded() {
+        let mut payload = vec![0xff; 8];
+        payload.extend_from_slice(&greet_frame());
+        let data = capture(&[
+            (100, &payload),
+            (100 + payload.len() as u32, &greet_frame()),
+        ]);
+        let messages = decode_pcap(&data, PORT, MESSAGE_LENGTH_MAX).unwrap();
+        // the direction is broken after the bogus prefix, the rest is skipped
+        assert_eq!(messages.len(), 1);
+        assert!(matches!(
+            messages[0].result,
+            Err(ProtocolError::FrameTooLong)
+        ));
+    }
+
+    #[test]
+    fn truncated_capture_is_an_error() {
+        let frame = greet_frame();
+        let data = capture(&[(100, &frame)]);
+        let result = decode_pcap(&data[..data.len() - 3], PORT, MESSAGE_LENGTH_MAX);
+        assert_eq!(result.unwrap_err(), "Truncated packet.");
+        assert!(decode_pcap(&data[..10], PORT, MESSAGE_LENGTH_MAX).is_err());
+    }
+}
@@ -0,0 +1,152 @@
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::datatype::Result;
+
+const SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/* PROXY protocol v2 header, sent by a load balancer before any client data
+returns the client address, None for LOCAL connections (health checks) and unsupported families
+*/
+pub async fn read_proxy_header<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<SocketAddr>> {
+    let mut header = [0; 16];
+    stream.read_exact(&mut header).await?;
+    if header[..12] != SIGNATURE {
+        return err_invalid_data!("Missing PROXY protocol v2 signature.");
+    }
+    if header[12] >> 4 != 2 {
+        return err_invalid_data!("Unsupported PROXY protocol version {}.", header[12] >> 4);
+    }
+    let command = header[12] & 0xf;
+    let family = header[13] >> 4;
+    // addresses, then optional TLVs which are skipped
+    let mut body = vec![0; u16::from_be_bytes([header[14], header[15]]) as usize];
+    stream.read_exact(&mut body).await?;
+    match (command, family) {
+        (0, _) => Ok(None), // LOCAL
+        (1, 1) if body.len() >= 12 => {
+            let ip = Ipv4Addr::new(body[0], body[1], body[2], body[3]);
+            let port = u16::from_be_bytes([body[8], body[9]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        (1, 2) if body.len() >= 36 => {
+            let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&body[..16]).unwrap());
+            let port = u16::from_be_bytes([body[32], body[33]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        (1, 1) | (1, 2) => err_invalid_data!("Truncated PROXY protocol addresses."),
+        (1, 0) | (1, 3) => Ok(None), // UNSPEC and UNIX, keep the peer address
+        (1, _) => err_invalid_data!("Unknown PROXY protocol address family {}.", family),
+        _ => err_invalid_data!("Unknown PROXY protocol command {}.", command),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datatype::ProtocolError;
+
+    // the header followed by what the client sends
+    fn header(version_command: u8, family: u8, body: &[u8]) -> Vec<u8> {
+        let mut bytes = SIGNATURE.to_vec();
+        bytes.push(version_command);
+        bytes.push(family << 4 | 1); // over TCP
+        bytes.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(body);
+        bytes.extend_from_slice(b"5dc");
+        bytes
+    }
+
+    // the result and whether exactly the header was consumed
+    async fn read(bytes: &[u8]) -> (Result<Option<SocketAddr>>, bool) {
+        let mut reader = bytes;
+        let result = read_proxy_header(&mut reader).await;
+        (result, reader == b"5dc")
+    }
+
+    fn addresses(src: &[u8], dst: &[u8], ports: [u16; 2]) -> Vec<u8> {
+        let mut body = [src, dst].concat();
+        body.extend_from_slice(&ports[0].to_be_bytes());
+        body.extend_from_slice(&ports[1].to_be_bytes());
+        body
+    }
+
+    fn invalid_data(result: Result<Option<SocketAddr>>) -> String {
+        match result {
+            Err(ProtocolError::InvalidData(e)) => e,
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn proxied_addresses_are_read() {
+        let v4 = addresses(&[192, 0, 2, 1], &[198, 51, 100, 1], [39005, 443]);
+        let (result, consumed) = read(&header(0x21, 1, &v4)).await;
+        assert_eq!(result.unwrap(), Some("192.0.2.1:39005".parse().unwrap()));
+        assert!(consumed);
+
+        let src: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let dst: Ipv6Addr = "2001:db8::2".parse().unwrap();
+        let v6 = addresses(&src.octets(), &dst.octets(), [39005, 443]);
+        let (result, consumed) = read(&header(0x21, 2, &v6)).await;
+        assert_eq!(
+            result.unwrap(),
+            Some("[2001:db8::1]:39005".parse().unwrap())
+        );
+        assert!(consumed);
+    }
+
+    #[tokio::test]
+    async fn tlvs_after_the_addresses_are_skipped() {
+        let mut body = addresses(&[192, 0, 2, 1], &[198, 51, 100, 1], [39005, 443]);
+        body.extend_from_slice(&[0x04, 0x00, 0x03, 1, 2, 3]); // PP2_TYPE_NOOP
+        let (result, consumed) = read(&header(0x21, 1, &body)).await;
+        assert_eq!(result.unwrap(), Some("192.0.2.1:39005".parse().unwrap()));
+        assert!(consumed);
+    }
+
+    #[tokio::test]
+    async fn local_and_unspecified_keep_the_peer_address() {
+        for (version_command, family, body) in
+            [(0x20, 0, &[][..]), (0x21, 0, &[]), (0x21, 3, &[0; 216])]
+        {
+            let (result, consumed) = read(&header(version_command, family, body)).await;
+            assert_eq!(result.unwrap(), None);
+            assert!(consumed);
+        }
+    }
+
+    #[tokio::test]
+    async fn malformed_headers_are_rejected() {
+        let mut bytes = header(0x21, 1, &[0; 12]);
+        bytes[11] = b'\r';
+        assert_eq!(
+            invalid_data(read(&bytes).await.0),
+            "Missing PROXY protocol v2 signature."
+        );
+        assert_eq!(
+            invalid_data(read(&header(0x11, 1, &[0; 12])).await.0),
+            "Unsupported PROXY protocol version 1."
+        );
+        assert_eq!(
+            invalid_data(read(&header(0x22, 1, &[0; 12])).await.0),
+            "Unknown PROXY protocol command 2."
+        );
+        assert_eq!(
+            invalid_data(read(&header(0x21, 4, &[])).await.0),
+            "Unknown PROXY protocol address family 4."
+        );
+        // one byte short of each address block, never sliced out of range
+        for (family, length) in [(1, 11), (2, 35)] {
+            let (result, consumed) = read(&header(0x21, family, &vec![0; length])).await;
+            assert_eq!(invalid_data(result), "Truncated PROXY protocol addresses.");
+            assert!(consumed);
+        }
+        // the stream ends within the header
+        let bytes = header(0x21, 1, &[0; 12]);
+        assert!(matches!(
+            read(&bytes[..20]).await.0,
+            Err(ProtocolError::Io(_))
+        ));
+    }
+}
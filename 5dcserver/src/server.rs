@@ -1,51 +1,419 @@
+use futures::future::join_all;
 use indexmap::IndexMap;
-use std::collections::{HashMap, HashSet};
+use rand::distributions::WeightedIndex;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::error::Error;
+use std::hash::BuildHasher;
 use std::io::ErrorKind;
-use std::net::SocketAddr;
-use std::sync::atomic::{AtomicI64, Ordering};
-use std::sync::Arc;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::atomic::{AtomicI64, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::Duration;
 use tokio::net::TcpStream;
 use tokio::select;
-use tokio::sync::{broadcast, watch, Mutex};
-use tokio::time::Instant;
-use tracing::{error, info, trace};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{broadcast, mpsc, watch, Mutex};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep_until, timeout, Instant};
+use tracing::{error, info, info_span, trace, warn, Instrument};
 
+use crate::access_log::{AccessLog, AccessRecord};
+use crate::bot::run_echo_bot;
+use crate::cidr::IpFilter;
 use crate::datatype::*;
+use crate::proxy::read_proxy_header;
+
+#[derive(Debug, Clone)]
+pub enum ServerEvent {
+    Connected {
+        connection_id: ConnectionId,
+        addr: SocketAddr,
+    },
+    MatchCreated {
+        match_id: MatchId,
+        variant: Variant,
+        visibility: Visibility,
+    },
+    MatchStarted {
+        match_id: MatchId,
+    },
+    MatchEnded {
+        match_id: MatchId,
+        reason: ForfeitReason,
+    },
+    Disconnected {
+        connection_id: ConnectionId,
+        addr: SocketAddr,
+        reason: DisconnectReason,
+    },
+}
+
+// why a connection ended, logged as the reason field of "Disconnected."
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum DisconnectReason {
+    ClientClosed, // end of stream, or reset by the client
+    Shutdown,
+    Timeout,      // limit_connection_duration, limit_idle_duration or a timed out wait
+    OpponentGone, // the other side of the match vanished mid-handshake or mid-send
+    Error,        // logged before as a warning or error
+}
+
+impl std::fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            DisconnectReason::ClientClosed => "client_closed",
+            DisconnectReason::Shutdown => "shutdown",
+            DisconnectReason::Timeout => "timeout",
+            DisconnectReason::OpponentGone => "opponent_gone",
+            DisconnectReason::Error => "error",
+        })
+    }
+}
+
+// owned point-in-time view of ServerState, see ServerState::snapshot
+#[derive(Debug, Clone)]
+pub struct ServerSnapshot {
+    pub connections: usize,
+    pub waiting_matches: usize, // public and private
+    pub public_matches: Vec<MatchSettingsWithoutVisibility>, // in creation order
+    pub server_history_matches: Vec<(MatchId, ServerHistoryMatch)>, // oldest first
+    pub variant_counts: HashMap<Variant, u64>,
+}
+
+impl ServerSnapshot {
+    pub fn playing_matches(&self) -> usize {
+        self.server_history_matches
+            .iter()
+            .filter(|(_, m)| m.state == HistoryMatchState::InProgress)
+            .count()
+    }
+}
+
+// what to do when the opponent doesn't drain internal messages within lag_timeout
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum LagPolicy {
+    Skip,
+    Disconnect,
+}
+
+// what to do with actions beyond limit_actions_per_second
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ActionLimitPolicy {
+    Drop,
+    Disconnect,
+}
+
+// what to do with a frame that can't be decoded as a client message
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum InvalidMessagePolicy {
+    Disconnect,
+    Ignore,
+}
+
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub artificial_delay: Option<Duration>, // before every reply, needs allow_debug_features
+    pub allow_reset_puzzle: bool,
+    pub bot_fill_timeout: Option<Duration>, // pair a waiting match with the echo bot, see bot.rs
+    pub flush_interval: Option<Duration>, // puts are coalesced for this long, None flushes each time
+    pub handshake_timeout: Duration,
+    pub history_size: usize, // matches kept in server_history_matches, the match list shows 13
+    pub lag_timeout: Duration, // waiting for room in the opponent's queue
+    pub ip_filter: IpFilter, // checked against the peer address before any framing
+    pub limit_actions_per_second: u32, // per player and match, in bursts of up to a second, 0 means no limit
+    pub limit_connection_duration: Option<Duration>,
+    pub limit_connections: usize,              // 0 means no limit
+    pub limit_idle_duration: Option<Duration>, // without client messages
+    pub limit_message_length: usize,
+    pub limit_pending_frames: usize, // unflushed outgoing frames, 0 means no limit
+    pub match_idle_timeout: Option<Duration>, // without relayed actions while playing
+    pub max_client_version: Option<(i64, i64)>, // newest C2SGreet version accepted
+    pub min_client_version: Option<(i64, i64)>, // oldest C2SGreet version accepted
+    pub on_action_limit: ActionLimitPolicy,
+    pub on_invalid_message: InvalidMessagePolicy,
+    pub on_lag: LagPolicy,
+    pub proxy_protocol: bool,
+    pub rng_seed: Option<u64>, // fixed seed for reproducible passcodes and colors
+    pub server_name: String,   // sent in S2CServerInfo
+    pub strict_actions: bool,  // see check_board_colors
+    pub strict_messages: bool, // see MessageIO::strict
+    pub timeline_length: usize, // relayed actions kept per history match, 0 disables the timeline
+    pub trace_moves: bool,     // log every relayed action, see trace_action
+    pub variants: HashSet<Variant>,
+    pub variant_weights: HashMap<Variant, u32>, // of drawing Random, 1 if absent
+    pub waiting_match_ttl: Option<Duration>,    // without a joiner, the match is cancelled
+}
+
+// the defaults of the config file, for embedding the server without one
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            artificial_delay: None,
+            allow_reset_puzzle: false,
+            bot_fill_timeout: None,
+            flush_interval: None,
+            handshake_timeout: Duration::from_secs(5),
+            history_size: 13,
+            lag_timeout: Duration::from_secs(5),
+            ip_filter: IpFilter::default(),
+            limit_actions_per_second: 0,
+            limit_connection_duration: None,
+            limit_connections: 0,
+            limit_idle_duration: None,
+            limit_message_length: MESSAGE_LENGTH_MAX,
+            limit_pending_frames: 64,
+            match_idle_timeout: None,
+            max_client_version: None,
+            min_client_version: None,
+            on_action_limit: ActionLimitPolicy::Disconnect,
+            on_invalid_message: InvalidMessagePolicy::Disconnect,
+            on_lag: LagPolicy::Disconnect,
+            proxy_protocol: false,
+            rng_seed: None,
+            server_name: String::new(),
+            strict_actions: false,
+            strict_messages: false,
+            timeline_length: 0,
+            trace_moves: false,
+            variants: HashSet::from_iter(Variant::all()),
+            variant_weights: HashMap::new(),
+            waiting_match_ttl: None,
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct ServerState {
     pub match_id: AtomicI64,
-    pub matches: Mutex<HashMap<Passcode, broadcast::Receiver<Message>>>,
-    pub public_matches: Mutex<HashMap<Passcode, MatchSettingsWithoutVisibility>>,
+    pub connection_id: AtomicU64, // assigned by the accept loop and connect_virtual
+    pub matches: Mutex<HashMap<Passcode, mpsc::Receiver<Message>>>,
+    pub public_matches: Mutex<IndexMap<Passcode, MatchSettingsWithoutVisibility>>, // in creation order
     pub server_history_matches: Mutex<IndexMap<MatchId, ServerHistoryMatch>>,
     pub instant_start: Instant,
-    pub allow_reset_puzzle: bool,
-    pub variants: HashSet<Variant>,
+    pub oversized_frames: AtomicU64,
+    pub connections: AtomicUsize, // held by ConnectionGuard, see admit_connection
+    pub config: ServerConfig,
     pub variants_without_random: Vec<Variant>,
+    pub variant_weights: WeightedIndex<u32>, // in the order of variants_without_random
+    pub events: Option<broadcast::Sender<ServerEvent>>,
+    pub peer_id_key: RandomState,
+    pub rng: StdMutex<StdRng>, // never held across an await
+    pub notices: broadcast::Sender<String>,
+    pub variant_counts: Mutex<HashMap<Variant, u64>>, // matches created, before random is resolved
+    pub access_log: Option<AccessLog>,
+    pub match_durations: [AtomicU64; 4], // completed matches, bucketed by MATCH_DURATION_BUCKETS
+    pub bytes_read: AtomicU64,           // of closed connections
+    pub bytes_written: AtomicU64,
+    pub messages_read: Mutex<MessageCounts>, // of closed connections
+    pub observer: Option<mpsc::Sender<(MatchId, C2SOrS2CActionBody)>>, // see forward_actions
+    pub observer_dropped: AtomicU64,
 }
 
 impl ServerState {
-    pub fn new(allow_reset_puzzle: bool, variants: HashSet<Variant>) -> Self {
-        let mut variants_without_random = variants.clone();
-        variants_without_random.remove(&Variant::Random);
+    pub fn new(config: ServerConfig) -> Self {
+        let mut variants_without_random = Vec::from_iter(config.variants.clone());
+        variants_without_random.retain(|v| *v != Variant::Random);
+        // set order is not stable across runs
+        variants_without_random.sort_by_key(|v| *v as i64);
+        let variant_weights = WeightedIndex::new(
+            variants_without_random
+                .iter()
+                .map(|v| config.variant_weights.get(v).copied().unwrap_or(1)),
+        )
+        .expect("No allowed variant has a positive weight.");
+        let rng = match config.rng_seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
         ServerState {
             match_id: AtomicI64::new(1),
+            connection_id: AtomicU64::new(1),
             matches: Mutex::new(HashMap::new()),
-            public_matches: Mutex::new(HashMap::new()),
+            public_matches: Mutex::new(IndexMap::new()),
             server_history_matches: Mutex::new(IndexMap::new()),
             instant_start: Instant::now(),
-            allow_reset_puzzle,
-            variants,
-            variants_without_random: Vec::from_iter(variants_without_random)
+            oversized_frames: AtomicU64::new(0),
+            connections: AtomicUsize::new(0),
+            config,
+            variants_without_random,
+            variant_weights,
+            events: None,
+            peer_id_key: RandomState::new(),
+            rng: StdMutex::new(rng),
+            notices: broadcast::channel(8).0,
+            variant_counts: Mutex::new(HashMap::new()),
+            access_log: None,
+            match_durations: Default::default(),
+            bytes_read: AtomicU64::new(0),
+            bytes_written: AtomicU64::new(0),
+            messages_read: Mutex::new([0; MESSAGE_TYPE_SLOTS]),
+            observer: None,
+            observer_dropped: AtomicU64::new(0),
+        }
+    }
+
+    // None once limit_connections are held, the connection is counted until the guard drops
+    pub fn admit_connection(self: &Arc<Self>) -> Option<ConnectionGuard> {
+        let limit = self.config.limit_connections;
+        self.connections
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+                (limit == 0 || n < limit).then_some(n + 1)
+            })
+            .ok()?;
+        Some(ConnectionGuard(self.clone()))
+    }
+
+    // push a notice to every connection, returns the number of connections reached
+    pub fn notice(&self, text: String) -> Result<usize, ProtocolError> {
+        if text.len() > NOTICE_LENGTH_MAX {
+            return err_limit!("Notice is longer than {} bytes.", NOTICE_LENGTH_MAX);
+        }
+        Ok(self.notices.send(text).unwrap_or(0))
+    }
+
+    /* consistent view for read-only features, the maps are locked together in the order
+    matches, public_matches, server_history_matches, variant_counts
+    handlers never hold more than one of them, so this can't deadlock
+    */
+    pub async fn snapshot(&self) -> ServerSnapshot {
+        let matches = self.matches.lock().await;
+        let public_matches = self.public_matches.lock().await;
+        let server_history_matches = self.server_history_matches.lock().await;
+        let variant_counts = self.variant_counts.lock().await;
+        ServerSnapshot {
+            connections: self.connections.load(Ordering::Relaxed),
+            waiting_matches: matches.len(),
+            public_matches: public_matches.values().copied().collect(),
+            server_history_matches: server_history_matches
+                .iter()
+                .map(|(id, m)| (*id, m.clone()))
+                .collect(),
+            variant_counts: variant_counts.clone(),
+        }
+    }
+
+    // lists a waiting match under a passcode not used by any other, chosen under the same lock
+    // the preferred passcode if it's in range and no match is waiting with it, else a random one
+    pub async fn insert_waiting_match(
+        &self,
+        preferred: Passcode,
+        rx_peer: mpsc::Receiver<Message>,
+    ) -> Passcode {
+        let mut matches = self.matches.lock().await;
+        let passcode = if (0..=PASSCODE_MAX).contains(&preferred)
+            && !matches.contains_key(&preferred)
+        {
+            preferred
+        } else {
+            generate_random_passcode_internal_excluding(&matches, &mut *self.rng.lock().unwrap())
+        };
+        matches.insert(passcode, rx_peer);
+        passcode
+    }
+
+    // opaque identifier of a client, keyed per process so the address can't be recovered
+    pub fn peer_id(&self, addr: &SocketAddr) -> PeerId {
+        self.peer_id_key.hash_one(addr.ip())
+    }
+
+    // done by the joiner once the match starts, the only place the history grows
+    pub async fn insert_history_match(&self, match_id: MatchId, m: MatchSettings) {
+        let mut server_history_matches = self.server_history_matches.lock().await;
+        server_history_matches.insert(match_id, ServerHistoryMatch::new(m));
+        let excess = server_history_matches
+            .len()
+            .saturating_sub(self.config.history_size);
+        server_history_matches.drain(..excess);
+    }
+
+    // enable lifecycle events, no overhead when disabled
+    pub fn with_events(mut self, capacity: usize) -> Self {
+        self.events = Some(broadcast::channel(capacity).0);
+        self
+    }
+
+    // one line per closed connection, see AccessLog
+    pub fn with_access_log(mut self, access_log: AccessLog) -> Self {
+        self.access_log = Some(access_log);
+        self
+    }
+
+    // every relayed action is queued for the collector, see forward_actions
+    pub fn with_observer(mut self, tx: mpsc::Sender<(MatchId, C2SOrS2CActionBody)>) -> Self {
+        self.observer = Some(tx);
+        self
+    }
+
+    /* in-process connection for embedding, served exactly like a socket over an in-memory pipe
+    returns the client side, it greets, creates or joins and plays with the usual messages
+    dropping it disconnects, the connection also ends when running changes
+    */
+    pub fn connect_virtual(self: &Arc<Self>, running: watch::Receiver<bool>) -> MessageIO {
+        let (client, server) = tokio::io::duplex(self.config.limit_message_length * 4);
+        let io = MessageIO::new(server, self.config.limit_message_length);
+        let ss = self.clone();
+        let id = ss.connection_id.fetch_add(1, Ordering::Relaxed);
+        // counted but never refused, the embedder limits its own connections
+        ss.connections.fetch_add(1, Ordering::Relaxed);
+        let guard = ConnectionGuard(ss.clone());
+        tokio::spawn(
+            async move {
+                serve_connection(ss, io, VIRTUAL_ADDR, running, id).await;
+                drop(guard);
+            }
+            .instrument(info_span!("connection", id)),
+        );
+        MessageIO::new(client, self.config.limit_message_length).client_side()
+    }
+
+    pub fn record_match_duration(&self, duration: Duration) {
+        let bucket = MATCH_DURATION_BUCKETS
+            .iter()
+            .position(|bound| duration.as_secs() < *bound)
+            .unwrap_or(MATCH_DURATION_BUCKETS.len());
+        self.match_durations[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn subscribe_events(&self) -> Option<broadcast::Receiver<ServerEvent>> {
+        self.events.as_ref().map(|tx| tx.subscribe())
+    }
+
+    pub fn emit(&self, event: ServerEvent) {
+        if let Some(tx) = &self.events {
+            // no subscriber is not an error
+            let _ = tx.send(event);
         }
     }
 }
 
-/* state machine of one connection:
-Idle -> PublicWaiting -> Playing -> Idle
-Idle -> PrivateWaiting -> Playing -> Idle
-*/
+// upper bounds in seconds of all but the last match_durations bucket
+pub const MATCH_DURATION_BUCKETS: [u64; 3] = [60, 300, 900];
+
+// one connection in ServerState::connections, also released if the handler is aborted
+pub struct ConnectionGuard(Arc<ServerState>);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// how long after a successful cancel another one from idle still succeeds
+const CANCEL_REPEAT_WINDOW: Duration = Duration::from_secs(5);
+
+// peer address of connect_virtual connections in logs, no socket has it
+pub const VIRTUAL_ADDR: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0));
+
+// the client isn't read while this many internal messages wait for room in the opponent's queue
+const PEER_OUTBOX_LENGTH: usize = 64;
+
+// with flush_interval, this many pending frames are flushed without waiting for the timer
+const FLUSH_BATCH_FRAMES: usize = 32;
+
+// state machine of one connection, see STATE_TRANSITIONS
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub enum ConnectionStateEnum {
     Idle,
@@ -53,34 +421,148 @@ pub enum ConnectionStateEnum {
     Playing,
 }
 
+// every transition the handlers may perform, they go through ConnectionState::transition
+const STATE_TRANSITIONS: &[(ConnectionStateEnum, &str, ConnectionStateEnum)] = &[
+    (
+        ConnectionStateEnum::Idle,
+        "create",
+        ConnectionStateEnum::Waiting,
+    ),
+    (
+        ConnectionStateEnum::Idle,
+        "join",
+        ConnectionStateEnum::Playing,
+    ),
+    (
+        ConnectionStateEnum::Waiting,
+        "cancel",
+        ConnectionStateEnum::Idle,
+    ),
+    (
+        ConnectionStateEnum::Waiting,
+        "expire",
+        ConnectionStateEnum::Idle,
+    ),
+    (
+        ConnectionStateEnum::Waiting,
+        "opponent joined",
+        ConnectionStateEnum::Playing,
+    ),
+    (
+        ConnectionStateEnum::Playing,
+        "forfeit",
+        ConnectionStateEnum::Idle,
+    ),
+    (
+        ConnectionStateEnum::Playing,
+        "opponent forfeited",
+        ConnectionStateEnum::Idle,
+    ),
+    (
+        ConnectionStateEnum::Playing,
+        "abandon",
+        ConnectionStateEnum::Idle,
+    ),
+];
+
+// the state after the event, None if it isn't declared from this state
+pub fn next_state(state: ConnectionStateEnum, event: &str) -> Option<ConnectionStateEnum> {
+    STATE_TRANSITIONS
+        .iter()
+        .find(|(from, e, _)| *from == state && *e == event)
+        .map(|(_, _, to)| *to)
+}
+
+pub fn state_transitions() -> Vec<(ConnectionStateEnum, &'static str, ConnectionStateEnum)> {
+    STATE_TRANSITIONS.to_vec()
+}
+
+// render the state machine as graphviz dot
+pub fn state_transitions_dot() -> String {
+    let mut dot = String::from("digraph connection {\n");
+    for (from, event, to) in STATE_TRANSITIONS {
+        dot += &format!("    {:?} -> {:?} [label={:?}];\n", from, to, event);
+    }
+    dot += "}\n";
+    dot
+}
+
 #[derive(Debug)]
 pub struct ConnectionState {
     pub state: ConnectionStateEnum,
     pub ss: Arc<ServerState>,
+    pub id: ConnectionId, // also the id field of the connection span
     pub addr: SocketAddr,
     pub io: MessageIO,
-    pub tx: Option<broadcast::Sender<Message>>,
-    pub rx: Option<broadcast::Receiver<Message>>,
-    pub m: Option<MatchSettings>, // match is reserved as a key word
+    pub tx: Option<mpsc::Sender<Message>>, // bounded, what doesn't fit waits in outbox
+    pub rx: Option<mpsc::Receiver<Message>>,
+    pub m: Option<MatchSettings>,  // match is reserved as a key word
+    pub outbox: VecDeque<Message>, // for the opponent, see peer_send
+    pub outbox_lag_at: Option<Instant>, // while the outbox makes no progress, see lag_timeout
+    pub extensions: i64,
+    pub version: Option<(i64, i64)>, // from C2SGreet, required before anything else
     pub running: watch::Receiver<bool>,
+    pub notices: broadcast::Receiver<String>,
+    pub instant_connect: Instant,
+    pub record: AccessRecord,
+    pub last_cancel: Option<Instant>, // repeated cancels within CANCEL_REPEAT_WINDOW succeed
+    pub bot_fill_at: Option<Instant>, // while waiting, see bot_fill_timeout
+    pub match_idle_at: Option<Instant>, // while playing, see match_idle_timeout
+    pub waiting_expire_at: Option<Instant>, // while waiting, see waiting_match_ttl
+    pub flush_at: Option<Instant>,    // while frames are pending, see flush_interval
+    pub action_tokens: (f64, Instant), // while playing, see take_action_token
+    pub actions_throttled: bool,      // the last action was over the limit, logged once per burst
 }
 
 impl ConnectionState {
+    // called before the match fields are cleared, so a failure still tears down the old state
+    fn transition(&mut self, event: &'static str) -> Result<(), ProtocolError> {
+        let next = next_state(self.state, event);
+        debug_assert!(
+            next.is_some(),
+            "Undeclared transition {:?} from {:?}.",
+            event,
+            self.state
+        );
+        self.state = next.ok_or(ProtocolError::UndeclaredTransition {
+            state: self.state,
+            event,
+        })?;
+        Ok(())
+    }
+
     pub fn new(
         ss: Arc<ServerState>,
         addr: SocketAddr,
-        stream: TcpStream,
+        io: MessageIO,
         running: watch::Receiver<bool>,
+        id: ConnectionId,
     ) -> Self {
+        let notices = ss.notices.subscribe();
         ConnectionState {
             state: ConnectionStateEnum::Idle,
             ss,
+            id,
             addr,
-            io: MessageIO::new(stream),
+            io,
             tx: None,
             rx: None,
             m: None,
+            outbox: VecDeque::new(),
+            outbox_lag_at: None,
+            extensions: 0,
+            version: None,
             running,
+            notices,
+            instant_connect: Instant::now(),
+            record: AccessRecord::default(),
+            last_cancel: None,
+            bot_fill_at: None,
+            match_idle_at: None,
+            waiting_expire_at: None,
+            flush_at: None,
+            action_tokens: (0.0, Instant::now()),
+            actions_throttled: false,
         }
     }
 }
@@ -89,91 +571,593 @@ fn trace_error(cs: &mut ConnectionState, e: Box<dyn Error>) {
     error!("[{}:{}] {}", cs.addr.ip(), cs.addr.port(), e);
 }
 
+// the client address, None if the connection is rejected
+pub async fn read_client_addr(
+    ss: &ServerState,
+    stream: &mut TcpStream,
+    addr: SocketAddr,
+) -> Option<SocketAddr> {
+    if !ss.config.proxy_protocol {
+        return Some(addr);
+    }
+    // the header comes before any message, replace the balancer address with the client's
+    match timeout(ss.config.handshake_timeout, read_proxy_header(stream)).await {
+        Ok(Ok(Some(client))) => Some(client),
+        Ok(Ok(None)) => Some(addr),
+        Ok(Err(e)) => {
+            warn!("[{}:{}] Rejected, {}", addr.ip(), addr.port(), e);
+            None
+        }
+        Err(_) => {
+            warn!(
+                "[{}:{}] Rejected, no PROXY protocol header.",
+                addr.ip(),
+                addr.port()
+            );
+            None
+        }
+    }
+}
+
+// on accepted streams, moves are small and latency matters more than packet count
+pub fn set_stream_options(stream: &TcpStream, addr: SocketAddr, tcp_nodelay: bool) {
+    if tcp_nodelay {
+        if let Err(e) = stream.set_nodelay(true) {
+            warn!(
+                "[{}:{}] Failed to set TCP_NODELAY: {}",
+                addr.ip(),
+                addr.port(),
+                e
+            );
+        }
+    }
+}
+
+// waits for the handlers once running changed, returns how many were aborted after limit
+// a handler stuck in an await that doesn't watch running would never end
+pub async fn join_or_abort(
+    handles: &mut VecDeque<JoinHandle<()>>,
+    limit: Option<Duration>,
+) -> usize {
+    let joined = join_all(handles.iter_mut());
+    let Some(limit) = limit else {
+        joined.await;
+        return 0;
+    };
+    if timeout(limit, joined).await.is_ok() {
+        return 0;
+    }
+    let stuck: Vec<_> = handles.iter().filter(|h| !h.is_finished()).collect();
+    for handle in &stuck {
+        handle.abort();
+    }
+    stuck.len()
+}
+
 pub async fn handle_connection(
     ss: Arc<ServerState>,
-    stream: TcpStream,
+    mut stream: TcpStream,
+    addr: SocketAddr,
+    running: watch::Receiver<bool>,
+    id: ConnectionId,
+) {
+    if let Some(addr) = read_client_addr(&ss, &mut stream, addr).await {
+        let io = MessageIO::new(stream, ss.config.limit_message_length);
+        serve_connection(ss, io, addr, running, id).await;
+    }
+}
+
+// shared by every transport once frames can be exchanged
+pub async fn serve_connection(
+    ss: Arc<ServerState>,
+    io: MessageIO,
     addr: SocketAddr,
     running: watch::Receiver<bool>,
+    id: ConnectionId,
 ) {
+    let io = if ss.config.strict_messages {
+        io.strict()
+    } else {
+        io
+    };
+    let io = match ss.config.artificial_delay {
+        Some(delay) => io.delayed(delay),
+        None => io,
+    };
+    let io = match ss.config.flush_interval {
+        Some(_) => io.coalesced(),
+        None => io,
+    };
     info!("[{}:{}] Connected.", addr.ip(), addr.port());
-    let mut cs = ConnectionState::new(ss, addr, stream, running);
-    match handle_connection_main_loop(&mut cs).await {
-        Ok(()) => {}
-        Err(e) => match e.downcast::<std::io::Error>() {
-            Ok(e) if e.kind() == ErrorKind::ConnectionAborted => {}
-            Ok(e) => trace_error(&mut cs, e),
+    ss.emit(ServerEvent::Connected {
+        connection_id: id,
+        addr,
+    });
+    let mut cs = ConnectionState::new(ss, addr, io, running, id);
+    let reason = match handle_connection_main_loop(&mut cs).await {
+        Ok(reason) => reason,
+        Err(e) => match e.downcast::<ProtocolError>() {
+            Ok(e) => match *e {
+                ProtocolError::Disconnected => DisconnectReason::OpponentGone,
+                ProtocolError::FrameTooLong => {
+                    cs.ss.oversized_frames.fetch_add(1, Ordering::Relaxed);
+                    warn!("Frame exceeded limit_message_length from {}.", cs.addr.ip());
+                    DisconnectReason::Error
+                }
+                ProtocolError::Stalled(e) => {
+                    warn!("[{}:{}] {}", cs.addr.ip(), cs.addr.port(), e);
+                    DisconnectReason::Error
+                }
+                e => {
+                    let reason = match &e {
+                        ProtocolError::Timeout(_) => DisconnectReason::Timeout,
+                        ProtocolError::Io(e) if is_closed_by_client(e) => {
+                            DisconnectReason::ClientClosed
+                        }
+                        _ => DisconnectReason::Error,
+                    };
+                    trace_error(&mut cs, e.into());
+                    reason
+                }
+            },
             Err(e) => match e.downcast::<broadcast::error::RecvError>() {
-                Ok(e) if e.as_ref() == &broadcast::error::RecvError::Closed => {}
-                Ok(e) => trace_error(&mut cs, e),
-                Err(e) => trace_error(&mut cs, e),
+                // the notice sender lives as long as the server
+                Ok(e) if e.as_ref() == &broadcast::error::RecvError::Closed => {
+                    DisconnectReason::Shutdown
+                }
+                Ok(e) => {
+                    trace_error(&mut cs, e);
+                    DisconnectReason::Error
+                }
+                Err(e) => {
+                    trace_error(&mut cs, e);
+                    DisconnectReason::Error
+                }
             },
         },
     };
 
     // clean resources, remove match from public match list, etc.
-    match cs.state {
-        ConnectionStateEnum::Idle => {}
-        ConnectionStateEnum::Waiting => {
-            let m = cs.m.unwrap();
+    // m is None only if create_match failed right after its transition, before listing anything
+    match (cs.state, cs.m) {
+        (ConnectionStateEnum::Idle, _) | (_, None) => {}
+        (ConnectionStateEnum::Waiting, Some(m)) => {
             if m.visibility == Visibility::Public {
-                cs.ss.public_matches.lock().await.remove(&m.passcode);
+                cs.ss.public_matches.lock().await.shift_remove(&m.passcode);
             }
             cs.ss.matches.lock().await.remove(&m.passcode);
         }
-        ConnectionStateEnum::Playing => {
-            let match_id = cs.m.unwrap().match_id;
-            let mut server_history_matches = cs.ss.server_history_matches.lock().await;
-            match server_history_matches.get_mut(&match_id) {
-                Some(v) => {
-                    v.state = HistoryMatchState::Completed;
-                }
-                None => {}
-            }
+        (ConnectionStateEnum::Playing, Some(_)) => {
+            // notify the opponent now instead of when the channel is dropped, a forfeit already
+            // sent has left the playing state so this never forfeits twice
+            // the opponent may be gone too, it sees the closed channel as a disconnect anyway
+            let _ = peer_send(&mut cs, Message::InternalForfeit(ForfeitReason::Disconnect));
+            hand_over_outbox(&mut cs);
+            complete_match(&mut cs, ForfeitReason::Disconnect, true).await;
         }
     }
-    let _ = cs.io.close().await;
-    info!("[{}:{}] Disconnected.", cs.addr.ip(), cs.addr.port());
+    cs.record.bytes_read = cs.io.bytes_read();
+    cs.record.bytes_written = cs.io.bytes_written();
+    cs.ss
+        .bytes_read
+        .fetch_add(cs.record.bytes_read, Ordering::Relaxed);
+    cs.ss
+        .bytes_written
+        .fetch_add(cs.record.bytes_written, Ordering::Relaxed);
+    let messages_read = *cs.io.messages_read();
+    for (total, n) in cs
+        .ss
+        .messages_read
+        .lock()
+        .await
+        .iter_mut()
+        .zip(messages_read)
+    {
+        *total += n;
+    }
+    // a stalled client would never take the pending frames
+    let _ = timeout(cs.ss.config.lag_timeout, cs.io.close()).await;
+    if let Some(access_log) = &cs.ss.access_log {
+        let duration = Instant::now().duration_since(cs.instant_connect);
+        access_log
+            .write(cs.addr.ip(), cs.id, duration, &cs.record)
+            .await;
+    }
+    info!(
+        reason = %reason,
+        messages = %MessageType::describe_counts(&messages_read),
+        "[{}:{}] Disconnected.",
+        cs.addr.ip(),
+        cs.addr.port()
+    );
+    cs.ss.emit(ServerEvent::Disconnected {
+        connection_id: cs.id,
+        addr: cs.addr,
+        reason,
+    });
 }
 
-async fn handle_connection_main_loop(cs: &mut ConnectionState) -> Result<(), Box<dyn Error>> {
-    loop {
+// resets count as a close, the official client often quits without a FIN
+fn is_closed_by_client(e: &std::io::Error) -> bool {
+    matches!(
+        e.kind(),
+        ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::BrokenPipe
+            | ErrorKind::UnexpectedEof
+    )
+}
+
+async fn handle_connection_main_loop(
+    cs: &mut ConnectionState,
+) -> Result<DisconnectReason, Box<dyn Error>> {
+    // hard cap on the connection duration and rolling inactivity timer share one sleep
+    let hard_deadline = cs
+        .ss
+        .config
+        .limit_connection_duration
+        .map(|d| Instant::now() + d);
+    let limited = hard_deadline.is_some() || cs.ss.config.limit_idle_duration.is_some();
+    let expiry = sleep_until(next_expiry(cs, hard_deadline));
+    tokio::pin!(expiry);
+    let reason = loop {
         match cs.state {
             ConnectionStateEnum::Idle => select! {
-                result = cs.io.get() => handle_connection_idle(cs, result?).await?,
-                result = cs.running.changed() => break result?
+                result = get_message(&mut cs.io, cs.ss.config.on_invalid_message, cs.addr) => match result? {
+                    Some(msg) => {
+                        expiry.as_mut().reset(next_expiry(cs, hard_deadline));
+                        handle_connection_idle(cs, msg).await?
+                    }
+                    None => break DisconnectReason::ClientClosed,
+                },
+                result = cs.notices.recv() => handle_notice(cs, result).await?,
+                _ = sleep_until(cs.flush_at.unwrap_or_else(Instant::now)), if cs.flush_at.is_some() => {},
+                _ = &mut expiry, if limited => {
+                    log_expiry(cs, hard_deadline);
+                    break DisconnectReason::Timeout
+                },
+                result = cs.running.changed() => {
+                    result?;
+                    break DisconnectReason::Shutdown
+                }
             },
             ConnectionStateEnum::Waiting => select! {
-                result = cs.io.get() => handle_connection_waiting(cs, result?).await?,
-                result = cs.rx.as_mut().unwrap().recv() => handle_connection_waiting(cs, result?).await?,
-                result = cs.running.changed() => break result?
+                result = get_message(&mut cs.io, cs.ss.config.on_invalid_message, cs.addr) => match result? {
+                    Some(msg) => {
+                        expiry.as_mut().reset(next_expiry(cs, hard_deadline));
+                        handle_connection_waiting(cs, msg).await?
+                    }
+                    None => break DisconnectReason::ClientClosed,
+                },
+                result = cs.notices.recv() => handle_notice(cs, result).await?,
+                _ = sleep_until(cs.flush_at.unwrap_or_else(Instant::now)), if cs.flush_at.is_some() => {},
+                _ = &mut expiry, if limited => {
+                    log_expiry(cs, hard_deadline);
+                    break DisconnectReason::Timeout
+                },
+                _ = sleep_until(cs.bot_fill_at.unwrap_or_else(Instant::now)), if cs.bot_fill_at.is_some() => {
+                    fill_with_bot(cs).await
+                },
+                _ = sleep_until(cs.waiting_expire_at.unwrap_or_else(Instant::now)), if cs.waiting_expire_at.is_some() => {
+                    expire_waiting_match(cs).await?
+                },
+                result = cs.rx.as_mut().unwrap().recv() => {
+                    match result {
+                        Some(msg) => handle_connection_waiting(cs, msg).await?,
+                        None => Err(ProtocolError::Disconnected)?,
+                    };
+                },
+                result = cs.running.changed() => {
+                    result?;
+                    break DisconnectReason::Shutdown
+                }
             },
             ConnectionStateEnum::Playing => select! {
-                result = cs.io.get() => handle_connection_playing(cs, result?).await?,
+                // backpressure on a client that sends faster than the opponent drains
+                result = get_message(&mut cs.io, cs.ss.config.on_invalid_message, cs.addr), if cs.outbox.len() < PEER_OUTBOX_LENGTH => match result? {
+                    Some(msg) => {
+                        expiry.as_mut().reset(next_expiry(cs, hard_deadline));
+                        handle_connection_playing(cs, msg).await?
+                    }
+                    None => break DisconnectReason::ClientClosed,
+                },
+                result = cs.notices.recv() => handle_notice(cs, result).await?,
+                _ = sleep_until(cs.flush_at.unwrap_or_else(Instant::now)), if cs.flush_at.is_some() => {},
+                _ = &mut expiry, if limited => {
+                    log_expiry(cs, hard_deadline);
+                    break DisconnectReason::Timeout
+                },
+                _ = sleep_until(cs.match_idle_at.unwrap_or_else(Instant::now)), if cs.match_idle_at.is_some() => {
+                    abandon_match(cs).await?
+                },
+                sent = send_outbox(cs.tx.as_ref().unwrap(), &mut cs.outbox), if !cs.outbox.is_empty() => {
+                    if !sent {
+                        // the opponent is gone, rx tells how
+                        cs.outbox.clear();
+                    }
+                    cs.outbox_lag_at = (!cs.outbox.is_empty())
+                        .then(|| Instant::now() + cs.ss.config.lag_timeout);
+                },
+                _ = sleep_until(cs.outbox_lag_at.unwrap_or_else(Instant::now)), if cs.outbox_lag_at.is_some() => {
+                    skip_outbox(cs)?
+                },
                 result = cs.rx.as_mut().unwrap().recv() => {
                     match result {
-                        Ok(msg) => handle_connection_playing(cs, msg).await?,
-                        Err(e) => {
-                            if e == broadcast::error::RecvError::Closed {
-                                // handle unexpected opponent disconnect
-                                handle_connection_playing(cs, Message::InternalForfeit).await?;
-                            } else {
-                                Err(e)?
-                            }
+                        Some(msg) => handle_connection_playing(cs, msg).await?,
+                        None => {
+                            // handle unexpected opponent disconnect
+                            handle_connection_playing(cs, Message::InternalForfeit(ForfeitReason::Disconnect))
+                                .await?;
                         },
                     };
                 },
-                result = cs.running.changed() => break result?
+                result = cs.running.changed() => {
+                    result?;
+                    break DisconnectReason::Shutdown
+                }
             },
         }
-        cs.io.flush().await?;
+        if let Some(interval) = cs.ss.config.flush_interval {
+            if cs.io.pending() == 0 {
+                continue;
+            }
+            let flush_at = *cs.flush_at.get_or_insert_with(|| Instant::now() + interval);
+            if Instant::now() < flush_at && cs.io.pending() < FLUSH_BATCH_FRAMES {
+                continue;
+            }
+            cs.flush_at = None;
+        }
+        match cs.ss.config.limit_pending_frames {
+            0 => cs.io.flush().await?,
+            // don't wait for a client that stopped reading, the match is forfeited on cleanup
+            limit => {
+                let pending = cs.io.try_flush()?;
+                if pending > limit {
+                    Err(ProtocolError::Stalled(format!(
+                        "Stalled with {} pending frames, limit_pending_frames reached.",
+                        pending
+                    )))?;
+                }
+                // coalesced frames aren't flushed in the background, retry on the timer
+                if let (Some(interval), 1..) = (cs.ss.config.flush_interval, pending) {
+                    cs.flush_at = Some(Instant::now() + interval);
+                }
+            }
+        }
+    };
+    Ok(reason)
+}
+
+fn trace_action(match_id: MatchId, body: &C2SOrS2CActionBody) {
+    info!("{}", action_line(match_id, body));
+}
+
+/* one tab-separated line per relayed action, for analysis scripts, see trace_moves and observer
+"action", match id, then the body fields in wire order as integers
+seconds_passed correlates the line with what both clients received
+*/
+pub fn action_line(match_id: MatchId, body: &C2SOrS2CActionBody) -> String {
+    format!(
+        "action\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+        match_id,
+        body.action_type as i64,
+        body.color as i64,
+        body.seconds_passed,
+        body.src.l,
+        body.src.t,
+        body.src.board_color as i64,
+        body.src.y,
+        body.src.x,
+        body.dst.l,
+        body.dst.t,
+        body.dst.board_color as i64,
+        body.dst.y,
+        body.dst.x
+    )
+}
+
+// the match may already have left the history, then there is nothing to record into
+async fn record_action(ss: &ServerState, match_id: MatchId, body: C2SOrS2CActionBody) {
+    if let Some(m) = ss.server_history_matches.lock().await.get_mut(&match_id) {
+        if m.timeline.len() >= ss.config.timeline_length {
+            m.timeline.pop_front();
+        }
+        m.timeline.push_back(body);
+    }
+}
+
+// every relayed action passes here exactly once, whoever plays it
+pub async fn stamp_action(ss: &ServerState, match_id: MatchId, body: &mut C2SOrS2CActionBody) {
+    body.seconds_passed = Instant::now().duration_since(ss.instant_start).as_secs();
+    if ss.config.trace_moves {
+        trace_action(match_id, body);
+    }
+    if ss.config.timeline_length > 0 {
+        record_action(ss, match_id, *body).await;
+    }
+    // a slow collector must not hold up the relay
+    if let Some(tx) = &ss.observer {
+        if tx.try_send((match_id, *body)).is_err()
+            && ss.observer_dropped.fetch_add(1, Ordering::Relaxed) == 0
+        {
+            warn!("The observer queue is full, actions are dropped, see the admin stats.");
+        }
+    }
+}
+
+// the frame is consumed even if it can't be decoded, so ignoring it keeps the stream in sync
+async fn get_message(
+    io: &mut MessageIO,
+    policy: InvalidMessagePolicy,
+    addr: SocketAddr,
+) -> Result<Option<Message>, ProtocolError> {
+    loop {
+        match io.get().await {
+            Err(ProtocolError::InvalidData(e)) if policy == InvalidMessagePolicy::Ignore => {
+                warn!("[{}:{}] Ignored, {}", addr.ip(), addr.port(), e);
+            }
+            result => return result,
+        }
+    }
+}
+
+fn has_peer_info(cs: &ConnectionState) -> bool {
+    cs.extensions & EXTENSION_PEER_INFO != 0
+}
+
+// far future if neither limit is set, the timer is not polled then
+fn next_expiry(cs: &ConnectionState, hard_deadline: Option<Instant>) -> Instant {
+    let idle_deadline = cs.ss.config.limit_idle_duration.map(|d| Instant::now() + d);
+    [hard_deadline, idle_deadline]
+        .into_iter()
+        .flatten()
+        .min()
+        .unwrap_or_else(|| Instant::now() + Duration::from_secs(86400))
+}
+
+fn log_expiry(cs: &ConnectionState, hard_deadline: Option<Instant>) {
+    let reason = match hard_deadline {
+        Some(deadline) if Instant::now() >= deadline => "limit_connection_duration",
+        _ => "limit_idle_duration",
+    };
+    info!(
+        "[{}:{}] Closing, {} reached.",
+        cs.addr.ip(),
+        cs.addr.port(),
+        reason
+    );
+}
+
+// forwarded in any state, only to clients that asked for them
+async fn handle_notice(
+    cs: &mut ConnectionState,
+    result: Result<String, broadcast::error::RecvError>,
+) -> Result<(), Box<dyn Error>> {
+    match result {
+        Ok(text) if cs.extensions & EXTENSION_SERVER_NOTICE != 0 => {
+            cs.io.put(Message::S2CServerNotice(text)).await?
+        }
+        Ok(_) => {}
+        // notices are best effort
+        Err(broadcast::error::RecvError::Lagged(_)) => {}
+        Err(e) => Err(e)?,
     }
     Ok(())
 }
 
+/* never waits for the opponent, who may be waiting for room in our queue at the same time
+what doesn't fit waits in the outbox, the main loop sends it in order while still draining rx
+the handshake messages go into fresh queues and always fit
+*/
 fn peer_send(cs: &mut ConnectionState, msg: Message) -> Result<(), Box<dyn Error>> {
     trace!("Internal {:?}", msg);
-    cs.tx.as_mut().unwrap().send(msg)?;
-    Ok(())
+    if !cs.outbox.is_empty() {
+        cs.outbox.push_back(msg);
+        return Ok(());
+    }
+    match cs.tx.as_ref().unwrap().try_send(msg) {
+        Ok(()) => Ok(()),
+        Err(TrySendError::Full(msg)) => {
+            cs.outbox.push_back(msg);
+            cs.outbox_lag_at = Some(Instant::now() + cs.ss.config.lag_timeout);
+            Ok(())
+        }
+        // fails only if the opponent is gone
+        Err(TrySendError::Closed(_)) => Err(ProtocolError::Disconnected)?,
+    }
+}
+
+// the oldest message once there is room, false if the opponent is gone
+async fn send_outbox(tx: &mpsc::Sender<Message>, outbox: &mut VecDeque<Message>) -> bool {
+    match tx.reserve().await {
+        Ok(permit) => {
+            permit.send(outbox.pop_front().unwrap());
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+// the outbox made no progress within lag_timeout, never drops a message unless on_lag is skip
+fn skip_outbox(cs: &mut ConnectionState) -> Result<(), Box<dyn Error>> {
+    match cs.ss.config.on_lag {
+        LagPolicy::Skip => {
+            warn!(
+                "[{}:{}] Skipped {} internal messages, the opponent is lagging.",
+                cs.addr.ip(),
+                cs.addr.port(),
+                cs.outbox.len()
+            );
+            cs.outbox.clear();
+            cs.outbox_lag_at = None;
+            Ok(())
+        }
+        LagPolicy::Disconnect => Ok(err_timeout!("The opponent is lagging.")?),
+    }
+}
+
+// leaving the match, the opponent still gets what is queued for it without holding us up
+fn hand_over_outbox(cs: &mut ConnectionState) {
+    let outbox = std::mem::take(&mut cs.outbox);
+    cs.outbox_lag_at = None;
+    let (Some(tx), false) = (cs.tx.clone(), outbox.is_empty()) else {
+        return;
+    };
+    let lag_timeout = cs.ss.config.lag_timeout;
+    tokio::spawn(
+        async move {
+            for msg in outbox {
+                // the opponent left as well or stopped draining
+                if !matches!(timeout(lag_timeout, tx.send(msg)).await, Ok(Ok(()))) {
+                    break;
+                }
+            }
+        }
+        .in_current_span(),
+    );
+}
+
+// receive from the host while joining, the match is already removed from the match list
+async fn handshake_recv(
+    cs: &ConnectionState,
+    rx: &mut mpsc::Receiver<Message>,
+) -> Result<Message, Box<dyn Error>> {
+    match timeout(cs.ss.config.handshake_timeout, rx.recv()).await {
+        Ok(Some(msg)) => Ok(msg),
+        Ok(None) => Err(ProtocolError::Disconnected)?,
+        Err(_) => Ok(err_timeout!("Timed out waiting for the host.")?),
+    }
+}
+
+// mark match as completed in server history, emit event only once per match
+// whoever forfeits loses, both sides may call this so only the first one counts
+async fn complete_match(cs: &mut ConnectionState, reason: ForfeitReason, self_lost: bool) {
+    let match_id = cs.m.unwrap().match_id;
+    let outcome = match Color::try_from(cs.m.unwrap().color) {
+        _ if reason == ForfeitReason::Abandoned => MatchOutcome::Aborted,
+        Ok(color) if self_lost => MatchOutcome::lost_by(color),
+        Ok(color) => MatchOutcome::lost_by(color.reversed()),
+        Err(_) => MatchOutcome::Aborted,
+    };
+    cs.record.outcome = Some(outcome);
+    let completed = {
+        let mut server_history_matches = cs.ss.server_history_matches.lock().await;
+        match server_history_matches.get_mut(&match_id) {
+            Some(v) if v.state == HistoryMatchState::Completed => false,
+            Some(v) => {
+                v.state = HistoryMatchState::Completed;
+                v.end_reason = Some(reason);
+                v.outcome = Some(outcome);
+                v.time_end = Some(Instant::now());
+                cs.ss.record_match_duration(v.duration());
+                true
+            }
+            None => true,
+        }
+    };
+    if completed {
+        cs.ss.emit(ServerEvent::MatchEnded { match_id, reason });
+    }
 }
 
 async fn handle_match_list_request(
@@ -181,26 +1165,8 @@ async fn handle_match_list_request(
     m: Option<MatchSettings>,
 ) -> Result<(), Box<dyn Error>> {
     let mut public_matches_count = 0;
-    let server_history_matches_count = cs.ss.server_history_matches.lock().await.len();
-    let mut body = S2CMatchListNonhostBody {
-        public_matches: [MatchSettingsWithoutVisibility {
-            color: OptionalColorWithRandom::None,
-            clock: OptionalClock::None,
-            variant: Variant::Standard,
-            passcode: 0,
-            match_id: -1,
-        }; 13],
-        public_matches_count,
-        server_history_matches: [S2CMatchListServerHistoryMatch {
-            state: HistoryMatchState::Completed,
-            clock: OptionalClock::None,
-            variant: Variant::Standard,
-            visibility: Visibility::Public,
-            seconds_passed: 0,
-        }; 13],
-        server_history_matches_count,
-    };
-    for (_i, (_passcode, public_match)) in cs.ss.public_matches.lock().await.iter().enumerate() {
+    let mut body = S2CMatchListNonhostBody::empty(cs.extensions & EXTENSION_MATCH_OUTCOME != 0);
+    for public_match in cs.ss.public_matches.lock().await.values() {
         match m {
             // skip host match
             Some(m) if m.match_id == public_match.match_id => {}
@@ -208,161 +1174,322 @@ async fn handle_match_list_request(
                 if public_matches_count >= 13 {
                     break;
                 }
-                body.public_matches[public_matches_count] = public_match.clone();
+                body.public_matches[public_matches_count] = *public_match;
                 public_matches_count += 1;
             }
         }
     }
     body.public_matches_count = public_matches_count;
-    for (i, (_match_id, server_history_match)) in
-        cs.ss.server_history_matches.lock().await.iter().enumerate()
+    // newest first, counted under the same lock so a concurrent insert can't skew the indices
+    for (i, server_history_match) in cs
+        .ss
+        .server_history_matches
+        .lock()
+        .await
+        .values()
+        .rev()
+        .take(13) // the wire format has room for no more
+        .enumerate()
     {
-        body.server_history_matches[server_history_matches_count - i - 1] =
-            server_history_match.clone().into();
+        body.server_history_matches[i] = server_history_match.into();
+        body.server_history_matches_count = i + 1;
     }
     match m {
         Some(m) => {
             cs.io
-                .put(Message::S2CMatchList(S2CMatchListBody::Host(
-                    S2CMatchListHostBody {
-                        color: m.color,
-                        clock: m.clock,
-                        variant: m.variant,
-                        passcode: m.passcode,
-                        body,
-                    },
-                )))
+                .put(Message::host_match_list(S2CMatchListHostBody {
+                    color: m.color,
+                    clock: m.clock,
+                    variant: m.variant,
+                    passcode: m.passcode,
+                    body,
+                }))
                 .await?;
         }
         None => {
-            cs.io
-                .put(Message::S2CMatchList(S2CMatchListBody::Nonhost(body)))
-                .await?;
+            cs.io.put(Message::nonhost_match_list(body)).await?;
         }
     }
     Ok(())
 }
 
+// m is already validated, the passcode and match_id are assigned here
+async fn create_match(
+    cs: &mut ConnectionState,
+    mut m: MatchSettings,
+) -> Result<(), Box<dyn Error>> {
+    // nothing is listed yet, so a failure leaves nothing behind
+    cs.transition("create")?;
+    let (tx, rx_peer) = mpsc::channel(8);
+    let (tx_peer, rx) = mpsc::channel(8);
+    cs.tx = Some(tx);
+    cs.rx = Some(rx);
+    // store tx_peer in rx_peer
+    peer_send(cs, Message::InternalInitialize(tx_peer))?;
+    // add to match list
+    m.passcode = cs.ss.insert_waiting_match(m.passcode, rx_peer).await;
+    m.match_id = cs.ss.match_id.fetch_add(1, Ordering::Relaxed);
+    if m.visibility == Visibility::Public {
+        // add to public match list
+        cs.ss
+            .public_matches
+            .lock()
+            .await
+            .insert(m.passcode, m.into());
+        // TODO: limit number of public matches
+    }
+    *cs.ss
+        .variant_counts
+        .lock()
+        .await
+        .entry(m.variant)
+        .or_insert(0) += 1;
+    cs.ss.emit(ServerEvent::MatchCreated {
+        match_id: m.match_id,
+        variant: m.variant,
+        visibility: m.visibility,
+    });
+    cs.m = Some(m);
+    cs.record.matches_created += 1;
+    cs.bot_fill_at = cs.ss.config.bot_fill_timeout.map(|d| Instant::now() + d);
+    cs.waiting_expire_at = cs.ss.config.waiting_match_ttl.map(|d| Instant::now() + d);
+    cs.last_cancel = None;
+    cs.record.variant = Some(m.variant);
+    cs.record.outcome = None;
+    cs.io.put(Message::create_success(m)).await?;
+    Ok(())
+}
+
+// false if there is no match waiting with the passcode
+async fn join_match(cs: &mut ConnectionState, passcode: Passcode) -> Result<bool, Box<dyn Error>> {
+    // remove from match list
+    let Some(mut rx) = cs.ss.matches.lock().await.remove(&passcode) else {
+        return Ok(false);
+    };
+    // remove from public match list
+    let visibility = if cs
+        .ss
+        .public_matches
+        .lock()
+        .await
+        .shift_remove(&passcode)
+        .is_some()
+    {
+        Visibility::Public
+    } else {
+        Visibility::Private
+    };
+    // receive sender from peer
+    let tx = match handshake_recv(cs, &mut rx).await? {
+        Message::InternalInitialize(tx) => tx,
+        msg => err_invalid_data!("Unexpected {} during join.", msg.kind_name())?,
+    };
+    cs.tx = Some(tx);
+    // notify peer
+    peer_send(cs, Message::InternalJoin(cs.ss.peer_id(&cs.addr)))?;
+    // receive match information from peer
+    let body = match handshake_recv(cs, &mut rx).await? {
+        Message::InternalMatchStart(body) => body,
+        msg => err_invalid_data!("Unexpected {} during join.", msg.kind_name())?,
+    };
+    cs.rx = Some(rx);
+    // before the history entry, the host sees the dropped channels if it fails
+    cs.transition("join")?;
+    cs.ss
+        .insert_history_match(body.match_id, MatchSettings::new(body.m, visibility))
+        .await;
+    cs.m = Some(MatchSettings::new(body.m, visibility));
+    cs.record.matches_joined += 1;
+    cs.last_cancel = None;
+    cs.record.variant = Some(body.m.variant);
+    cs.record.outcome = None;
+    reset_match_idle(cs);
+    reset_action_tokens(cs);
+    cs.io
+        .put(Message::create_success(MatchSettings::new(
+            body.m, visibility,
+        )))
+        .await?;
+    cs.io
+        .put(Message::S2CMatchStart(S2CMatchStartBody {
+            m: body.m,
+            match_id: body.match_id,
+            seconds_passed: body.seconds_passed,
+            opponent: body.opponent.filter(|_| has_peer_info(cs)),
+        }))
+        .await?;
+    Ok(true)
+}
+
 async fn handle_connection_idle(
     cs: &mut ConnectionState,
     msg: Message,
 ) -> Result<(), Box<dyn Error>> {
+    if cs.version.is_none() && !matches!(msg, Message::C2SGreet(_)) {
+        err_invalid_data!("{} before C2SGreet.", msg.message_type())?;
+    }
     match msg {
-        Message::C2SGreet(_body) => {
+        // a connection greets once, every greet would be answered with one or two replies
+        Message::C2SGreet(_) if cs.version.is_some() => {
+            err_invalid_data!("C2SGreet on a greeted connection.")?;
+        }
+        Message::C2SGreet(body) => {
+            let version = (body.version1, body.version2);
+            // compared as (major, minor), any version is accepted unless bounded
+            if cs
+                .ss
+                .config
+                .min_client_version
+                .is_some_and(|min| version < min)
+            {
+                err_invalid_data!(
+                    "Client version {}.{} is below min_client_version.",
+                    version.0,
+                    version.1
+                )?;
+            }
+            if cs
+                .ss
+                .config
+                .max_client_version
+                .is_some_and(|max| version > max)
+            {
+                err_invalid_data!(
+                    "Client version {}.{} is above max_client_version.",
+                    version.0,
+                    version.1
+                )?;
+            }
+            cs.version = Some(version);
+            cs.extensions = body.extensions;
             cs.io.put(Message::S2CGreet).await?;
+            // the legacy greet has no room for a string
+            if cs.extensions & EXTENSION_SERVER_INFO != 0 {
+                let name = cs.ss.config.server_name.clone();
+                cs.io.put(Message::S2CServerInfo(name)).await?;
+            }
         }
-        Message::C2SMatchCreateOrJoin(C2SMatchCreateOrJoinBody::Create(mut m)) => {
-            // create match
-            if !cs.ss.variants.contains(&m.variant) {
-                err_invalid_data!("Variant {:?} is not allowed.", m.variant)?;
-            }
-            m.passcode = generate_random_passcode_internal_with_exceptions(&cs.ss.matches).await;
-            let (tx, rx_peer) = broadcast::channel(8);
-            let (tx_peer, rx) = broadcast::channel(8);
-            cs.tx = Some(tx);
-            cs.rx = Some(rx);
-            // store tx_peer in rx_peer
-            peer_send(cs, Message::InternalInitialize(tx_peer))?;
-            // add to match list
-            cs.ss.matches.lock().await.insert(m.passcode, rx_peer);
-            m.match_id = cs.ss.match_id.fetch_add(1, Ordering::Relaxed);
-            if m.visibility == Visibility::Public {
-                // add to public match list
-                cs.ss
+        // unset, unlike NoClock which is untimed play, a failure instead of a disconnect
+        Message::C2SMatchCreateOrJoin(C2SMatchCreateOrJoinBody::Create(m))
+        | Message::C2SQuickMatch(m)
+            if m.clock == OptionalClock::None =>
+        {
+            cs.io.put(Message::create_failed()).await?;
+        }
+        Message::C2SMatchCreateOrJoin(C2SMatchCreateOrJoinBody::Create(m)) => {
+            let m = MatchSettingsBuilder::from(m)
+                .allowed_variants(&cs.ss.config.variants)
+                .allow_random_color()
+                .build()?;
+            create_match(cs, m).await?;
+        }
+        Message::C2SVariantListRequest => {
+            // a set, sorted so the reply is stable
+            let mut variants = Vec::from_iter(cs.ss.config.variants.iter().copied());
+            variants.sort_by_key(|v| *v as i64);
+            cs.io.put(Message::S2CVariantList(variants)).await?;
+        }
+        Message::C2SMatchCreateOrJoin(C2SMatchCreateOrJoinBody::Join(passcode)) => {
+            if !join_match(cs, passcode).await? {
+                cs.io.put(Message::create_failed()).await?;
+            }
+        }
+        Message::C2SQuickMatch(m) => {
+            let m = MatchSettingsBuilder::from(m)
+                .allowed_variants(&cs.ss.config.variants)
+                .allow_random_color()
+                .build()?;
+            // oldest compatible public match first, host one if there is none
+            let mut claimed = Vec::new();
+            loop {
+                let passcode = cs
+                    .ss
                     .public_matches
                     .lock()
                     .await
-                    .insert(m.passcode, m.clone().into());
-                // TODO: limit number of public matches
-            }
-            cs.m = Some(m);
-            cs.state = ConnectionStateEnum::Waiting;
-            cs.io
-                .put(Message::S2CMatchCreateOrJoinResult(
-                    S2CMatchCreateOrJoinResultBody::Success(m.clone()),
-                ))
-                .await?;
-        }
-        Message::C2SMatchCreateOrJoin(C2SMatchCreateOrJoinBody::Join(passcode)) => {
-            // join match
-            // remove from match list
-            let rx = cs.ss.matches.lock().await.remove(&passcode);
-            match rx {
-                Some(mut rx) => {
-                    // match found
-                    // remove from public match list
-                    let visibility = if cs
-                        .ss
-                        .public_matches
-                        .lock()
-                        .await
-                        .remove(&passcode)
-                        .is_some()
-                    {
-                        Visibility::Public
-                    } else {
-                        Visibility::Private
-                    };
-                    // receive sender from peer
-                    let tx = match rx.recv().await? {
-                        Message::InternalInitialize(tx) => tx,
-                        _ => unreachable!(),
-                    };
-                    cs.tx = Some(tx);
-                    // notify peer
-                    peer_send(cs, Message::InternalJoin)?;
-                    // receive match information from peer
-                    let body = match rx.recv().await? {
-                        Message::InternalMatchStart(body) => body,
-                        _ => unreachable!(),
-                    };
-                    cs.rx = Some(rx);
-                    let mut server_history_matches = cs.ss.server_history_matches.lock().await;
-                    server_history_matches.insert(
-                        body.match_id,
-                        ServerHistoryMatch::new(MatchSettings::new(body.m, visibility)),
-                    );
-                    if server_history_matches.len() > 13 {
-                        server_history_matches.shift_remove_index(0);
+                    .values()
+                    .find(|waiting| !claimed.contains(&waiting.passcode) && m.accepts(waiting))
+                    .map(|waiting| waiting.passcode);
+                match passcode {
+                    Some(passcode) if join_match(cs, passcode).await? => break,
+                    // another joiner got there first and is about to unlist it
+                    Some(passcode) => claimed.push(passcode),
+                    None => {
+                        create_match(cs, m).await?;
+                        break;
                     }
-                    cs.m = Some(MatchSettings::new(body.m, visibility));
-                    cs.state = ConnectionStateEnum::Playing;
-                    cs.io
-                        .put(Message::S2CMatchCreateOrJoinResult(
-                            S2CMatchCreateOrJoinResultBody::Success(MatchSettings::new(
-                                body.m, visibility,
-                            )),
-                        ))
-                        .await?;
-                    cs.io
-                        .put(Message::S2CMatchStart(S2CMatchStartBody {
-                            m: body.m,
-                            match_id: body.match_id,
-                            seconds_passed: body.seconds_passed,
-                        }))
-                        .await?;
-                }
-                None => {
-                    // match not found
-                    cs.io
-                        .put(Message::S2CMatchCreateOrJoinResult(
-                            S2CMatchCreateOrJoinResultBody::Failed,
-                        ))
-                        .await?;
                 }
             }
         }
         Message::C2SMatchCancel => {
-            cs.io
-                .put(Message::S2CMatchCancelResult(
-                    S2CMatchCancelResultBody::Failed,
-                ))
-                .await?;
+            // a resend on a flaky connection, the first one already succeeded
+            let result = match cs.last_cancel {
+                Some(instant) if instant.elapsed() < CANCEL_REPEAT_WINDOW => {
+                    Message::cancel_success()
+                }
+                _ => Message::cancel_failed(),
+            };
+            cs.io.put(result).await?;
         }
         Message::C2SForfeit => {}
         Message::C2SMatchListRequest => handle_match_list_request(cs, None).await?,
-        other => err_invalid_data!("Invalid message {:?} at state Idle.", other)?,
+        other => Err(ProtocolError::UnexpectedMessage {
+            state: ConnectionStateEnum::Idle,
+            kind: other.kind_name(),
+        })?,
+    }
+    Ok(())
+}
+
+// claim the match like a joiner would, a real joiner that got there first wins
+async fn fill_with_bot(cs: &mut ConnectionState) {
+    cs.bot_fill_at = None;
+    let m = cs.m.unwrap();
+    let Some(rx_peer) = cs.ss.matches.lock().await.remove(&m.passcode) else {
+        return;
+    };
+    if m.visibility == Visibility::Public {
+        cs.ss.public_matches.lock().await.shift_remove(&m.passcode);
     }
+    // the bot can't open a game, give it black unless the host insisted
+    if m.color == OptionalColorWithRandom::Random {
+        cs.m = Some(MatchSettings {
+            color: OptionalColorWithRandom::White,
+            ..m
+        });
+    }
+    info!(
+        "[{}:{}] No opponent within bot_fill_timeout, the echo bot joins.",
+        cs.addr.ip(),
+        cs.addr.port()
+    );
+    // its logs belong to the host's connection
+    tokio::spawn(run_echo_bot(cs.ss.clone(), rx_peer, m.visibility).in_current_span());
+}
+
+// like C2SMatchCancel, the client is told as if it had asked
+async fn expire_waiting_match(cs: &mut ConnectionState) -> Result<(), Box<dyn Error>> {
+    cs.waiting_expire_at = None;
+    let m = cs.m.unwrap();
+    // a joiner or the echo bot claimed it just now, its InternalJoin is on the way
+    if cs.ss.matches.lock().await.remove(&m.passcode).is_none() {
+        return Ok(());
+    }
+    if m.visibility == Visibility::Public {
+        cs.ss.public_matches.lock().await.shift_remove(&m.passcode);
+    }
+    info!(
+        "[{}:{}] No opponent within waiting_match_ttl, match cancelled.",
+        cs.addr.ip(),
+        cs.addr.port()
+    );
+    cs.transition("expire")?;
+    cs.tx = None;
+    cs.rx = None;
+    cs.m = None;
+    // a cancel sent meanwhile gets the same answer
+    cs.last_cancel = Some(Instant::now());
+    cs.io.put(Message::cancel_success()).await?;
     Ok(())
 }
 
@@ -372,35 +1499,107 @@ async fn handle_connection_waiting(
 ) -> Result<(), Box<dyn Error>> {
     match msg {
         Message::C2SMatchCancel => {
-            let passcode = cs.m.unwrap().passcode;
-            cs.ss.public_matches.lock().await.remove(&passcode);
-            cs.ss.matches.lock().await.remove(&passcode);
+            let m = cs.m.unwrap();
+            // a joiner claims the match by removing it first, its InternalJoin is on the way
+            if cs.ss.matches.lock().await.remove(&m.passcode).is_none() {
+                cs.io.put(Message::cancel_failed()).await?;
+                return Ok(());
+            }
+            if m.visibility == Visibility::Public {
+                cs.ss.public_matches.lock().await.shift_remove(&m.passcode);
+            }
+            cs.transition("cancel")?;
             cs.tx = None;
             cs.rx = None;
             cs.m = None;
-            cs.state = ConnectionStateEnum::Idle;
-            cs.io
-                .put(Message::S2CMatchCancelResult(
-                    S2CMatchCancelResultBody::Success,
-                ))
-                .await?;
+            cs.last_cancel = Some(Instant::now());
+            cs.io.put(Message::cancel_success()).await?;
         }
         Message::C2SMatchListRequest => handle_match_list_request(cs, cs.m).await?,
-        Message::InternalJoin => {
+        Message::InternalJoin(opponent) => {
             let mut body = S2CMatchStartBody {
                 m: cs.m.unwrap().into(),
                 match_id: cs.m.unwrap().match_id,
                 seconds_passed: Instant::now().duration_since(cs.ss.instant_start).as_secs(),
+                opponent: Some(opponent).filter(|_| has_peer_info(cs)),
             };
-            cs.state = ConnectionStateEnum::Playing;
-            body.m.variant = body.m.variant.determined(&cs.ss.variants_without_random);
-            body.m.color = body.m.color.determined();
+            cs.transition("opponent joined")?;
+            reset_match_idle(cs);
+            reset_action_tokens(cs);
+            {
+                let mut rng = cs.ss.rng.lock().unwrap();
+                body.m.variant = body.m.variant.determined(
+                    &cs.ss.variants_without_random,
+                    &cs.ss.variant_weights,
+                    &mut *rng,
+                );
+                body.m.color = body.m.color.determined(&mut *rng);
+            }
+            // keep what was actually played, random is resolved now
+            cs.m = cs.m.map(|m| MatchSettings {
+                color: body.m.color,
+                variant: body.m.variant,
+                ..m
+            });
+            cs.record.variant = Some(body.m.variant);
             cs.io.put(Message::S2CMatchStart(body)).await?;
             body.m.color = body.m.color.reversed();
+            // the joiner decides whether to forward it
+            body.opponent = Some(cs.ss.peer_id(&cs.addr));
             peer_send(cs, Message::InternalMatchStart(body))?;
+            cs.ss.emit(ServerEvent::MatchStarted {
+                match_id: body.match_id,
+            });
         }
-        other => err_invalid_data!("Invalid message {:?} at state Waiting.", other)?,
+        other => Err(ProtocolError::UnexpectedMessage {
+            state: ConnectionStateEnum::Waiting,
+            kind: other.kind_name(),
+        })?,
+    }
+    Ok(())
+}
+
+// both sides see every relayed action, so their timers expire together
+fn reset_match_idle(cs: &mut ConnectionState) {
+    cs.match_idle_at = cs.ss.config.match_idle_timeout.map(|d| Instant::now() + d);
+}
+
+// the bucket starts full with every match
+fn reset_action_tokens(cs: &mut ConnectionState) {
+    cs.action_tokens = (cs.ss.config.limit_actions_per_second as f64, Instant::now());
+    cs.actions_throttled = false;
+}
+
+// token bucket refilled at limit_actions_per_second, holding at most a second's worth
+fn take_action_token(cs: &mut ConnectionState) -> bool {
+    let limit = cs.ss.config.limit_actions_per_second as f64;
+    if limit == 0.0 {
+        return true;
     }
+    let (tokens, since) = cs.action_tokens;
+    let now = Instant::now();
+    let tokens = (tokens + now.duration_since(since).as_secs_f64() * limit).min(limit);
+    let taken = tokens >= 1.0;
+    cs.action_tokens = (if taken { tokens - 1.0 } else { tokens }, now);
+    taken
+}
+
+// either side may get here first, the other one is told like after a forfeit
+async fn abandon_match(cs: &mut ConnectionState) -> Result<(), Box<dyn Error>> {
+    info!(
+        "[{}:{}] Match abandoned, match_idle_timeout reached.",
+        cs.addr.ip(),
+        cs.addr.port()
+    );
+    // the opponent may have abandoned it already and dropped its receiver
+    let _ = peer_send(cs, Message::InternalForfeit(ForfeitReason::Abandoned));
+    hand_over_outbox(cs);
+    complete_match(cs, ForfeitReason::Abandoned, true).await;
+    cs.transition("abandon")?;
+    cs.tx = None;
+    cs.rx = None;
+    cs.m = None;
+    cs.io.put(Message::S2COpponentLeft).await?;
     Ok(())
 }
 
@@ -410,48 +1609,1285 @@ async fn handle_connection_playing(
 ) -> Result<(), Box<dyn Error>> {
     match msg {
         Message::C2SForfeit => {
-            peer_send(cs, Message::InternalForfeit)?;
-            let match_id = cs.m.unwrap().match_id;
-            let mut server_history_matches = cs.ss.server_history_matches.lock().await;
-            match server_history_matches.get_mut(&match_id) {
-                Some(v) => {
-                    v.state = HistoryMatchState::Completed;
-                }
-                None => {}
+            // both forfeited at once, the opponent ended the match first and dropped its receiver
+            match peer_send(cs, Message::InternalForfeit(ForfeitReason::Resign)) {
+                Err(e) if matches!(e.downcast_ref(), Some(ProtocolError::Disconnected)) => {}
+                result => result?,
             }
+            hand_over_outbox(cs);
+            complete_match(cs, ForfeitReason::Resign, true).await;
+            cs.transition("forfeit")?;
             cs.tx = None;
             cs.rx = None;
             cs.m = None;
-            cs.state = ConnectionStateEnum::Idle;
         }
         Message::C2SOrS2CAction(mut body) => {
-            if (!cs.ss.allow_reset_puzzle) && body.action_type == ActionType::ResetPuzzle {
-                err_invalid_data!("Action type of {:?} is not allowed.", body.action_type)?;
+            // every relayed action costs the opponent too, a flood is stopped here
+            if !take_action_token(cs) {
+                if cs.ss.config.on_action_limit == ActionLimitPolicy::Disconnect {
+                    err_invalid_data!("Actions faster than limit_actions_per_second.")?;
+                }
+                if !cs.actions_throttled {
+                    warn!(
+                        "[{}:{}] Dropping actions, limit_actions_per_second reached.",
+                        cs.addr.ip(),
+                        cs.addr.port()
+                    );
+                    cs.actions_throttled = true;
+                }
+                return Ok(());
+            }
+            cs.actions_throttled = false;
+            match body.action_type {
+                ActionType::ResetPuzzle if !cs.ss.config.allow_reset_puzzle => {
+                    err_invalid_data!("Action type of {:?} is not allowed.", body.action_type)?;
+                }
+                ActionType::Move => {
+                    body.check_coordinates(cs.m.unwrap().variant)?;
+                    if cs.ss.config.strict_actions {
+                        body.check_board_colors()?;
+                    }
+                }
+                ActionType::DisplayCheckReason => body.check_coordinates(cs.m.unwrap().variant)?,
+                // relayed as is, the others carry no squares
+                _ => {}
             }
-            body.seconds_passed = Instant::now().duration_since(cs.ss.instant_start).as_secs();
+            stamp_action(&cs.ss, cs.m.unwrap().match_id, &mut body).await;
+            reset_match_idle(cs);
             peer_send(cs, Message::InternalAction(body))?;
             cs.io.put(Message::C2SOrS2CAction(body)).await?;
         }
         Message::C2SMatchListRequest => handle_match_list_request(cs, None).await?,
-        Message::InternalForfeit => {
-            let match_id = cs.m.unwrap().match_id;
-            let mut server_history_matches = cs.ss.server_history_matches.lock().await;
-            match server_history_matches.get_mut(&match_id) {
-                Some(v) => {
-                    v.state = HistoryMatchState::Completed;
-                }
-                None => {}
-            }
+        // the cancel raced a join and lost, the match has already started
+        Message::C2SMatchCancel => cs.io.put(Message::cancel_failed()).await?,
+        Message::InternalForfeit(reason) => {
+            complete_match(cs, reason, false).await;
+            // nobody is left to read it
+            cs.outbox.clear();
+            cs.outbox_lag_at = None;
+            cs.transition("opponent forfeited")?;
             cs.tx = None;
             cs.rx = None;
             cs.m = None;
-            cs.state = ConnectionStateEnum::Idle;
             cs.io.put(Message::S2COpponentLeft).await?;
         }
         Message::InternalAction(body) => {
+            reset_match_idle(cs);
             cs.io.put(Message::C2SOrS2CAction(body)).await?;
         }
-        other => err_invalid_data!("Invalid message {:?} at state Playing.", other)?,
+        other => Err(ProtocolError::UnexpectedMessage {
+            state: ConnectionStateEnum::Playing,
+            kind: other.kind_name(),
+        })?,
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BytesMut;
+    use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+
+    const TEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+    fn server(config: ServerConfig) -> (Arc<ServerState>, watch::Sender<bool>) {
+        let ss = Arc::new(ServerState::new(ServerConfig {
+            rng_seed: Some(0),
+            ..config
+        }));
+        (ss, watch::channel(true).0)
+    }
+
+    fn server_with_events(config: ServerConfig) -> (Arc<ServerState>, watch::Sender<bool>) {
+        let ss = ServerState::new(ServerConfig {
+            rng_seed: Some(0),
+            ..config
+        });
+        (Arc::new(ss.with_events(64)), watch::channel(true).0)
+    }
+
+    fn greet() -> Message {
+        Message::C2SGreet(C2SGreetBody {
+            version1: GREET_VERSION.0,
+            version2: GREET_VERSION.1,
+            extensions: 0,
+        })
+    }
+
+    fn create(color: OptionalColorWithRandom) -> Message {
+        Message::C2SMatchCreateOrJoin(C2SMatchCreateOrJoinBody::Create(MatchSettings {
+            color,
+            clock: OptionalClock::NoClock,
+            variant: Variant::Standard,
+            visibility: Visibility::Private,
+            passcode: -1,
+            match_id: -1,
+        }))
+    }
+
+    fn create_public(color: OptionalColorWithRandom) -> Message {
+        let Message::C2SMatchCreateOrJoin(C2SMatchCreateOrJoinBody::Create(m)) = create(color)
+        else {
+            unreachable!()
+        };
+        Message::C2SMatchCreateOrJoin(C2SMatchCreateOrJoinBody::Create(MatchSettings {
+            visibility: Visibility::Public,
+            ..m
+        }))
+    }
+
+    // (0T1)e2 -> (0T1)e4 of the given side
+    fn action(color: Color) -> Message {
+        let src = Coordinate {
+            l: 0,
+            t: 1,
+            board_color: color,
+            y: 1,
+            x: 4,
+        };
+        Message::C2SOrS2CAction(C2SOrS2CActionBody {
+            action_type: ActionType::Move,
+            color,
+            seconds_passed: 0,
+            src,
+            dst: Coordinate { y: 3, ..src },
+        })
+    }
+
+    // served like connect_virtual, raw so that both directions can be driven at once
+    fn connect_raw(ss: &Arc<ServerState>, running: &watch::Sender<bool>) -> DuplexStream {
+        let (client, server) = tokio::io::duplex(1 << 20);
+        let io = MessageIO::new(server, ss.config.limit_message_length);
+        tokio::spawn(serve_connection(
+            ss.clone(),
+            io,
+            VIRTUAL_ADDR,
+            running.subscribe(),
+            0,
+        ));
+        client
+    }
+
+    async fn send_raw<W: AsyncWrite + Unpin>(w: &mut W, msg: Message) {
+        let bytes = msg.pack().unwrap();
+        w.write_all(&(bytes.len() as u64).to_le_bytes())
+            .await
+            .unwrap();
+        w.write_all(&bytes).await.unwrap();
+    }
+
+    // None once the server closed the connection
+    async fn recv_raw<R: AsyncRead + Unpin>(r: &mut R) -> Option<Message> {
+        let mut length = [0; 8];
+        r.read_exact(&mut length).await.ok()?;
+        let mut bytes = BytesMut::zeroed(u64::from_le_bytes(length) as usize);
+        r.read_exact(&mut bytes).await.unwrap();
+        Some(Message::unpack_s2c(bytes).unwrap())
+    }
+
+    // white hosts, black joins, both have seen S2CMatchStart
+    async fn start_raw_match(
+        ss: &Arc<ServerState>,
+        running: &watch::Sender<bool>,
+    ) -> (DuplexStream, DuplexStream) {
+        let mut white = connect_raw(ss, running);
+        send_raw(&mut white, greet()).await;
+        assert!(matches!(
+            recv_raw(&mut white).await,
+            Some(Message::S2CGreet)
+        ));
+        send_raw(&mut white, create(OptionalColorWithRandom::White)).await;
+        let passcode = match recv_raw(&mut white).await {
+            Some(Message::S2CMatchCreateOrJoinResult(S2CMatchCreateOrJoinResultBody::Success(
+                m,
+            ))) => m.passcode,
+            other => panic!("Expected a created match, got {:?}.", other),
+        };
+        let mut black = connect_raw(ss, running);
+        send_raw(&mut black, greet()).await;
+        assert!(matches!(
+            recv_raw(&mut black).await,
+            Some(Message::S2CGreet)
+        ));
+        send_raw(
+            &mut black,
+            Message::C2SMatchCreateOrJoin(C2SMatchCreateOrJoinBody::Join(passcode)),
+        )
+        .await;
+        assert!(matches!(
+            recv_raw(&mut black).await,
+            Some(Message::S2CMatchCreateOrJoinResult(_))
+        ));
+        for io in [&mut white, &mut black] {
+            assert!(matches!(
+                recv_raw(io).await,
+                Some(Message::S2CMatchStart(_))
+            ));
+        }
+        (white, black)
+    }
+
+    // sends actions without pausing while reading its echoes and the opponent's relays
+    async fn flood(stream: DuplexStream, color: Color, actions: usize) -> usize {
+        let (mut r, mut w) = tokio::io::split(stream);
+        let writer = async move {
+            for _ in 0..actions {
+                send_raw(&mut w, action(color)).await;
+            }
+            w
+        };
+        let reader = async move {
+            let mut received = 0;
+            while received < actions * 2 {
+                match recv_raw(&mut r).await {
+                    Some(Message::C2SOrS2CAction(_)) => received += 1,
+                    _ => break,
+                }
+            }
+            received
+        };
+        tokio::join!(writer, reader).1
+    }
+
+    // None once the server closed the connection
+    async fn get(io: &mut MessageIO) -> Option<Message> {
+        timeout(TEST_TIMEOUT, io.get()).await.unwrap().unwrap()
+    }
+
+    async fn put(io: &mut MessageIO, msg: Message) {
+        io.put(msg).await.unwrap();
+        io.flush().await.unwrap();
+    }
+
+    async fn connect_greeted(ss: &Arc<ServerState>, running: &watch::Sender<bool>) -> MessageIO {
+        let mut io = ss.connect_virtual(running.subscribe());
+        put(&mut io, greet()).await;
+        assert!(matches!(get(&mut io).await, Some(Message::S2CGreet)));
+        io
+    }
+
+    async fn create_virtual(io: &mut MessageIO, msg: Message) -> Passcode {
+        put(io, msg).await;
+        match get(io).await {
+            Some(Message::S2CMatchCreateOrJoinResult(S2CMatchCreateOrJoinResultBody::Success(
+                m,
+            ))) => m.passcode,
+            other => panic!("Expected a created match, got {:?}.", other),
+        }
+    }
+
+    // white hosts, black joins, both have seen S2CMatchStart
+    async fn start_virtual_match(
+        ss: &Arc<ServerState>,
+        running: &watch::Sender<bool>,
+    ) -> (MessageIO, MessageIO) {
+        let mut white = connect_greeted(ss, running).await;
+        let passcode = create_virtual(&mut white, create(OptionalColorWithRandom::White)).await;
+        let mut black = connect_greeted(ss, running).await;
+        let join = C2SMatchCreateOrJoinBody::Join(passcode);
+        put(&mut black, Message::C2SMatchCreateOrJoin(join)).await;
+        assert!(matches!(
+            get(&mut black).await,
+            Some(Message::S2CMatchCreateOrJoinResult(
+                S2CMatchCreateOrJoinResultBody::Success(_)
+            ))
+        ));
+        for io in [&mut white, &mut black] {
+            assert!(matches!(get(io).await, Some(Message::S2CMatchStart(_))));
+        }
+        (white, black)
+    }
+
+    // the reason of the next connection to end, skipping other events
+    async fn next_disconnect(events: &mut broadcast::Receiver<ServerEvent>) -> DisconnectReason {
+        loop {
+            let event = timeout(TEST_TIMEOUT, events.recv()).await.unwrap().unwrap();
+            if let ServerEvent::Disconnected { reason, .. } = event {
+                return reason;
+            }
+        }
+    }
+
+    fn connection(ss: &Arc<ServerState>, running: &watch::Sender<bool>) -> ConnectionState {
+        let (io, _) = MessageIO::new_duplex();
+        ConnectionState::new(ss.clone(), VIRTUAL_ADDR, io, running.subscribe(), 0)
+    }
+
+    #[tokio::test]
+    async fn declared_transitions_are_taken() {
+        let (ss, running) = server(ServerConfig::default());
+        let mut cs = connection(&ss, &running);
+        for (from, event, to) in STATE_TRANSITIONS {
+            cs.state = *from;
+            cs.transition(event).unwrap();
+            assert_eq!(cs.state, *to, "{:?} from {:?}", event, from);
+        }
+    }
+
+    #[tokio::test]
+    async fn undeclared_transitions_are_rejected() {
+        use ConnectionStateEnum::*;
+        for (state, event) in [(Idle, "cancel"), (Waiting, "join"), (Playing, "create")] {
+            assert_eq!(next_state(state, event), None);
+        }
+        assert_eq!(next_state(Idle, "no such event"), None);
+        // debug builds assert instead, the bug should not go unnoticed in tests
+        #[cfg(not(debug_assertions))]
+        {
+            let (ss, running) = server(ServerConfig::default());
+            let mut cs = connection(&ss, &running);
+            assert!(matches!(
+                cs.transition("cancel"),
+                Err(ProtocolError::UndeclaredTransition { state: Idle, .. })
+            ));
+            assert_eq!(cs.state, Idle);
+        }
+    }
+
+    // two creates reopening the same passcode at once must not share it
+    #[tokio::test]
+    async fn preferred_passcode_is_given_once() {
+        let (ss, _running) = server(ServerConfig::default());
+        let (a, b) = tokio::join!(
+            ss.insert_waiting_match(123456, mpsc::channel(1).1),
+            ss.insert_waiting_match(123456, mpsc::channel(1).1)
+        );
+        assert_ne!(a, b);
+        assert!(a == 123456 || b == 123456);
+        assert_eq!(ss.matches.lock().await.len(), 2);
+        // out of range asks for none in particular
+        let c = ss
+            .insert_waiting_match(PASSCODE_MAX + 1, mpsc::channel(1).1)
+            .await;
+        assert!((0..=PASSCODE_MAX).contains(&c));
+        assert_eq!(ss.matches.lock().await.len(), 3);
+    }
+
+    // both sides flooding at once used to wait on each other's full queue until lag_timeout
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn flood_from_both_sides_is_relayed() {
+        let (ss, running) = server(ServerConfig {
+            lag_timeout: Duration::from_secs(2),
+            ..ServerConfig::default()
+        });
+        let (white, black) = start_raw_match(&ss, &running).await;
+        let actions = 2000;
+        let both = async {
+            tokio::join!(
+                tokio::spawn(flood(white, Color::White, actions)),
+                tokio::spawn(flood(black, Color::Black, actions))
+            )
+        };
+        let (white, black) = timeout(TEST_TIMEOUT, both).await.unwrap();
+        assert_eq!((white.unwrap(), black.unwrap()), (actions * 2, actions * 2));
+    }
+
+    #[tokio::test]
+    async fn virtual_connections_start_a_match() {
+        let (ss, running) = server(ServerConfig::default());
+        let mut white = connect_greeted(&ss, &running).await;
+        put(&mut white, create(OptionalColorWithRandom::White)).await;
+        let passcode = match get(&mut white).await {
+            Some(Message::S2CMatchCreateOrJoinResult(S2CMatchCreateOrJoinResultBody::Success(
+                m,
+            ))) => m.passcode,
+            other => panic!("Expected a created match, got {:?}.", other),
+        };
+        let mut black = connect_greeted(&ss, &running).await;
+        let join = C2SMatchCreateOrJoinBody::Join(passcode);
+        put(&mut black, Message::C2SMatchCreateOrJoin(join)).await;
+        assert!(matches!(
+            get(&mut black).await,
+            Some(Message::S2CMatchCreateOrJoinResult(
+                S2CMatchCreateOrJoinResultBody::Success(_)
+            ))
+        ));
+        let mut started = Vec::new();
+        for io in [&mut white, &mut black] {
+            match get(io).await {
+                Some(Message::S2CMatchStart(body)) => started.push(body),
+                other => panic!("Expected S2CMatchStart, got {:?}.", other),
+            }
+        }
+        assert_eq!(started[0].match_id, started[1].match_id);
+        assert_eq!(started[0].m.color, OptionalColorWithRandom::White);
+        assert_eq!(started[1].m.color, OptionalColorWithRandom::Black);
+    }
+
+    #[tokio::test]
+    async fn second_greet_disconnects() {
+        let (ss, running) = server(ServerConfig::default());
+        let mut cs = connection(&ss, &running);
+        handle_connection_idle(&mut cs, greet()).await.unwrap();
+        let e = handle_connection_idle(&mut cs, greet()).await.unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<ProtocolError>(),
+            Some(ProtocolError::InvalidData(_))
+        ));
+
+        let mut io = connect_greeted(&ss, &running).await;
+        put(&mut io, greet()).await;
+        match timeout(TEST_TIMEOUT, io.get()).await.unwrap() {
+            Ok(None) | Err(_) => {}
+            Ok(Some(msg)) => panic!("Expected a disconnect, got {:?}.", msg),
+        }
+    }
+
+    // the history as listed to a client, by variant, which is the nth match inserted
+    async fn listed_history(history: usize) -> Vec<Variant> {
+        let (ss, running) = server(ServerConfig {
+            history_size: 20,
+            ..ServerConfig::default()
+        });
+        for i in 0..history {
+            let m = MatchSettingsBuilder::new()
+                .color(OptionalColorWithRandom::White)
+                .clock(OptionalClock::NoClock)
+                .variant(try_i64_to_enum(i as i64 + 1).unwrap())
+                .build()
+                .unwrap();
+            ss.insert_history_match(i as MatchId, m).await;
+        }
+        let (io, peer) = MessageIO::new_duplex();
+        let mut cs = ConnectionState::new(ss.clone(), VIRTUAL_ADDR, io, running.subscribe(), 0);
+        handle_match_list_request(&mut cs, None).await.unwrap();
+        cs.io.flush().await.unwrap();
+        match get(&mut peer.client_side()).await {
+            Some(Message::S2CMatchList(body)) => {
+                let body = body.nonhost_body();
+                body.server_history_matches[..body.server_history_matches_count]
+                    .iter()
+                    .map(|m| m.variant)
+                    .collect()
+            }
+            other => panic!("Expected S2CMatchList, got {:?}.", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn history_is_listed_newest_first() {
+        let expected = |n: i64| -> Vec<Variant> {
+            (1..=n)
+                .rev()
+                .take(13)
+                .map(|v| try_i64_to_enum(v).unwrap())
+                .collect()
+        };
+        assert_eq!(listed_history(0).await, expected(0));
+        assert_eq!(listed_history(1).await, expected(1));
+        assert_eq!(listed_history(13).await, expected(13));
+        // more than the wire format has room for
+        assert_eq!(listed_history(20).await, expected(20));
+    }
+
+    // answered in any state, skipping an S2COpponentLeft still on its way
+    async fn assert_connected(stream: &mut DuplexStream) {
+        send_raw(stream, Message::C2SMatchListRequest).await;
+        loop {
+            match recv_raw(stream).await {
+                Some(Message::S2CMatchList(_)) => break,
+                Some(Message::S2COpponentLeft) => {}
+                other => panic!("Expected S2CMatchList, got {:?}.", other),
+            }
+        }
+    }
+
+    // the host forfeits as soon as it sees S2CMatchStart, while the joiner is still being handed
+    // the match, with both_forfeit the joiner forfeits at once too
+    async fn forfeit_during_handoff(
+        ss: &Arc<ServerState>,
+        running: &watch::Sender<bool>,
+        both_forfeit: bool,
+    ) {
+        let mut white = connect_raw(ss, running);
+        let mut black = connect_raw(ss, running);
+        for stream in [&mut white, &mut black] {
+            send_raw(stream, greet()).await;
+            assert!(matches!(recv_raw(stream).await, Some(Message::S2CGreet)));
+        }
+        send_raw(&mut white, create(OptionalColorWithRandom::White)).await;
+        let passcode = match recv_raw(&mut white).await {
+            Some(Message::S2CMatchCreateOrJoinResult(S2CMatchCreateOrJoinResultBody::Success(
+                m,
+            ))) => m.passcode,
+            other => panic!("Expected a created match, got {:?}.", other),
+        };
+        let join = C2SMatchCreateOrJoinBody::Join(passcode);
+        send_raw(&mut black, Message::C2SMatchCreateOrJoin(join)).await;
+        assert!(matches!(
+            recv_raw(&mut white).await,
+            Some(Message::S2CMatchStart(_))
+        ));
+        send_raw(&mut white, Message::C2SForfeit).await;
+        if both_forfeit {
+            send_raw(&mut black, Message::C2SForfeit).await;
+        }
+        assert!(matches!(
+            recv_raw(&mut black).await,
+            Some(Message::S2CMatchCreateOrJoinResult(_))
+        ));
+        assert!(matches!(
+            recv_raw(&mut black).await,
+            Some(Message::S2CMatchStart(_))
+        ));
+        if !both_forfeit {
+            assert!(matches!(
+                recv_raw(&mut black).await,
+                Some(Message::S2COpponentLeft)
+            ));
+        }
+        // neither side is disconnected, both are back in Idle
+        assert_connected(&mut white).await;
+        assert_connected(&mut black).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn forfeit_during_handoff_ends_the_match() {
+        let (ss, running) = server(ServerConfig {
+            history_size: 100,
+            ..ServerConfig::default()
+        });
+        let races = async {
+            for i in 0..50 {
+                forfeit_during_handoff(&ss, &running, i % 2 == 1).await;
+            }
+        };
+        timeout(TEST_TIMEOUT, races).await.unwrap();
+        let history = ss.server_history_matches.lock().await;
+        assert_eq!(history.len(), 50);
+        assert!(history
+            .values()
+            .all(|m| m.state == HistoryMatchState::Completed));
+    }
+
+    #[tokio::test]
+    async fn client_versions_are_bounded_only_when_configured() {
+        let greeted = |config: ServerConfig, version: (i64, i64)| async move {
+            let (ss, running) = server(config);
+            let mut cs = connection(&ss, &running);
+            let body = C2SGreetBody {
+                version1: version.0,
+                version2: version.1,
+                extensions: 0,
+            };
+            handle_connection_idle(&mut cs, Message::C2SGreet(body))
+                .await
+                .is_ok()
+        };
+        for version in [(1, 0), GREET_VERSION, (12, 0)] {
+            assert!(greeted(ServerConfig::default(), version).await);
+        }
+        let bounded = || ServerConfig {
+            min_client_version: Some((11, 10)),
+            max_client_version: Some(GREET_VERSION),
+            ..ServerConfig::default()
+        };
+        assert!(greeted(bounded(), (11, 10)).await);
+        assert!(greeted(bounded(), GREET_VERSION).await);
+        assert!(!greeted(bounded(), (11, 9)).await);
+        assert!(!greeted(bounded(), (11, 17)).await);
+    }
+
+    // one of the two wins, a cancel too late fails and the match starts as if it came first
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn cancel_races_join_on_a_private_match() {
+        let (ss, running) = server(ServerConfig::default());
+        let (mut cancelled, mut started) = (0, 0);
+        for _ in 0..50 {
+            let mut white = connect_greeted(&ss, &running).await;
+            let passcode = create_virtual(&mut white, create(OptionalColorWithRandom::White)).await;
+            let mut black = connect_greeted(&ss, &running).await;
+            let join = Message::C2SMatchCreateOrJoin(C2SMatchCreateOrJoinBody::Join(passcode));
+            tokio::join!(
+                put(&mut white, Message::C2SMatchCancel),
+                put(&mut black, join)
+            );
+            match get(&mut black).await {
+                Some(Message::S2CMatchCreateOrJoinResult(
+                    S2CMatchCreateOrJoinResultBody::Failed,
+                )) => {
+                    assert!(matches!(
+                        get(&mut white).await,
+                        Some(Message::S2CMatchCancelResult(
+                            S2CMatchCancelResultBody::Success
+                        ))
+                    ));
+                    cancelled += 1;
+                }
+                Some(Message::S2CMatchCreateOrJoinResult(
+                    S2CMatchCreateOrJoinResultBody::Success(_),
+                )) => {
+                    assert!(matches!(
+                        get(&mut black).await,
+                        Some(Message::S2CMatchStart(_))
+                    ));
+                    // the cancel is answered before or after the start, depending on which
+                    // the host saw first
+                    let mut replies = [get(&mut white).await, get(&mut white).await];
+                    replies.sort_by_key(|msg| msg.as_ref().map(|msg| msg.kind_name()));
+                    assert!(
+                        matches!(
+                            replies,
+                            [
+                                Some(Message::S2CMatchCancelResult(
+                                    S2CMatchCancelResultBody::Failed
+                                )),
+                                Some(Message::S2CMatchStart(_))
+                            ]
+                        ),
+                        "{:?}",
+                        replies
+                    );
+                    // both are playing, an action is relayed
+                    put(&mut white, action(Color::White)).await;
+                    assert!(matches!(
+                        get(&mut black).await,
+                        Some(Message::C2SOrS2CAction(_))
+                    ));
+                    started += 1;
+                }
+                other => panic!("Expected a join result, got {:?}.", other),
+            }
+        }
+        assert_eq!(cancelled + started, 50);
+    }
+
+    // white stops reading while black keeps moving, white is dropped instead of buffering
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn stalled_reader_is_dropped() {
+        let (ss, running) = server_with_events(ServerConfig {
+            limit_pending_frames: 4,
+            ..ServerConfig::default()
+        });
+        let mut events = ss.subscribe_events().unwrap();
+        let (mut white, mut black) = start_virtual_match(&ss, &running).await;
+        let mut moves = 0;
+        loop {
+            put(&mut black, action(Color::Black)).await;
+            match get(&mut black).await {
+                Some(Message::C2SOrS2CAction(_)) => moves += 1,
+                Some(Message::S2COpponentLeft) => break,
+                other => panic!("Expected an action or S2COpponentLeft, got {:?}.", other),
+            }
+            // far more than the pipe holds
+            assert!(moves < 10000, "Never stalled.");
+        }
+        assert_eq!(next_disconnect(&mut events).await, DisconnectReason::Error);
+        // what was written before the stall is still readable, then the connection is closed
+        while let Ok(Some(_)) = timeout(TEST_TIMEOUT, white.get()).await.unwrap() {}
+    }
+
+    // a greeted connection gets a frame too short for any message, then asks for the list
+    async fn answered_after_invalid_frame(policy: InvalidMessagePolicy) -> Option<Message> {
+        let (ss, running) = server(ServerConfig {
+            on_invalid_message: policy,
+            ..ServerConfig::default()
+        });
+        let mut stream = connect_raw(&ss, &running);
+        send_raw(&mut stream, greet()).await;
+        assert!(matches!(
+            recv_raw(&mut stream).await,
+            Some(Message::S2CGreet)
+        ));
+        stream.write_all(&4u64.to_le_bytes()).await.unwrap();
+        stream.write_all(b"5dc?").await.unwrap();
+        send_raw(&mut stream, Message::C2SMatchListRequest).await;
+        timeout(TEST_TIMEOUT, recv_raw(&mut stream)).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn invalid_frames_follow_on_invalid_message() {
+        assert!(
+            answered_after_invalid_frame(InvalidMessagePolicy::Disconnect)
+                .await
+                .is_none()
+        );
+        assert!(matches!(
+            answered_after_invalid_frame(InvalidMessagePolicy::Ignore).await,
+            Some(Message::S2CMatchList(_))
+        ));
+    }
+
+    // white sends a burst of moves at once and forfeits, the time is paused so nothing refills
+    async fn relayed_of_burst(policy: ActionLimitPolicy, burst: usize) -> (usize, Option<Message>) {
+        let (ss, running) = server(ServerConfig {
+            limit_actions_per_second: 10,
+            on_action_limit: policy,
+            ..ServerConfig::default()
+        });
+        let (mut white, mut black) = start_virtual_match(&ss, &running).await;
+        for _ in 0..burst {
+            white.put(action(Color::White)).await.unwrap();
+        }
+        // a disconnected sender may not take these any more
+        for msg in [Message::C2SForfeit, Message::C2SMatchListRequest] {
+            let _ = white.put(msg).await;
+        }
+        let _ = white.flush().await;
+        let mut relayed = 0;
+        loop {
+            match get(&mut black).await {
+                Some(Message::C2SOrS2CAction(_)) => relayed += 1,
+                Some(Message::S2COpponentLeft) => break,
+                other => panic!("Expected an action or S2COpponentLeft, got {:?}.", other),
+            }
+        }
+        // what white sees after its echoes, the match list if still connected
+        loop {
+            match get(&mut white).await {
+                Some(Message::C2SOrS2CAction(_)) => {}
+                other => return (relayed, other),
+            }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn action_floods_are_limited() {
+        // the rest is dropped, white stays connected and is back in Idle after its forfeit
+        let (relayed, after) = relayed_of_burst(ActionLimitPolicy::Drop, 50).await;
+        assert_eq!(relayed, 10);
+        assert!(
+            matches!(after, Some(Message::S2CMatchList(_))),
+            "{:?}",
+            after
+        );
+        // one over the budget is enough to be disconnected
+        let (relayed, after) = relayed_of_burst(ActionLimitPolicy::Disconnect, 11).await;
+        assert_eq!(relayed, 10);
+        assert!(after.is_none(), "{:?}", after);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stuck_handlers_are_aborted_after_the_limit() {
+        let mut handles = VecDeque::from([
+            tokio::spawn(async {}),
+            tokio::spawn(std::future::pending::<()>()),
+        ]);
+        let limit = Duration::from_secs(10);
+        let start = Instant::now();
+        assert_eq!(join_or_abort(&mut handles, Some(limit)).await, 1);
+        assert_eq!(Instant::now() - start, limit);
+        assert!(handles
+            .pop_back()
+            .unwrap()
+            .await
+            .unwrap_err()
+            .is_cancelled());
+        // nothing is aborted without a limit, the handlers all end
+        let mut handles = VecDeque::from([tokio::spawn(tokio::time::sleep(limit * 2))]);
+        assert_eq!(join_or_abort(&mut handles, None).await, 0);
+        assert!(handles[0].is_finished());
+    }
+
+    #[tokio::test]
+    async fn limit_connections_are_held_until_dropped() {
+        let (ss, _running) = server(ServerConfig {
+            limit_connections: 2,
+            ..ServerConfig::default()
+        });
+        let first = ss.admit_connection().unwrap();
+        let second = ss.admit_connection().unwrap();
+        assert!(ss.admit_connection().is_none());
+        assert_eq!(ss.connections.load(Ordering::Relaxed), 2);
+        drop(first);
+        let third = ss.admit_connection().unwrap();
+        assert!(ss.admit_connection().is_none());
+        drop((second, third));
+        assert_eq!(ss.connections.load(Ordering::Relaxed), 0);
+
+        let (ss, _running) = server(ServerConfig::default());
+        let unlimited: Vec<_> = (0..100).map(|_| ss.admit_connection().unwrap()).collect();
+        assert_eq!(ss.connections.load(Ordering::Relaxed), unlimited.len());
+    }
+
+    // white sends the action, None if white was disconnected for it instead of relaying it
+    async fn relayed_action(config: ServerConfig, body: C2SOrS2CActionBody) -> Option<Message> {
+        let (ss, running) = server(config);
+        let (mut white, mut black) = start_virtual_match(&ss, &running).await;
+        put(&mut white, Message::C2SOrS2CAction(body)).await;
+        match get(&mut black).await {
+            Some(Message::S2COpponentLeft) => {
+                assert!(get(&mut white).await.is_none());
+                None
+            }
+            other => other,
+        }
+    }
+
+    // action with the (0T1)e2 -> (0T1)e4 squares of white
+    fn action_of_type(action_type: ActionType) -> C2SOrS2CActionBody {
+        let Message::C2SOrS2CAction(body) = action(Color::White) else {
+            unreachable!()
+        };
+        C2SOrS2CActionBody {
+            action_type,
+            ..body
+        }
+    }
+
+    #[tokio::test]
+    async fn reset_puzzle_is_relayed_only_when_allowed() {
+        let reset = action_of_type(ActionType::ResetPuzzle);
+        assert!(relayed_action(ServerConfig::default(), reset)
+            .await
+            .is_none());
+        let allowed = ServerConfig {
+            allow_reset_puzzle: true,
+            ..ServerConfig::default()
+        };
+        assert!(matches!(
+            relayed_action(allowed, reset).await,
+            Some(Message::C2SOrS2CAction(C2SOrS2CActionBody {
+                action_type: ActionType::ResetPuzzle,
+                ..
+            }))
+        ));
+    }
+
+    #[tokio::test]
+    async fn check_reasons_off_the_board_are_rejected() {
+        let reason = action_of_type(ActionType::DisplayCheckReason);
+        assert!(relayed_action(ServerConfig::default(), reason)
+            .await
+            .is_some());
+        // only src is checked, dst isn't carried
+        let src = Coordinate { x: 8, ..reason.src };
+        let dst = Coordinate { x: 8, ..reason.dst };
+        assert!(relayed_action(
+            ServerConfig::default(),
+            C2SOrS2CActionBody { src, ..reason }
+        )
+        .await
+        .is_none());
+        assert!(relayed_action(
+            ServerConfig::default(),
+            C2SOrS2CActionBody { dst, ..reason }
+        )
+        .await
+        .is_some());
+    }
+
+    #[tokio::test]
+    async fn only_oversized_frames_are_counted() {
+        let (ss, running) = server_with_events(ServerConfig::default());
+        let mut events = ss.subscribe_events().unwrap();
+        for length in [MESSAGE_LENGTH_MAX as u64 + 1, 4] {
+            let mut stream = connect_raw(&ss, &running);
+            stream.write_all(&length.to_le_bytes()).await.unwrap();
+            stream.write_all(b"5dc?").await.unwrap();
+            assert_eq!(next_disconnect(&mut events).await, DisconnectReason::Error);
+        }
+        assert_eq!(ss.oversized_frames.load(Ordering::Relaxed), 1);
+    }
+
+    // a host that doesn't follow the handshake ends the join instead of the server
+    #[tokio::test]
+    async fn unexpected_handshake_disconnects_the_joiner() {
+        let (ss, running) = server_with_events(ServerConfig::default());
+        let mut events = ss.subscribe_events().unwrap();
+        let (host_tx, host_rx) = mpsc::channel(8);
+        let passcode = ss.insert_waiting_match(-1, host_rx).await;
+        host_tx
+            .send(Message::InternalForfeit(ForfeitReason::Resign))
+            .await
+            .unwrap();
+        let mut io = connect_greeted(&ss, &running).await;
+        let join = C2SMatchCreateOrJoinBody::Join(passcode);
+        put(&mut io, Message::C2SMatchCreateOrJoin(join)).await;
+        assert!(get(&mut io).await.is_none());
+        assert_eq!(next_disconnect(&mut events).await, DisconnectReason::Error);
+    }
+
+    #[tokio::test]
+    async fn create_without_a_clock_fails() {
+        let (ss, running) = server(ServerConfig::default());
+        let mut io = connect_greeted(&ss, &running).await;
+        let Message::C2SMatchCreateOrJoin(C2SMatchCreateOrJoinBody::Create(m)) =
+            create(OptionalColorWithRandom::White)
+        else {
+            unreachable!()
+        };
+        let unset = MatchSettings {
+            clock: OptionalClock::None,
+            ..m
+        };
+        put(
+            &mut io,
+            Message::C2SMatchCreateOrJoin(C2SMatchCreateOrJoinBody::Create(unset)),
+        )
+        .await;
+        assert!(matches!(
+            get(&mut io).await,
+            Some(Message::S2CMatchCreateOrJoinResult(
+                S2CMatchCreateOrJoinResultBody::Failed
+            ))
+        ));
+        assert!(ss.matches.lock().await.is_empty());
+        // still connected and idle, a valid create succeeds
+        create_virtual(&mut io, create(OptionalColorWithRandom::White)).await;
+    }
+
+    #[tokio::test]
+    async fn create_before_greet_disconnects() {
+        let (ss, running) = server(ServerConfig::default());
+        let mut io = ss.connect_virtual(running.subscribe());
+        put(&mut io, create(OptionalColorWithRandom::White)).await;
+        assert!(get(&mut io).await.is_none());
+        assert!(ss.matches.lock().await.is_empty());
+    }
+
+    // whether the cancel succeeded
+    async fn cancel(io: &mut MessageIO) -> bool {
+        put(io, Message::C2SMatchCancel).await;
+        match get(io).await {
+            Some(Message::S2CMatchCancelResult(body)) => {
+                matches!(body, S2CMatchCancelResultBody::Success)
+            }
+            other => panic!("Expected S2CMatchCancelResult, got {:?}.", other),
+        }
+    }
+
+    // the first cancel leaves Waiting, a resend from Idle succeeds for a while
+    #[tokio::test(start_paused = true)]
+    async fn repeated_cancels_succeed_within_the_window() {
+        let (ss, running) = server(ServerConfig::default());
+        let mut io = connect_greeted(&ss, &running).await;
+        create_virtual(&mut io, create(OptionalColorWithRandom::White)).await;
+        assert!(cancel(&mut io).await);
+        assert!(cancel(&mut io).await);
+        tokio::time::advance(CANCEL_REPEAT_WINDOW).await;
+        assert!(!cancel(&mut io).await);
+    }
+
+    // the host hands over its channel and never starts the match
+    #[tokio::test]
+    async fn joiner_times_out_without_match_start() {
+        let (ss, running) = server_with_events(ServerConfig {
+            handshake_timeout: Duration::from_millis(50),
+            ..ServerConfig::default()
+        });
+        let mut events = ss.subscribe_events().unwrap();
+        let (host_tx, host_rx) = mpsc::channel(8);
+        // what the joiner sends the host
+        let (inbox_tx, mut inbox) = mpsc::channel(8);
+        let passcode = ss.insert_waiting_match(-1, host_rx).await;
+        host_tx
+            .send(Message::InternalInitialize(inbox_tx))
+            .await
+            .unwrap();
+        let mut io = connect_greeted(&ss, &running).await;
+        let join = C2SMatchCreateOrJoinBody::Join(passcode);
+        put(&mut io, Message::C2SMatchCreateOrJoin(join)).await;
+        assert!(matches!(inbox.recv().await, Some(Message::InternalJoin(_))));
+        assert!(get(&mut io).await.is_none());
+        assert_eq!(
+            next_disconnect(&mut events).await,
+            DisconnectReason::Timeout
+        );
+    }
+
+    // as seen by a client that isn't hosting
+    async fn listed_public_matches(io: &mut MessageIO) -> usize {
+        put(io, Message::C2SMatchListRequest).await;
+        match get(io).await {
+            Some(Message::S2CMatchList(body)) => body.nonhost_body().public_matches_count,
+            other => panic!("Expected S2CMatchList, got {:?}.", other),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn public_matches_expire_after_waiting_match_ttl() {
+        let ttl = Duration::from_secs(60);
+        let (ss, running) = server(ServerConfig {
+            waiting_match_ttl: Some(ttl),
+            ..ServerConfig::default()
+        });
+        let mut host = connect_greeted(&ss, &running).await;
+        let mut lister = connect_greeted(&ss, &running).await;
+        create_virtual(&mut host, create_public(OptionalColorWithRandom::White)).await;
+        assert_eq!(listed_public_matches(&mut lister).await, 1);
+        tokio::time::advance(ttl).await;
+        // the host is told like after its own cancel
+        assert!(matches!(
+            get(&mut host).await,
+            Some(Message::S2CMatchCancelResult(
+                S2CMatchCancelResultBody::Success
+            ))
+        ));
+        assert!(ss.public_matches.lock().await.is_empty());
+        assert!(ss.matches.lock().await.is_empty());
+        assert_eq!(listed_public_matches(&mut lister).await, 0);
+    }
+
+    fn quick_match(color: OptionalColorWithRandom) -> Message {
+        Message::C2SQuickMatch(MatchSettings {
+            color,
+            clock: OptionalClock::NoClock,
+            variant: Variant::Standard,
+            visibility: Visibility::Public,
+            passcode: -1,
+            match_id: -1,
+        })
+    }
+
+    #[tokio::test]
+    async fn quick_match_joins_a_waiting_public_match() {
+        let (ss, running) = server(ServerConfig::default());
+        let mut host = connect_greeted(&ss, &running).await;
+        let passcode =
+            create_virtual(&mut host, create_public(OptionalColorWithRandom::White)).await;
+        let mut io = connect_greeted(&ss, &running).await;
+        put(&mut io, quick_match(OptionalColorWithRandom::Random)).await;
+        match get(&mut io).await {
+            Some(Message::S2CMatchCreateOrJoinResult(S2CMatchCreateOrJoinResultBody::Success(
+                m,
+            ))) => assert_eq!(m.passcode, passcode),
+            other => panic!("Expected a joined match, got {:?}.", other),
+        }
+        for io in [&mut host, &mut io] {
+            assert!(matches!(get(io).await, Some(Message::S2CMatchStart(_))));
+        }
+        assert!(ss.public_matches.lock().await.is_empty());
+    }
+
+    // white asking for white can't join a white host, it hosts a match of its own
+    #[tokio::test]
+    async fn quick_match_hosts_when_nothing_accepts_it() {
+        let (ss, running) = server(ServerConfig::default());
+        let mut host = connect_greeted(&ss, &running).await;
+        let passcode =
+            create_virtual(&mut host, create_public(OptionalColorWithRandom::White)).await;
+        let mut io = connect_greeted(&ss, &running).await;
+        let hosted = create_virtual(&mut io, quick_match(OptionalColorWithRandom::White)).await;
+        assert_ne!(hosted, passcode);
+        let public_matches = ss.public_matches.lock().await;
+        assert_eq!(
+            public_matches.keys().copied().collect::<Vec<_>>(),
+            [passcode, hosted]
+        );
+    }
+
+    // limit_idle_duration is about the connection, a match in progress doesn't keep it open
+    #[tokio::test(start_paused = true)]
+    async fn idle_players_time_out() {
+        let idle = Duration::from_secs(30);
+        let (ss, running) = server_with_events(ServerConfig {
+            limit_idle_duration: Some(idle),
+            ..ServerConfig::default()
+        });
+        let mut events = ss.subscribe_events().unwrap();
+        let (mut white, mut black) = start_virtual_match(&ss, &running).await;
+        tokio::time::advance(idle).await;
+        for _ in 0..2 {
+            assert_eq!(
+                next_disconnect(&mut events).await,
+                DisconnectReason::Timeout
+            );
+        }
+        // one of them may have seen the other leave first
+        for io in [&mut white, &mut black] {
+            while let Some(msg) = get(io).await {
+                assert!(matches!(msg, Message::S2COpponentLeft), "{:?}", msg);
+            }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn disconnect_reasons_are_reported() {
+        let limit = Duration::from_secs(60);
+        let (ss, running) = server_with_events(ServerConfig {
+            limit_connection_duration: Some(limit),
+            ..ServerConfig::default()
+        });
+        let mut events = ss.subscribe_events().unwrap();
+        let io = connect_greeted(&ss, &running).await;
+        drop(io);
+        assert_eq!(
+            next_disconnect(&mut events).await,
+            DisconnectReason::ClientClosed
+        );
+        let mut io = connect_greeted(&ss, &running).await;
+        tokio::time::advance(limit).await;
+        assert_eq!(
+            next_disconnect(&mut events).await,
+            DisconnectReason::Timeout
+        );
+        assert!(get(&mut io).await.is_none());
+    }
+
+    // the reply waits for a flush_interval that never comes before the shutdown
+    #[tokio::test(start_paused = true)]
+    async fn queued_replies_are_sent_on_shutdown() {
+        let (ss, running) = server(ServerConfig {
+            flush_interval: Some(Duration::from_secs(60)),
+            ..ServerConfig::default()
+        });
+        let mut io = ss.connect_virtual(running.subscribe());
+        put(&mut io, greet()).await;
+        put(&mut io, Message::C2SMatchListRequest).await;
+        // returns once the server is idle with both replies queued
+        tokio::time::sleep(Duration::from_millis(1)).await;
+        running.send(false).unwrap();
+        assert!(matches!(get(&mut io).await, Some(Message::S2CGreet)));
+        assert!(matches!(get(&mut io).await, Some(Message::S2CMatchList(_))));
+        assert!(get(&mut io).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn random_variants_follow_variant_weights() {
+        let (ss, _running) = server(ServerConfig {
+            variants: HashSet::from([
+                Variant::Standard,
+                Variant::Small,
+                Variant::VerySmall,
+                Variant::Random,
+            ]),
+            variant_weights: HashMap::from([(Variant::Standard, 3), (Variant::VerySmall, 0)]),
+            ..ServerConfig::default()
+        });
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut drawn = HashMap::new();
+        for _ in 0..10000 {
+            let variant = Variant::Random.determined(
+                &ss.variants_without_random,
+                &ss.variant_weights,
+                &mut rng,
+            );
+            *drawn.entry(variant).or_insert(0) += 1;
+        }
+        // 3 to 1, about 7500 and 2500
+        assert!(
+            (7300..7700).contains(&drawn[&Variant::Standard]),
+            "{:?}",
+            drawn
+        );
+        assert!(
+            (2300..2700).contains(&drawn[&Variant::Small]),
+            "{:?}",
+            drawn
+        );
+        assert_eq!(drawn.len(), 2, "{:?}", drawn);
+    }
+
+    #[tokio::test]
+    async fn variant_list_is_the_configured_set_sorted() {
+        let (ss, running) = server(ServerConfig {
+            variants: HashSet::from([Variant::Small, Variant::Random, Variant::Standard]),
+            ..ServerConfig::default()
+        });
+        let mut io = connect_greeted(&ss, &running).await;
+        put(&mut io, Message::C2SVariantListRequest).await;
+        match get(&mut io).await {
+            Some(Message::S2CVariantList(variants)) => assert_eq!(
+                variants,
+                [Variant::Standard, Variant::Small, Variant::Random]
+            ),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn history_keeps_the_newest_history_size_matches() {
+        let (ss, _running) = server(ServerConfig {
+            history_size: 5,
+            ..ServerConfig::default()
+        });
+        let m = MatchSettingsBuilder::new()
+            .color(OptionalColorWithRandom::White)
+            .clock(OptionalClock::NoClock)
+            .build()
+            .unwrap();
+        for match_id in 0..8 {
+            ss.insert_history_match(match_id, m).await;
+        }
+        let history = ss.server_history_matches.lock().await;
+        assert_eq!(history.len(), 5);
+        assert_eq!(history.keys().copied().collect::<Vec<_>>(), [3, 4, 5, 6, 7]);
+    }
+
+    async fn timeline_after_five_actions(timeline_length: usize) -> Vec<i64> {
+        let (ss, _running) = server(ServerConfig {
+            timeline_length,
+            ..ServerConfig::default()
+        });
+        let m = MatchSettingsBuilder::new()
+            .color(OptionalColorWithRandom::White)
+            .clock(OptionalClock::NoClock)
+            .build()
+            .unwrap();
+        ss.insert_history_match(0, m).await;
+        let mut body = action_of_type(ActionType::Move);
+        for x in 0..5 {
+            body.dst.x = x;
+            stamp_action(&ss, 0, &mut body).await;
+        }
+        // gone from the history, nothing to record into
+        stamp_action(&ss, 1, &mut body).await;
+        let history = ss.server_history_matches.lock().await;
+        history[&0].timeline.iter().map(|body| body.dst.x).collect()
+    }
+
+    #[tokio::test]
+    async fn timeline_keeps_the_newest_timeline_length_actions() {
+        assert_eq!(timeline_after_five_actions(3).await, [2, 3, 4]);
+        assert_eq!(timeline_after_five_actions(8).await, [0, 1, 2, 3, 4]);
+        assert!(timeline_after_five_actions(0).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn strict_actions_reject_inconsistent_boards() {
+        let strict = || ServerConfig {
+            strict_actions: true,
+            ..ServerConfig::default()
+        };
+        let e2e4 = action_of_type(ActionType::Move);
+        assert!(relayed_action(strict(), e2e4).await.is_some());
+        // on a board of black to move
+        let src = Coordinate {
+            board_color: Color::Black,
+            ..e2e4.src
+        };
+        let on_black = C2SOrS2CActionBody { src, ..e2e4 };
+        assert!(relayed_action(strict(), on_black).await.is_none());
+        assert!(relayed_action(ServerConfig::default(), on_black)
+            .await
+            .is_some());
+        // to T2, forward in time
+        let dst = Coordinate { t: 2, ..e2e4.dst };
+        let forward = C2SOrS2CActionBody { dst, ..e2e4 };
+        assert!(relayed_action(strict(), forward).await.is_none());
+        assert!(relayed_action(ServerConfig::default(), forward)
+            .await
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn nodelay_is_set_on_accepted_streams() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let listen_addr = listener.local_addr().unwrap();
+        for tcp_nodelay in [true, false] {
+            let _client = TcpStream::connect(listen_addr).await.unwrap();
+            let (stream, addr) = listener.accept().await.unwrap();
+            set_stream_options(&stream, addr, tcp_nodelay);
+            assert_eq!(stream.nodelay().unwrap(), tcp_nodelay);
+        }
+    }
+
+    #[tokio::test]
+    async fn no_create_is_handled_once_stopped() {
+        let (ss, running) = server_with_events(ServerConfig::default());
+        let mut events = ss.subscribe_events().unwrap();
+        let mut io = connect_greeted(&ss, &running).await;
+        running.send(false).unwrap();
+        assert_eq!(
+            next_disconnect(&mut events).await,
+            DisconnectReason::Shutdown
+        );
+        // the connection is gone, the create is never read and nothing is replied
+        let _ = io.put(create(OptionalColorWithRandom::White)).await;
+        let reply = timeout(TEST_TIMEOUT, io.get()).await.unwrap();
+        assert!(!matches!(reply, Ok(Some(_))), "{:?}", reply);
+        assert_eq!(ss.snapshot().await.waiting_matches, 0);
+    }
+}
@@ -0,0 +1,143 @@
+use crate::datatype::*;
+
+/* golden vectors, one instance of every message that goes on the wire
+named c2s_ or s2c_ and its kind_name, written out by the gen_vectors example
+internal messages never go on the wire and have no vector
+*/
+
+fn settings() -> MatchSettings {
+    MatchSettings {
+        color: OptionalColorWithRandom::White,
+        clock: OptionalClock::Medium,
+        variant: Variant::Standard,
+        visibility: Visibility::Public,
+        passcode: 123456,
+        match_id: -1, // not sent
+    }
+}
+
+fn action() -> C2SOrS2CActionBody {
+    let src = Coordinate {
+        l: 0,
+        t: 1,
+        board_color: Color::White,
+        y: 1,
+        x: 4,
+    };
+    C2SOrS2CActionBody {
+        action_type: ActionType::Move,
+        color: Color::White,
+        seconds_passed: 42,
+        src,
+        dst: Coordinate { y: 3, ..src },
+    }
+}
+
+fn match_list() -> S2CMatchListHostBody {
+    let mut body = S2CMatchListNonhostBody::empty(true);
+    body.public_matches[0] = MatchSettingsWithoutVisibility {
+        color: OptionalColorWithRandom::Black,
+        clock: OptionalClock::Short,
+        variant: Variant::Random,
+        passcode: 654321,
+        match_id: -1, // not sent
+    };
+    body.public_matches_count = 1;
+    body.server_history_matches[0] = S2CMatchListServerHistoryMatch {
+        state: HistoryMatchState::Completed,
+        end_reason: None, // not sent
+        outcome: Some(MatchOutcome::WhiteWin),
+        clock: OptionalClock::NoClock,
+        variant: Variant::Standard,
+        visibility: Visibility::Private,
+        seconds_passed: 600,
+    };
+    body.server_history_matches_count = 1;
+    S2CMatchListHostBody {
+        color: OptionalColorWithRandom::White,
+        clock: OptionalClock::Medium,
+        variant: Variant::Standard,
+        passcode: 123456,
+        body,
+    }
+}
+
+// (file name, message, whether the server sends it)
+pub fn golden_vectors() -> Vec<(String, Message, bool)> {
+    let m = settings();
+    let c2s = [
+        Message::C2SGreet(C2SGreetBody {
+            version1: GREET_VERSION.0,
+            version2: GREET_VERSION.1,
+            extensions: 0,
+        }),
+        Message::C2SMatchCreateOrJoin(C2SMatchCreateOrJoinBody::Create(MatchSettings {
+            passcode: -1,
+            ..m
+        })),
+        Message::C2SMatchCancel,
+        Message::C2SForfeit,
+        Message::C2SOrS2CAction(action()),
+        Message::C2SMatchListRequest,
+        Message::C2SQuickMatch(MatchSettings {
+            visibility: Visibility::Public,
+            passcode: -1,
+            ..m
+        }),
+        Message::C2SVariantListRequest,
+    ];
+    let s2c = [
+        Message::S2CGreet,
+        Message::create_success(MatchSettings { match_id: -1, ..m }),
+        Message::cancel_success(),
+        Message::S2CMatchStart(S2CMatchStartBody {
+            m: MatchSettingsWithoutVisibility {
+                color: OptionalColorWithRandom::Black,
+                passcode: -1, // not sent
+                match_id: 7,
+                ..MatchSettingsWithoutVisibility::new(m)
+            },
+            match_id: 7,
+            seconds_passed: 42,
+            opponent: None,
+        }),
+        Message::S2COpponentLeft,
+        Message::C2SOrS2CAction(action()),
+        Message::host_match_list(match_list()),
+        Message::S2CServerNotice("Restarting in 5 minutes.".to_string()),
+        Message::S2CServerInfo("5dcserver".to_string()),
+        Message::S2CVariantList(vec![Variant::Standard, Variant::Random]),
+    ];
+    let mut vectors: Vec<_> = c2s.into_iter().map(|msg| (msg, false)).collect();
+    vectors.extend(s2c.into_iter().map(|msg| (msg, true)));
+    vectors
+        .into_iter()
+        .map(|(msg, from_server)| {
+            // an action is sent both ways, the side tells them apart
+            let side = if from_server { "s2c" } else { "c2s" };
+            (format!("{}_{}", side, msg.kind_name()), msg, from_server)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BytesMut;
+
+    #[test]
+    fn vectors_round_trip() {
+        for (name, msg, from_server) in golden_vectors() {
+            let bytes = msg.pack().unwrap();
+            let decoded = if from_server {
+                Message::unpack_s2c(BytesMut::from(&bytes[..]))
+            } else {
+                Message::unpack(BytesMut::from(&bytes[..]))
+            }
+            .unwrap_or_else(|e| panic!("{} doesn't decode: {}", name, e));
+            // the messages have no PartialEq, Debug shows every field
+            assert_eq!(format!("{:?}", decoded), format!("{:?}", msg), "{}", name);
+            assert_eq!(decoded.pack().unwrap(), bytes, "{}", name);
+        }
+    }
+}
@@ -0,0 +1,125 @@
+use bytes::{Bytes, BytesMut};
+use futures::{ready, Sink, SinkExt, Stream, StreamExt};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::net::TcpStream;
+use tokio::sync::watch;
+use tokio::time::timeout;
+use tokio_tungstenite::tungstenite::error::ProtocolError as WsProtocolError;
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+use tokio_tungstenite::tungstenite::{Error as WsError, Message as WsMessage};
+use tokio_tungstenite::{accept_async_with_config, WebSocketStream};
+use tracing::warn;
+
+use crate::datatype::{ConnectionId, MessageIO, ProtocolError, Result};
+use crate::server::{read_client_addr, serve_connection, ServerState};
+
+/* one binary message per frame, exactly what Message::pack produces
+websocket already frames, so there is no length prefix
+*/
+pub struct WebSocketFrames {
+    ws: WebSocketStream<TcpStream>,
+    max_frame_length: usize,
+}
+
+fn ws_error(e: WsError) -> ProtocolError {
+    match e {
+        WsError::ConnectionClosed | WsError::AlreadyClosed => ProtocolError::Disconnected,
+        WsError::Capacity(_) => ProtocolError::FrameTooLong,
+        WsError::Io(e) => ProtocolError::Io(e),
+        e => ProtocolError::InvalidData(e.to_string()),
+    }
+}
+
+impl Stream for WebSocketFrames {
+    type Item = Result<BytesMut>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            return Poll::Ready(match ready!(self.ws.poll_next_unpin(cx)) {
+                Some(Ok(WsMessage::Binary(frame))) => Some(Ok(BytesMut::from(&frame[..]))),
+                // pings are answered by tungstenite
+                Some(Ok(WsMessage::Ping(_) | WsMessage::Pong(_))) => continue,
+                Some(Ok(WsMessage::Close(_))) => None,
+                Some(Ok(_)) => Some(Err(ProtocolError::InvalidData(
+                    "Only binary WebSocket messages are accepted.".to_string(),
+                ))),
+                // browsers often drop the socket without a close frame
+                Some(Err(
+                    WsError::ConnectionClosed
+                    | WsError::AlreadyClosed
+                    | WsError::Protocol(WsProtocolError::ResetWithoutClosingHandshake),
+                )) => None,
+                Some(Err(e)) => Some(Err(ws_error(e))),
+                None => None,
+            });
+        }
+    }
+}
+
+impl Sink<Bytes> for WebSocketFrames {
+    type Error = ProtocolError;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.ws.poll_ready_unpin(cx).map_err(ws_error)
+    }
+
+    // outgoing messages are limited too, like the length delimited codec
+    fn start_send(mut self: Pin<&mut Self>, frame: Bytes) -> Result<()> {
+        if frame.len() > self.max_frame_length {
+            return Err(ProtocolError::FrameTooLong);
+        }
+        self.ws
+            .start_send_unpin(WsMessage::Binary(frame.to_vec()))
+            .map_err(ws_error)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.ws.poll_flush_unpin(cx).map_err(ws_error)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.ws.poll_close_unpin(cx).map_err(ws_error)
+    }
+}
+
+// same as handle_connection after the WebSocket handshake
+pub async fn handle_websocket_connection(
+    ss: Arc<ServerState>,
+    mut stream: TcpStream,
+    addr: SocketAddr,
+    running: watch::Receiver<bool>,
+    id: ConnectionId,
+) {
+    let Some(addr) = read_client_addr(&ss, &mut stream, addr).await else {
+        return;
+    };
+    let max_frame_length = ss.config.limit_message_length;
+    let config = WebSocketConfig {
+        max_message_size: Some(max_frame_length),
+        max_frame_size: Some(max_frame_length),
+        ..Default::default()
+    };
+    match timeout(
+        ss.config.handshake_timeout,
+        accept_async_with_config(stream, Some(config)),
+    )
+    .await
+    {
+        Ok(Ok(ws)) => {
+            let io = MessageIO::from_frames(WebSocketFrames {
+                ws,
+                max_frame_length,
+            });
+            serve_connection(ss, io, addr, running, id).await;
+        }
+        Ok(Err(e)) => warn!("[{}:{}] Rejected, {}", addr.ip(), addr.port(), e),
+        Err(_) => warn!(
+            "[{}:{}] Rejected, no WebSocket handshake.",
+            addr.ip(),
+            addr.port()
+        ),
+    }
+}